@@ -0,0 +1,168 @@
+//! Throughput of `BinaryHeap<Reverse<Item>>` vs `LoserTree<Item>` on gensort-style
+//! records, for the replacement-selection workspace and for the k-way merge selector,
+//! and of the slot-based `Workspace` that run generation uses, also on longer keys.
+//!
+//! Run with `cargo bench --bench loser_tree`.
+
+use rs::generate::Generator;
+use rs::loser_tree::LoserTree;
+use rs::record::{Item, Rec};
+use rs::workspace::Workspace;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const WORKSPACE: usize = 100_000;
+const RECORDS: usize = 2_000_000;
+const FAN_IN: usize = 64;
+
+/// Deterministic gensort input with uniformly random keys.
+fn gen_records(n: usize) -> Vec<Rec> {
+    Generator::new(n as u64, 42).records().collect()
+}
+
+/// The same records with 40-byte keys (the gensort key repeated) and 60-byte payloads.
+fn long_keys(input: &[Rec]) -> Vec<Rec> {
+    input
+        .iter()
+        .map(|r| Rec::new(r.key.repeat(4), r.payload[..60].to_vec()))
+        .collect()
+}
+
+fn next_item(prev: &Item, rec: &Rec, seq: u64) -> Item {
+    let g = if rec.key < prev.rec().key {
+        prev.g() + 1
+    } else {
+        prev.g()
+    };
+    Item::new(rec.clone(), g, seq)
+}
+
+fn rs_binary_heap(input: &[Rec]) -> usize {
+    let mut heap: BinaryHeap<Reverse<Item>> = input[..WORKSPACE]
+        .iter()
+        .enumerate()
+        .map(|(i, r)| Reverse(Item::new(r.clone(), 0, i as u64)))
+        .collect();
+    let mut out = 0;
+    for (i, rec) in input[WORKSPACE..].iter().enumerate() {
+        let Reverse(top) = heap.pop().unwrap();
+        heap.push(Reverse(next_item(&top, rec, (WORKSPACE + i) as u64)));
+        out += black_box(&top).rec().key[0] as usize;
+    }
+    out
+}
+
+fn rs_loser_tree(input: &[Rec]) -> usize {
+    let mut tree = LoserTree::new(
+        input[..WORKSPACE]
+            .iter()
+            .enumerate()
+            .map(|(i, r)| Some(Item::new(r.clone(), 0, i as u64)))
+            .collect(),
+    );
+    let mut out = 0;
+    for (i, rec) in input[WORKSPACE..].iter().enumerate() {
+        let next = next_item(tree.peek().unwrap(), rec, (WORKSPACE + i) as u64);
+        let top = tree.replace_top(Some(next)).unwrap();
+        out += black_box(&top).rec().key[0] as usize;
+    }
+    out
+}
+
+fn rs_workspace(input: &[Rec]) -> usize {
+    let mut ws = Workspace::new(input[..WORKSPACE].to_vec());
+    let mut out = 0;
+    for (i, rec) in input[WORKSPACE..].iter().enumerate() {
+        let (top, g) = ws.peek().unwrap();
+        let g = if rec.key < top.key { g + 1 } else { g };
+        let top = ws.replace_top(rec.clone(), g, (WORKSPACE + i) as u64);
+        out += black_box(&top).key[0] as usize;
+    }
+    out
+}
+
+/// Split the input into `FAN_IN` sorted runs.
+fn make_runs(input: &[Rec]) -> Vec<Vec<Item>> {
+    let mut runs: Vec<Vec<Item>> = input
+        .chunks(input.len().div_ceil(FAN_IN))
+        .enumerate()
+        .map(|(run, c)| {
+            c.iter()
+                .map(|r| Item::new(r.clone(), 0, run as u64))
+                .collect()
+        })
+        .collect();
+    for r in runs.iter_mut() {
+        r.sort();
+        r.reverse(); // pop from the back
+    }
+    runs
+}
+
+fn merge_binary_heap(mut runs: Vec<Vec<Item>>) -> usize {
+    let mut heap: BinaryHeap<Reverse<Item>> =
+        runs.iter_mut().map(|r| Reverse(r.pop().unwrap())).collect();
+    let mut out = 0;
+    while let Some(Reverse(top)) = heap.pop() {
+        if let Some(next) = runs[top.seq() as usize].pop() {
+            heap.push(Reverse(next));
+        }
+        out += black_box(&top).rec().key[0] as usize;
+    }
+    out
+}
+
+fn merge_loser_tree(mut runs: Vec<Vec<Item>>) -> usize {
+    let mut tree = LoserTree::new(runs.iter_mut().map(|r| r.pop()).collect());
+    let mut out = 0;
+    while let Some(run) = tree.peek_index() {
+        let top = tree.replace_top(runs[run].pop()).unwrap();
+        out += black_box(&top).rec().key[0] as usize;
+    }
+    out
+}
+
+fn report(name: &str, records: usize, elapsed: Duration) {
+    eprintln!(
+        "{:<28} {:>8.1} ms  {:>7.2} M records/s",
+        name,
+        elapsed.as_secs_f64() * 1e3,
+        records as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
+
+fn time<T>(name: &str, records: usize, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let r = f();
+    report(name, records, start.elapsed());
+    r
+}
+
+fn main() {
+    let input = gen_records(RECORDS);
+    let steps = RECORDS - WORKSPACE;
+
+    let a = time("rs / BinaryHeap", steps, || rs_binary_heap(&input));
+    let b = time("rs / LoserTree", steps, || rs_loser_tree(&input));
+    let c = time("rs / Workspace", steps, || rs_workspace(&input));
+    assert!(
+        a == b && b == c,
+        "all workspaces must output the same records"
+    );
+    let long = long_keys(&input);
+    time("rs / LoserTree, 40-byte keys", steps, || {
+        rs_loser_tree(&long)
+    });
+    time("rs / Workspace, 40-byte keys", steps, || {
+        rs_workspace(&long)
+    });
+
+    let runs = make_runs(&input);
+    let a = time("merge / BinaryHeap", RECORDS, || {
+        merge_binary_heap(runs.clone())
+    });
+    let b = time("merge / LoserTree", RECORDS, || merge_loser_tree(runs));
+    assert_eq!(a, b, "both selectors must output the same records");
+}
@@ -0,0 +1,196 @@
+//! Background I/O threads for the threaded backend.
+//!
+//! `ReadThread` and `WriteThread` mirror the io_uring `ReadAhead` / `WriteBehind`:
+//! a fixed set of aligned buffers circulates between the caller and one I/O
+//! thread over channels, so the caller only waits when every buffer is busy.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+/// Filled buffer and its valid bytes, or the read error.
+type Filled = io::Result<(Vec<u8>, usize)>;
+/// Buffer to write: `(buffer, len, offset)`.
+type Pending = (Vec<u8>, usize, u64);
+/// Written buffer and the result of the write.
+type Written = (Vec<u8>, io::Result<()>);
+
+/// Read at least `want` bytes at `offset` (fewer only at end of file) into `buf`.
+/// Every request covers the rest of `buf`, so with Direct I/O the lengths stay
+/// aligned even for the final, partial chunk of a file.
+fn read_full_at(file: &File, buf: &mut [u8], want: usize, offset: u64) -> io::Result<usize> {
+    let mut done = 0;
+    while done < want {
+        match file.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done.min(want))
+}
+
+/// Sequential read-ahead on a thread that fills up to `buffers - 1` chunks while
+/// the caller consumes the current one.
+pub(crate) struct ReadThread {
+    /// Empty buffers to the thread, and filled ones back.
+    channels: Option<(SyncSender<Vec<u8>>, Receiver<Filled>)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReadThread {
+    /// Start reading `file` at `offset`, which must be aligned for Direct I/O.
+    pub(crate) fn new(
+        file: File,
+        file_size: u64,
+        offset: u64,
+        buffers: usize,
+        chunk: usize,
+        alloc: &dyn Fn(usize) -> Vec<u8>,
+    ) -> io::Result<Self> {
+        let (free_tx, free_rx) = mpsc::sync_channel::<Vec<u8>>(buffers);
+        let (full_tx, full_rx) = mpsc::sync_channel(buffers);
+        // The caller holds the last buffer
+        for _ in 1..buffers.max(2) {
+            free_tx.send(alloc(chunk)).unwrap();
+        }
+        let handle = std::thread::Builder::new()
+            .name("rs-read-ahead".to_string())
+            .spawn(move || {
+                let mut offset = offset;
+                while let Ok(mut buf) = free_rx.recv() {
+                    let want = (file_size.saturating_sub(offset) as usize).min(chunk);
+                    let res = read_full_at(&file, &mut buf, want, offset);
+                    let stop = !matches!(res, Ok(n) if n > 0);
+                    if let Ok(n) = res {
+                        offset += n as u64;
+                    }
+                    if full_tx.send(res.map(|n| (buf, n))).is_err() || stop {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self {
+            channels: Some((free_tx, full_rx)),
+            handle: Some(handle),
+        })
+    }
+
+    /// Swap the next chunk in file order into `buf`, which must be a chunk-sized
+    /// buffer from the same allocator. Returns the number of valid bytes, 0 at EOF.
+    pub(crate) fn next(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let Some((free_tx, full_rx)) = &self.channels else {
+            return Ok(0);
+        };
+        // The thread stops after EOF or an error and drops its sender
+        let Ok(res) = full_rx.recv() else {
+            return Ok(0);
+        };
+        let (full, n) = res?;
+        let empty = std::mem::replace(buf, full);
+        let _ = free_tx.send(empty);
+        Ok(n)
+    }
+}
+
+impl Drop for ReadThread {
+    fn drop(&mut self) {
+        // Closing the channels stops the thread at its next send or receive
+        self.channels = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Write-behind on a thread that writes full chunks while the caller fills the
+/// next one.
+pub(crate) struct WriteThread {
+    /// Full buffers to the thread, and written ones back.
+    channels: Option<(SyncSender<Pending>, Receiver<Written>)>,
+    handle: Option<JoinHandle<()>>,
+    spare: Vec<Vec<u8>>,
+    in_flight: usize,
+}
+
+impl WriteThread {
+    pub(crate) fn new(
+        file: File,
+        buffers: usize,
+        chunk: usize,
+        alloc: &dyn Fn(usize) -> Vec<u8>,
+    ) -> io::Result<Self> {
+        let buffers = buffers.max(2);
+        let (full_tx, full_rx) = mpsc::sync_channel::<Pending>(buffers);
+        let (done_tx, done_rx) = mpsc::sync_channel(buffers);
+        let handle = std::thread::Builder::new()
+            .name("rs-write-behind".to_string())
+            .spawn(move || {
+                while let Ok((buf, len, offset)) = full_rx.recv() {
+                    let res = file.write_all_at(&buf[..len], offset);
+                    if done_tx.send((buf, res)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self {
+            channels: Some((full_tx, done_rx)),
+            handle: Some(handle),
+            // The caller holds the last buffer
+            spare: (1..buffers).map(|_| alloc(chunk)).collect(),
+            in_flight: 0,
+        })
+    }
+
+    /// Wait for the oldest write and keep its buffer as a spare.
+    fn reap(&mut self) -> io::Result<()> {
+        let (_, done_rx) = self.channels.as_ref().unwrap();
+        let Ok((buf, res)) = done_rx.recv() else {
+            self.in_flight = 0;
+            return Err(io::Error::other("write-behind thread exited"));
+        };
+        self.in_flight -= 1;
+        self.spare.push(buf);
+        res
+    }
+
+    /// Write the first `len` bytes of `buf` at `offset`. `buf` is swapped with an
+    /// idle chunk-sized buffer, waiting for the oldest write if all are busy.
+    pub(crate) fn write(&mut self, buf: &mut Vec<u8>, len: usize, offset: u64) -> io::Result<()> {
+        if self.spare.is_empty() {
+            self.reap()?;
+        }
+        let full = std::mem::replace(buf, self.spare.pop().unwrap());
+        let (full_tx, _) = self.channels.as_ref().unwrap();
+        full_tx
+            .send((full, len, offset))
+            .map_err(|_| io::Error::other("write-behind thread exited"))?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Wait for every write in flight and report the first error.
+    pub(crate) fn wait_all(&mut self) -> io::Result<()> {
+        let mut first_err = None;
+        while self.in_flight > 0 {
+            if let Err(e) = self.reap() {
+                first_err.get_or_insert(e);
+            }
+        }
+        first_err.map_or(Ok(()), Err)
+    }
+}
+
+impl Drop for WriteThread {
+    fn drop(&mut self) {
+        // The thread finishes the writes already handed over, then sees the
+        // closed channel
+        self.channels = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use rs::generate::{KeyDistribution, KeyType};
+use rs::io::{InputFormat, IoOptions, OutputFormat};
+use rs::key_spec::KeySpec;
+use rs::memory::MemoryBudget;
+
+pub const USAGE: &str = "\
+Usage: rs <COMMAND> [OPTIONS]
+
+Commands:
+  runs <INPUT>              Generate sorted runs from INPUT
+  merge <OUTPUT> <RUN>...   Merge sorted run files into OUTPUT
+  merge <OUTPUT> --manifest <FILE>
+                            Merge the runs listed in a run manifest into OUTPUT
+  sort <INPUT> <OUTPUT>     Generate runs from INPUT and merge them into OUTPUT
+  validate <FILE>           Check that FILE is sorted; print its record count, duplicate
+                            keys and an order-independent checksum to compare with the input
+  gen <OUTPUT>              Write gensort records to OUTPUT (needs --records)
+
+INPUT and FILE may be `-` for stdin, and OUTPUT `-` for stdout, e.g.
+`producer | rs sort - - | consumer`; streams use buffered I/O, and merging into stdout
+is single-threaded.
+
+Options:
+  -m, --mem <SIZE>             Memory budget for run generation, e.g. 512M, 2G [default: 1G]
+      --heap-cap <N>           Workspace size in records instead of a memory budget
+  -j, --threads <N>            Threads for run generation and merge; run generation
+                               workers share the memory budget or heap capacity [default: 1]
+  -o, --output-dir <DIR>       Directory for run files written by `runs` [default: .]
+  -t, --temp-dir <DIR>         Directory for intermediate runs of `sort`; each job uses
+                               its own subdirectory, removed when done [default: .]
+      --keep-runs              Keep the intermediate runs of `sort`
+      --prefix <NAME>          Run file name prefix [default: run]
+      --manifest <FILE>        Run manifest for `merge`, as written by `runs`
+      --input-format <FMT>     gensort | len-prefixed | text[:DELIM]
+                               [default: gensort; for `validate`, a run file or
+                               len-prefixed]
+                               text sorts lines by the text before the first DELIM
+                               (one character, or tab), or by the whole line
+      --key <SPEC>             Sort by fields of the record instead of the format's key:
+                               comma-separated OFFSET[:LEN][:TYPE][:desc], TYPE one of
+                               bytes (default), uint-be, uint-le, int-be, int-le; offsets
+                               count from the start of the record (key, then payload).
+                               Records keep their layout; `merge` and `validate` need
+                               the same --key
+      --output-format <FMT>    Merged output: len-prefixed | gensort | text
+                               [default: len-prefixed]
+      --run-format <FMT>       compact | len-prefixed [default: compact]
+                               compact stores fixed-size (gensort) records in runs without
+                               length prefixes; variable-length input is always len-prefixed
+      --tag-sort               For `sort`: sort keys with each record's input position
+                               and gather the records from INPUT (a file) when merging;
+                               holds many more wide records per workspace
+      --io-mode <MODE>         direct | buffered | mmap [default: direct]
+                               direct falls back to buffered where O_DIRECT is rejected
+                               (e.g. tmpfs); mmap always uses the sync backend
+      --io-backend <BACKEND>   sync | uring | threaded[:BUFFERS] [default: sync]
+                               uring keeps several requests in flight per file,
+                               falling back to sync if io_uring is unavailable;
+                               threaded reads ahead / writes behind on a thread per file
+                               with BUFFERS buffers (at least 2) [default: 4]
+      --buffer-size <SIZE>     Bytes per read or write request, e.g. 1M, 8M [default: 4M]
+      --alignment <N>          Direct I/O alignment in bytes, a power of two, or auto to
+                               detect it per file (statx, block size or probe) [default: auto]
+      --durable                Sync run and output files and their directories to disk
+                               before reporting them written
+  -n, --records <N>            Number of records for `gen`
+      --seed <N>               RNG seed for `gen` [default: 0]
+      --key-type <TYPE>        Keys for `gen`: binary | ascii (like `gensort -a`) [default: binary]
+      --distribution <DIST>    Keys for `gen`: random | sorted | reverse | partial[:PCT]
+                               | dup[:DISTINCT] | zipf[:EXPONENT] [default: random]
+  -h, --help                   Print this help
+
+Exit codes:
+  0  success
+  1  I/O or data error
+  2  usage error
+  3  `validate` found unsorted records";
+
+/// Subcommand and its positional arguments.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Runs { input: String },
+    Merge { output: String, runs: Vec<String> },
+    Sort { input: String, output: String },
+    Validate { file: String },
+    Gen { output: String },
+    Help,
+}
+
+/// Flags shared by all subcommands.
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub mem: MemoryBudget,
+    pub heap_cap: Option<usize>,
+    pub threads: usize,
+    pub output_dir: String,
+    pub temp_dir: String,
+    pub prefix: String,
+    pub keep_runs: bool,
+    pub manifest: Option<String>,
+    pub input_format: Option<InputFormat>,
+    pub output_format: OutputFormat,
+    pub key: KeySpec,
+    pub compact_runs: bool,
+    pub tag_sort: bool,
+    pub io: IoOptions,
+    pub records: Option<u64>,
+    pub seed: u64,
+    pub key_type: KeyType,
+    pub distribution: KeyDistribution,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            mem: MemoryBudget::new(1 << 30),
+            heap_cap: None,
+            threads: 1,
+            output_dir: ".".to_string(),
+            temp_dir: ".".to_string(),
+            prefix: "run".to_string(),
+            keep_runs: false,
+            manifest: None,
+            input_format: None,
+            output_format: OutputFormat::default(),
+            key: KeySpec::default(),
+            compact_runs: true,
+            tag_sort: false,
+            io: IoOptions::default(),
+            records: None,
+            seed: 0,
+            key_type: KeyType::default(),
+            distribution: KeyDistribution::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    pub options: Options,
+}
+
+/// Invalid command line. Reported together with the usage text.
+#[derive(Debug, PartialEq)]
+pub struct UsageError(pub String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn usage_err<T>(msg: impl Into<String>) -> Result<T, UsageError> {
+    Err(UsageError(msg.into()))
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, UsageError> {
+    value
+        .parse()
+        .map_err(|_| UsageError(format!("invalid value '{}' for {}", value, flag)))
+}
+
+/// Parse the arguments following the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, UsageError> {
+    let mut options = Options::default();
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Cli {
+                command: Command::Help,
+                options,
+            });
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            positional.push(arg);
+            continue;
+        }
+
+        if arg == "--keep-runs" {
+            options.keep_runs = true;
+            continue;
+        }
+        if arg == "--tag-sort" {
+            options.tag_sort = true;
+            continue;
+        }
+        if arg == "--durable" {
+            options.io.durable = true;
+            continue;
+        }
+
+        // Accept both `--flag value` and `--flag=value`
+        let (flag, inline) = match arg.split_once('=') {
+            Some((f, v)) => (f.to_string(), Some(v.to_string())),
+            None => (arg.clone(), None),
+        };
+        let mut value = || match inline.clone().or_else(|| args.next()) {
+            Some(v) => Ok(v),
+            None => usage_err(format!("missing value for {}", flag)),
+        };
+        match flag.as_str() {
+            "-m" | "--mem" => {
+                let v = value()?;
+                options.mem = MemoryBudget::parse(&v)
+                    .ok_or_else(|| UsageError(format!("invalid size '{}' for {}", v, flag)))?;
+            }
+            "--heap-cap" => options.heap_cap = Some(parse_value(&flag, &value()?)?),
+            "-j" | "--threads" => {
+                options.threads = parse_value(&flag, &value()?)?;
+                if options.threads == 0 {
+                    return usage_err("--threads must be at least 1");
+                }
+            }
+            "-o" | "--output-dir" => options.output_dir = value()?,
+            "-t" | "--temp-dir" => options.temp_dir = value()?,
+            "--prefix" => options.prefix = value()?,
+            "--manifest" => options.manifest = Some(value()?),
+            "--input-format" => options.input_format = Some(value()?.parse().map_err(UsageError)?),
+            "--key" => options.key = value()?.parse().map_err(UsageError)?,
+            "--output-format" => options.output_format = value()?.parse().map_err(UsageError)?,
+            "--run-format" => {
+                options.compact_runs = match value()?.as_str() {
+                    "compact" => true,
+                    "len-prefixed" => false,
+                    v => {
+                        return usage_err(format!(
+                            "unknown run format '{}' (expected compact or len-prefixed)",
+                            v
+                        ));
+                    }
+                }
+            }
+            "--io-mode" => options.io.mode = value()?.parse().map_err(UsageError)?,
+            "--io-backend" => options.io.backend = value()?.parse().map_err(UsageError)?,
+            "--buffer-size" => {
+                let v = value()?;
+                options.io.buffer_size = MemoryBudget::parse(&v)
+                    .map(|b| b.bytes())
+                    .filter(|&b| b > 0)
+                    .ok_or_else(|| UsageError(format!("invalid size '{}' for {}", v, flag)))?;
+            }
+            "--alignment" => {
+                let v = value()?;
+                options.io.alignment = match v.as_str() {
+                    "auto" => None,
+                    _ => match v.parse::<usize>() {
+                        Ok(a) if a.is_power_of_two() => Some(a),
+                        _ => {
+                            return usage_err(format!(
+                                "invalid alignment '{}' (expected a power of two or auto)",
+                                v
+                            ));
+                        }
+                    },
+                };
+            }
+            "-n" | "--records" => options.records = Some(parse_value(&flag, &value()?)?),
+            "--seed" => options.seed = parse_value(&flag, &value()?)?,
+            "--key-type" => options.key_type = value()?.parse().map_err(UsageError)?,
+            "--distribution" => options.distribution = value()?.parse().map_err(UsageError)?,
+            _ => return usage_err(format!("unknown option '{}'", flag)),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let Some(name) = positional.next() else {
+        return usage_err("missing command");
+    };
+    let rest: Vec<String> = positional.collect();
+    let command = match (name.as_str(), rest.as_slice()) {
+        ("runs", [input]) => Command::Runs {
+            input: input.clone(),
+        },
+        ("merge", [output, runs @ ..]) if runs.is_empty() == options.manifest.is_some() => {
+            Command::Merge {
+                output: output.clone(),
+                runs: runs.to_vec(),
+            }
+        }
+        ("sort", [input, output]) => Command::Sort {
+            input: input.clone(),
+            output: output.clone(),
+        },
+        ("validate", [file]) => Command::Validate { file: file.clone() },
+        ("gen", [output]) => {
+            if options.records.is_none() {
+                return usage_err("gen requires --records");
+            }
+            Command::Gen {
+                output: output.clone(),
+            }
+        }
+        ("runs" | "merge" | "sort" | "validate" | "gen", _) => {
+            return usage_err(format!("wrong number of arguments for '{}'", name));
+        }
+        _ => return usage_err(format!("unknown command '{}'", name)),
+    };
+    if matches!(command, Command::Validate { .. })
+        && !options.key.is_format_key()
+        && options.input_format.is_none()
+    {
+        return usage_err("--key for 'validate' needs --input-format");
+    }
+    if options.tag_sort {
+        match &command {
+            Command::Sort { input, .. } if input != "-" => {}
+            Command::Sort { .. } => return usage_err("--tag-sort needs an input file, not stdin"),
+            _ => return usage_err("--tag-sort only applies to 'sort'"),
+        }
+    }
+    Ok(Cli { command, options })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rs::io::{IoBackend, IoMode};
+
+    fn parse_str(s: &str) -> Result<Cli, UsageError> {
+        parse(s.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_sort_with_flags() {
+        let cli = parse_str(
+            "sort in.bin out.bin --mem 2G -t /scratch --keep-runs --output-format=gensort --io-mode buffered",
+        )
+        .unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Sort {
+                input: "in.bin".to_string(),
+                output: "out.bin".to_string()
+            }
+        );
+        assert_eq!(cli.options.mem, MemoryBudget::new(2 << 30));
+        assert_eq!(cli.options.temp_dir, "/scratch");
+        assert!(cli.options.keep_runs);
+        assert_eq!(cli.options.output_format, OutputFormat::Gensort);
+        assert_eq!(cli.options.input_format, None);
+        assert_eq!(cli.options.io.mode, IoMode::Buffered);
+        assert_eq!(cli.options.io.backend, IoBackend::Sync);
+        assert_eq!(cli.options.threads, 1);
+        let cli = parse_str("sort in.bin out.bin --io-backend uring").unwrap();
+        assert_eq!(cli.options.io.backend, IoBackend::Uring);
+        let cli = parse_str("sort in.bin out.bin --io-backend threaded:3").unwrap();
+        assert_eq!(cli.options.io.backend, IoBackend::Threaded { buffers: 3 });
+        assert!(parse_str("sort in.bin out.bin --io-backend aio").is_err());
+        let cli = parse_str("sort in.bin out.bin --io-mode mmap").unwrap();
+        assert_eq!(cli.options.io.mode, IoMode::Mmap);
+        assert!(parse_str("sort in.bin out.bin --io-mode odirect").is_err());
+
+        let io = parse_str("sort in.bin out.bin --buffer-size 8M --alignment 512")
+            .unwrap()
+            .options
+            .io;
+        assert_eq!((io.buffer_size, io.alignment), (8 << 20, Some(512)));
+        let io = parse_str("sort in.bin out.bin --alignment auto")
+            .unwrap()
+            .options
+            .io;
+        assert_eq!(io.alignment, None);
+        assert!(parse_str("sort in.bin out.bin --alignment 1000").is_err());
+        assert!(parse_str("sort in.bin out.bin --buffer-size 0").is_err());
+        assert_eq!(parse_str("runs in.bin -j 8").unwrap().options.threads, 8);
+        assert!(parse_str("runs in.bin -j 0").is_err());
+        assert!(parse_str("runs in.bin").unwrap().options.compact_runs);
+        let cli = parse_str("runs in.bin --run-format len-prefixed").unwrap();
+        assert!(!cli.options.compact_runs);
+        assert!(parse_str("runs in.bin --run-format raw").is_err());
+        assert!(!cli.options.io.durable);
+        assert!(!cli.options.tag_sort);
+        assert!(
+            parse_str("sort in.bin out.bin --tag-sort")
+                .unwrap()
+                .options
+                .tag_sort
+        );
+        assert!(parse_str("sort - out.bin --tag-sort").is_err());
+        assert!(parse_str("runs in.bin --tag-sort").is_err());
+        assert!(
+            parse_str("sort in.bin out.bin --durable")
+                .unwrap()
+                .options
+                .io
+                .durable
+        );
+    }
+
+    #[test]
+    fn test_parse_merge_many_runs() {
+        let cli = parse_str("merge out.bin r0.bin r1.bin r2.bin").unwrap();
+        match cli.command {
+            Command::Merge { output, runs } => {
+                assert_eq!(output, "out.bin");
+                assert_eq!(runs.len(), 3);
+            }
+            other => panic!("expected merge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_stdio_paths() {
+        let cli = parse_str("sort - - --mem 64M").unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Sort {
+                input: "-".to_string(),
+                output: "-".to_string()
+            }
+        );
+        assert_eq!(cli.options.mem, MemoryBudget::new(64 << 20));
+    }
+
+    #[test]
+    fn test_parse_text_formats() {
+        let cli =
+            parse_str("sort in.txt out.txt --input-format text:tab --output-format text").unwrap();
+        assert_eq!(
+            cli.options.input_format,
+            Some(InputFormat::Text {
+                delimiter: Some(b'\t')
+            })
+        );
+        assert_eq!(cli.options.output_format, OutputFormat::Text);
+        let cli = parse_str("validate out.txt --input-format text:,").unwrap();
+        assert_eq!(
+            cli.options.input_format,
+            Some(InputFormat::Text {
+                delimiter: Some(b',')
+            })
+        );
+        let cli = parse_str("validate out.txt --input-format text").unwrap();
+        assert_eq!(
+            cli.options.input_format,
+            Some(InputFormat::Text { delimiter: None })
+        );
+    }
+
+    #[test]
+    fn test_parse_key_spec() {
+        let cli = parse_str("sort in.bin out.bin --key 4:4:uint-le:desc,0:4").unwrap();
+        assert_eq!(cli.options.key.fields().len(), 2);
+        assert!(
+            parse_str("sort in.bin out.bin")
+                .unwrap()
+                .options
+                .key
+                .is_format_key()
+        );
+        assert!(parse_str("sort in.bin out.bin --key 0:int-be").is_err());
+        assert!(parse_str("validate out.bin --key 0:4").is_err());
+        assert!(parse_str("validate out.bin --key 0:4 --input-format gensort").is_ok());
+    }
+
+    #[test]
+    fn test_parse_help() {
+        assert_eq!(parse_str("--help").unwrap().command, Command::Help);
+        assert_eq!(parse_str("sort a b -h").unwrap().command, Command::Help);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_str("").is_err());
+        assert!(parse_str("frobnicate x").is_err());
+        assert!(parse_str("sort only_input").is_err());
+        assert!(parse_str("merge out.bin").is_err());
+        assert!(parse_str("merge out.bin --manifest run.manifest").is_ok());
+        assert!(parse_str("merge out.bin r0.bin --manifest run.manifest").is_err());
+        assert!(parse_str("runs in.bin --mem lots").is_err());
+        assert!(parse_str("runs in.bin --input-format csv").is_err());
+        assert!(parse_str("runs in.bin --input-format text:ab").is_err());
+        assert!(parse_str("runs in.bin --bogus").is_err());
+        assert!(parse_str("runs in.bin --mem").is_err());
+        assert!(parse_str("gen out.bin").is_err());
+        assert!(parse_str("gen out.bin -n 10").is_ok());
+        assert!(parse_str("gen out.bin -n 10 --distribution zipf:0").is_err());
+        assert!(parse_str("gen out.bin -n 10 --key-type hex").is_err());
+    }
+
+    #[test]
+    fn test_parse_gen_options() {
+        let cli = parse_str("gen out.bin -n 1000 --seed 9 --key-type ascii --distribution=dup:5")
+            .unwrap();
+        assert_eq!(cli.options.records, Some(1000));
+        assert_eq!(cli.options.seed, 9);
+        assert_eq!(cli.options.key_type, KeyType::Ascii);
+        assert_eq!(
+            cli.options.distribution,
+            KeyDistribution::Duplicates { distinct: 5 }
+        );
+    }
+}
@@ -0,0 +1,401 @@
+//! Record formats: how records are laid out in input, run and output files.
+
+use crate::io::{
+    InputFormat, OutputFormat, RecordWrite, read_gensort_record, write_gensort_record,
+    write_len_key_len_payload,
+};
+use crate::key_spec::KeySpec;
+use crate::record::Rec;
+use std::io::{self, BufRead, Read};
+
+/// Layout of records in a file or stream.
+///
+/// Decoding extracts the key: it splits each stored record into the `key` that
+/// records are ordered by and the `payload` carried along with it. Encoding
+/// writes both back in the format's layout, so a record decoded and encoded by
+/// the same format comes out byte for byte as it went in. Replacement selection
+/// and the merge only compare keys, so adding a format needs no changes there.
+pub trait RecordFormat: Clone + std::fmt::Debug + Send + Sync {
+    /// Read the next record from `r`. None on clean EOF; a record cut off by the
+    /// end of the input is an `UnexpectedEof` error.
+    fn decode<R: BufRead>(&self, r: &mut R) -> io::Result<Option<Rec>>;
+
+    /// Write `rec` to `w`. Records the format cannot hold are `InvalidInput` errors.
+    fn encode<W: RecordWrite>(&self, w: &mut W, rec: &Rec) -> io::Result<()>;
+
+    /// Bytes each encoded record takes besides its key and payload, if that is the
+    /// same for every record. The parallel merge needs it to size output regions.
+    fn overhead(&self) -> Option<u64> {
+        None
+    }
+
+    /// Bytes `encode` writes for `rec`, for formats with an `overhead`.
+    fn encoded_size(&self, rec: &Rec) -> u64 {
+        self.overhead().unwrap_or(0) + rec.size() as u64
+    }
+
+    /// Key and payload size of every record, if the format only holds records of
+    /// one size. Replacement selection then writes compact runs without length
+    /// prefixes.
+    fn fixed_size(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Key spec the format builds keys from instead of its own key, as `Keyed`
+    /// does. Runs declare it, and the merge takes only runs with the spec of its
+    /// format.
+    fn key_spec(&self) -> Option<&KeySpec> {
+        None
+    }
+}
+
+/// Fixed 100-byte gensort records: a 10-byte key and a 90-byte payload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Gensort;
+
+impl RecordFormat for Gensort {
+    fn decode<R: BufRead>(&self, r: &mut R) -> io::Result<Option<Rec>> {
+        read_gensort_record(r)
+    }
+
+    fn encode<W: RecordWrite>(&self, w: &mut W, rec: &Rec) -> io::Result<()> {
+        write_gensort_record(w, rec)
+    }
+
+    fn overhead(&self) -> Option<u64> {
+        Some(0)
+    }
+
+    fn fixed_size(&self) -> Option<(usize, usize)> {
+        Some((Rec::KEY_SIZE, Rec::PAYLOAD_SIZE))
+    }
+}
+
+/// Variable-length `[u32 LE key_len][key][u32 LE payload_len][payload]` records,
+/// the layout of run files holding records of varying size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LenPrefixed {
+    /// Longest key accepted, guarding against corrupt lengths.
+    pub max_key: usize,
+    /// Longest payload accepted.
+    pub max_payload: usize,
+}
+
+impl Default for LenPrefixed {
+    fn default() -> Self {
+        Self {
+            max_key: Rec::MAX_KEY_SIZE,
+            max_payload: Rec::MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+
+impl LenPrefixed {
+    /// Bytes of the two length fields.
+    pub const OVERHEAD: u64 = 8;
+
+    /// Read a length field followed by that many bytes, up to `max`. None on
+    /// clean EOF if `eof_ok`.
+    fn read_field<R: Read>(
+        r: &mut R,
+        what: &str,
+        max: usize,
+        eof_ok: bool,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let truncated = || {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} missing (truncated length-prefixed record)", what),
+            )
+        };
+        let mut len = [0u8; 4];
+        let mut read = 0;
+        while read < len.len() {
+            match r.read(&mut len[read..]) {
+                Ok(0) if read == 0 && eof_ok => return Ok(None),
+                Ok(0) => return Err(truncated()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} length {} exceeds limit {}", what, len, max),
+            ));
+        }
+        let mut field = vec![0u8; len];
+        r.read_exact(&mut field).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => truncated(),
+            _ => e,
+        })?;
+        Ok(Some(field))
+    }
+}
+
+impl RecordFormat for LenPrefixed {
+    fn decode<R: BufRead>(&self, r: &mut R) -> io::Result<Option<Rec>> {
+        let Some(key) = Self::read_field(r, "key", self.max_key, true)? else {
+            return Ok(None);
+        };
+        let payload = Self::read_field(r, "payload", self.max_payload, false)?.unwrap();
+        Ok(Some(Rec::new(key, payload)))
+    }
+
+    fn encode<W: RecordWrite>(&self, w: &mut W, rec: &Rec) -> io::Result<()> {
+        write_len_key_len_payload(w, rec)
+    }
+
+    fn overhead(&self) -> Option<u64> {
+        Some(Self::OVERHEAD)
+    }
+}
+
+/// Newline-terminated text lines. The key is the line up to the first
+/// `delimiter` byte, or the whole line without one; the payload is the rest of
+/// the line from the delimiter on. A missing newline after the last line is
+/// accepted, and encoding always ends lines with one. Keys longer than
+/// `Rec::MAX_KEY_SIZE` are rejected like those of other formats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextLines {
+    pub delimiter: Option<u8>,
+}
+
+impl TextLines {
+    /// Lines are split at the first `delimiter`, e.g. `b'\t'` to sort by the first
+    /// column.
+    pub fn with_delimiter(delimiter: u8) -> Self {
+        Self {
+            delimiter: Some(delimiter),
+        }
+    }
+}
+
+impl RecordFormat for TextLines {
+    fn decode<R: BufRead>(&self, r: &mut R) -> io::Result<Option<Rec>> {
+        let mut line = Vec::new();
+        let limit = (Rec::MAX_KEY_SIZE + Rec::MAX_PAYLOAD_SIZE) as u64;
+        r.take(limit + 1).read_until(b'\n', &mut line)?;
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        } else if line.len() as u64 > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("text line longer than {} bytes", limit),
+            ));
+        }
+        let split = self
+            .delimiter
+            .and_then(|d| line.iter().position(|&b| b == d))
+            .unwrap_or(line.len());
+        if split > Rec::MAX_KEY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "text line key of {} bytes exceeds limit {}",
+                    split,
+                    Rec::MAX_KEY_SIZE
+                ),
+            ));
+        }
+        if line.len() - split > Rec::MAX_PAYLOAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "text line payload of {} bytes exceeds limit {}",
+                    line.len() - split,
+                    Rec::MAX_PAYLOAD_SIZE
+                ),
+            ));
+        }
+        let payload = line.split_off(split);
+        Ok(Some(Rec::new(line, payload)))
+    }
+
+    fn encode<W: RecordWrite>(&self, w: &mut W, rec: &Rec) -> io::Result<()> {
+        if rec.key.contains(&b'\n') || rec.payload.contains(&b'\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record containing a newline cannot be written as a text line",
+            ));
+        }
+        w.write_all(&rec.key)?;
+        w.write_all(&rec.payload)?;
+        w.write_all(b"\n")
+    }
+
+    fn overhead(&self) -> Option<u64> {
+        Some(1)
+    }
+}
+
+impl RecordFormat for InputFormat {
+    fn decode<R: BufRead>(&self, r: &mut R) -> io::Result<Option<Rec>> {
+        match *self {
+            InputFormat::Gensort => Gensort.decode(r),
+            InputFormat::LenPrefixed => LenPrefixed::default().decode(r),
+            InputFormat::Text { delimiter } => TextLines { delimiter }.decode(r),
+        }
+    }
+
+    fn encode<W: RecordWrite>(&self, w: &mut W, rec: &Rec) -> io::Result<()> {
+        match *self {
+            InputFormat::Gensort => Gensort.encode(w, rec),
+            InputFormat::LenPrefixed => LenPrefixed::default().encode(w, rec),
+            InputFormat::Text { delimiter } => TextLines { delimiter }.encode(w, rec),
+        }
+    }
+
+    fn overhead(&self) -> Option<u64> {
+        match *self {
+            InputFormat::Gensort => Gensort.overhead(),
+            InputFormat::LenPrefixed => LenPrefixed::default().overhead(),
+            InputFormat::Text { delimiter } => TextLines { delimiter }.overhead(),
+        }
+    }
+
+    fn fixed_size(&self) -> Option<(usize, usize)> {
+        match *self {
+            InputFormat::Gensort => Gensort.fixed_size(),
+            InputFormat::LenPrefixed => LenPrefixed::default().fixed_size(),
+            InputFormat::Text { delimiter } => TextLines { delimiter }.fixed_size(),
+        }
+    }
+}
+
+impl From<OutputFormat> for InputFormat {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::LenPrefixed => InputFormat::LenPrefixed,
+            OutputFormat::Gensort => InputFormat::Gensort,
+            OutputFormat::Text => InputFormat::Text { delimiter: None },
+        }
+    }
+}
+
+impl RecordFormat for OutputFormat {
+    fn decode<R: BufRead>(&self, r: &mut R) -> io::Result<Option<Rec>> {
+        InputFormat::from(*self).decode(r)
+    }
+
+    fn encode<W: RecordWrite>(&self, w: &mut W, rec: &Rec) -> io::Result<()> {
+        InputFormat::from(*self).encode(w, rec)
+    }
+
+    fn overhead(&self) -> Option<u64> {
+        InputFormat::from(*self).overhead()
+    }
+
+    fn fixed_size(&self) -> Option<(usize, usize)> {
+        InputFormat::from(*self).fixed_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufWriter, Cursor};
+
+    fn roundtrip<F: RecordFormat>(format: F, data: &[u8]) -> Vec<Rec> {
+        let mut rdr = Cursor::new(data);
+        let mut recs = Vec::new();
+        while let Some(rec) = format.decode(&mut rdr).unwrap() {
+            recs.push(rec);
+        }
+        let mut out = BufWriter::new(Vec::new());
+        for rec in &recs {
+            format.encode(&mut out, rec).unwrap();
+        }
+        assert_eq!(out.into_inner().unwrap(), data, "{:?}", format);
+        recs
+    }
+
+    #[test]
+    fn test_builtin_formats_roundtrip() {
+        let mut gensort = vec![1u8; 10];
+        gensort.extend_from_slice(&[2u8; 90]);
+        let recs = roundtrip(Gensort, &gensort);
+        assert_eq!(recs, vec![Rec::new([1u8; 10], [2u8; 90])]);
+
+        let mut prefixed = Vec::new();
+        prefixed.extend_from_slice(&2u32.to_le_bytes());
+        prefixed.extend_from_slice(b"ab");
+        prefixed.extend_from_slice(&0u32.to_le_bytes());
+        let recs = roundtrip(LenPrefixed::default(), &prefixed);
+        assert_eq!(recs, vec![Rec::new(b"ab".to_vec(), vec![])]);
+
+        let text = b"pear\t3\napple\t10\n\nfig\n";
+        let recs = roundtrip(TextLines::default(), text);
+        assert_eq!(recs[0], Rec::new(b"pear\t3".to_vec(), vec![]));
+        assert_eq!(recs[2], Rec::new(vec![], vec![]));
+        let recs = roundtrip(TextLines::with_delimiter(b'\t'), text);
+        assert_eq!(recs[1], Rec::new(b"apple".to_vec(), b"\t10".to_vec()));
+        assert_eq!(recs[3], Rec::new(b"fig".to_vec(), vec![]));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let err = Gensort.decode(&mut Cursor::new([0u8; 50])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut prefixed = 3u32.to_le_bytes().to_vec();
+        prefixed.extend_from_slice(b"abc");
+        let err = LenPrefixed::default()
+            .decode(&mut Cursor::new(&prefixed))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let limited = LenPrefixed {
+            max_key: 2,
+            ..Default::default()
+        };
+        let err = limited.decode(&mut Cursor::new(&prefixed)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // The last line may lack its newline
+        let mut rdr = Cursor::new(b"b\na");
+        assert_eq!(
+            TextLines::default().decode(&mut rdr).unwrap().unwrap().key,
+            b"b"
+        );
+        assert_eq!(
+            TextLines::default().decode(&mut rdr).unwrap().unwrap().key,
+            b"a"
+        );
+        assert_eq!(TextLines::default().decode(&mut rdr).unwrap(), None);
+
+        // A line without a delimiter is all key, which has the usual limit
+        let mut long = vec![b'x'; Rec::MAX_KEY_SIZE + 1];
+        long.push(b'\n');
+        let err = TextLines::default()
+            .decode(&mut Cursor::new(&long))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        long[10] = b'\t';
+        let rec = TextLines::with_delimiter(b'\t')
+            .decode(&mut Cursor::new(&long))
+            .unwrap()
+            .unwrap();
+        assert_eq!(rec.key.len(), 10);
+
+        // So is the rest of the line, from the delimiter on
+        let mut long = b"k\t".to_vec();
+        long.resize(2 + Rec::MAX_PAYLOAD_SIZE, b'x');
+        long.push(b'\n');
+        let err = TextLines::with_delimiter(b'\t')
+            .decode(&mut Cursor::new(&long))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&Rec::MAX_PAYLOAD_SIZE.to_string()));
+
+        let mut out = BufWriter::new(Vec::new());
+        let err = TextLines::default()
+            .encode(&mut out, &Rec::new(b"a\nb".to_vec(), vec![]))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
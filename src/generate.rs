@@ -0,0 +1,501 @@
+//! gensort-compatible input generator.
+//!
+//! Records follow gensort's layouts. Binary records (`gensort`) are a 10-byte key,
+//! `00 11`, the record number as 32 hex digits, `88 99 AA BB`, 48 filler bytes and
+//! `CC DD EE FF`. ASCII records (`gensort -a`) are a 10-character printable key, two
+//! spaces, the record number as 32 hex digits, two spaces, 52 filler bytes and `\r\n`.
+//! Keys come from a seeded generator rather than gensort's own random stream, and can
+//! be skewed with a `KeyDistribution`.
+
+use crate::io::{IoMode, RecordWrite, open_writer, write_gensort_record};
+use crate::record::Rec;
+use std::io;
+use std::str::FromStr;
+
+/// Small seedable PRNG (SplitMix64).
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform float in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let r = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&r[..chunk.len()]);
+        }
+    }
+}
+
+/// Key alphabet: any byte (`gensort`) or printable ASCII `' '..='~'` (`gensort -a`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyType {
+    #[default]
+    Binary,
+    Ascii,
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(Self::Binary),
+            "ascii" => Ok(Self::Ascii),
+            _ => Err(format!("unknown key type '{}' (binary, ascii)", s)),
+        }
+    }
+}
+
+/// How keys are distributed over the generated file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyDistribution {
+    /// Independent uniform keys.
+    #[default]
+    Random,
+    /// Ascending keys, already in sorted order.
+    Sorted,
+    /// Descending keys, the worst case for replacement selection.
+    Reverse,
+    /// Ascending keys where each record keeps its sorted key with probability
+    /// `percent`/100 and gets a random key otherwise.
+    PartiallySorted { percent: u8 },
+    /// Uniform keys drawn from `distinct` different values.
+    Duplicates { distinct: u64 },
+    /// Keys drawn from one value per record with Zipf-distributed frequencies:
+    /// the k-th most common key occurs proportionally to 1/k^exponent.
+    Zipf { exponent: f64 },
+}
+
+impl FromStr for KeyDistribution {
+    type Err = String;
+
+    /// `random`, `sorted`, `reverse`, `partial[:PERCENT]`, `dup[:DISTINCT]` or
+    /// `zipf[:EXPONENT]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let bad = || format!("invalid key distribution '{}'", s);
+        let dist = match (name, arg) {
+            ("random", None) => Self::Random,
+            ("sorted", None) => Self::Sorted,
+            ("reverse", None) => Self::Reverse,
+            ("partial", arg) => Self::PartiallySorted {
+                percent: arg.map_or(Ok(90), str::parse).map_err(|_| bad())?,
+            },
+            ("dup", arg) => Self::Duplicates {
+                distinct: arg.map_or(Ok(100), str::parse).map_err(|_| bad())?,
+            },
+            ("zipf", arg) => Self::Zipf {
+                exponent: arg.map_or(Ok(1.0), str::parse).map_err(|_| bad())?,
+            },
+            _ => {
+                return Err(format!(
+                    "unknown key distribution '{}' (random, sorted, reverse, partial[:PCT], dup[:N], zipf[:S])",
+                    s
+                ));
+            }
+        };
+        match dist {
+            Self::PartiallySorted { percent } if percent > 100 => Err(bad()),
+            Self::Duplicates { distinct: 0 } => Err(bad()),
+            Self::Zipf { exponent } if !(exponent > 0.0 && exponent.is_finite()) => Err(bad()),
+            _ => Ok(dist),
+        }
+    }
+}
+
+/// Zipf sampler over `1..=n` using rejection-inversion (Hörmann and Derflinger),
+/// constant time per sample for any `n`.
+struct Zipf {
+    n: f64,
+    s: f64,
+    h_integral_x1: f64,
+    h_integral_n: f64,
+    accept: f64,
+}
+
+impl Zipf {
+    fn new(n: u64, s: f64) -> Self {
+        let mut z = Self {
+            n: n as f64,
+            s,
+            h_integral_x1: 0.0,
+            h_integral_n: 0.0,
+            accept: 0.0,
+        };
+        z.h_integral_x1 = z.h_integral(1.5) - 1.0;
+        z.h_integral_n = z.h_integral(z.n + 0.5);
+        z.accept = 2.0 - z.h_integral_inv(z.h_integral(2.5) - z.h(2.0));
+        z
+    }
+
+    fn h(&self, x: f64) -> f64 {
+        (-self.s * x.ln()).exp()
+    }
+
+    fn h_integral(&self, x: f64) -> f64 {
+        let log_x = x.ln();
+        helper2((1.0 - self.s) * log_x) * log_x
+    }
+
+    fn h_integral_inv(&self, x: f64) -> f64 {
+        let t = (x * (1.0 - self.s)).max(-1.0);
+        (helper1(t) * x).exp()
+    }
+
+    /// Rank in `1..=n`, 1 being the most frequent.
+    fn sample(&self, rng: &mut Rng) -> u64 {
+        loop {
+            let u = self.h_integral_n + rng.next_f64() * (self.h_integral_x1 - self.h_integral_n);
+            let x = self.h_integral_inv(u);
+            let k = (x + 0.5).floor().clamp(1.0, self.n);
+            if k - x <= self.accept || u >= self.h_integral(k + 0.5) - self.h(k) {
+                return k as u64;
+            }
+        }
+    }
+}
+
+/// `ln(1 + x) / x`, accurate near 0.
+fn helper1(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.ln_1p() / x
+    } else {
+        1.0 - x * (0.5 - x * (1.0 / 3.0 - 0.25 * x))
+    }
+}
+
+/// `(exp(x) - 1) / x`, accurate near 0.
+fn helper2(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.exp_m1() / x
+    } else {
+        1.0 + x * 0.5 * (1.0 + x / 3.0 * (1.0 + 0.25 * x))
+    }
+}
+
+/// Scatter `v` over the key space, so duplicate and Zipf keys are not clustered by rank.
+fn mix(v: u64) -> u64 {
+    Rng::new(v).next_u64()
+}
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+const ASCII_KEY_BASE: u128 = 95;
+
+/// Generator for `records` gensort records. The same settings give the same records.
+#[derive(Clone, Debug)]
+pub struct Generator {
+    records: u64,
+    seed: u64,
+    key_type: KeyType,
+    distribution: KeyDistribution,
+}
+
+impl Generator {
+    pub fn new(records: u64, seed: u64) -> Self {
+        Self {
+            records,
+            seed,
+            key_type: KeyType::default(),
+            distribution: KeyDistribution::default(),
+        }
+    }
+
+    /// Set the key alphabet and record layout (binary by default).
+    pub fn with_key_type(mut self, key_type: KeyType) -> Self {
+        self.key_type = key_type;
+        self
+    }
+
+    /// Set the key distribution (uniform random by default).
+    pub fn with_distribution(mut self, distribution: KeyDistribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Number of distinct key values.
+    fn key_space(&self) -> u128 {
+        match self.key_type {
+            KeyType::Binary => 1 << (8 * Rec::KEY_SIZE),
+            KeyType::Ascii => ASCII_KEY_BASE.pow(Rec::KEY_SIZE as u32),
+        }
+    }
+
+    /// Key for `value` in `0..key_space()`; keys order like their values.
+    fn encode_key(&self, mut value: u128) -> [u8; Rec::KEY_SIZE] {
+        let mut key = [0u8; Rec::KEY_SIZE];
+        match self.key_type {
+            KeyType::Binary => {
+                key.copy_from_slice(&value.to_be_bytes()[16 - Rec::KEY_SIZE..]);
+            }
+            KeyType::Ascii => {
+                for b in key.iter_mut().rev() {
+                    *b = b' ' + (value % ASCII_KEY_BASE) as u8;
+                    value /= ASCII_KEY_BASE;
+                }
+            }
+        }
+        key
+    }
+
+    /// Key of rank `rank` out of `n`, spread evenly over the key space.
+    fn ranked_key(&self, rank: u64, n: u64) -> [u8; Rec::KEY_SIZE] {
+        self.encode_key(rank as u128 * (self.key_space() / n.max(1) as u128))
+    }
+
+    fn random_key(&self, rng: &mut Rng) -> [u8; Rec::KEY_SIZE] {
+        let r = ((rng.next_u64() as u128) << 64) | rng.next_u64() as u128;
+        self.encode_key(r % self.key_space())
+    }
+
+    fn key(&self, rng: &mut Rng, zipf: Option<&Zipf>, idx: u64) -> [u8; Rec::KEY_SIZE] {
+        match self.distribution {
+            KeyDistribution::Random => self.random_key(rng),
+            KeyDistribution::Sorted => self.ranked_key(idx, self.records),
+            KeyDistribution::Reverse => {
+                self.ranked_key(self.records.saturating_sub(idx + 1), self.records)
+            }
+            KeyDistribution::PartiallySorted { percent } => {
+                if rng.next_u64() % 100 < percent as u64 {
+                    self.ranked_key(idx, self.records)
+                } else {
+                    self.random_key(rng)
+                }
+            }
+            KeyDistribution::Duplicates { distinct } => {
+                let v = mix(self.seed ^ (rng.next_u64() % distinct));
+                self.encode_key(v as u128 * (self.key_space() >> 64))
+            }
+            KeyDistribution::Zipf { .. } => {
+                let rank = zipf.map_or(1, |z| z.sample(rng));
+                let v = mix(self.seed ^ rank);
+                self.encode_key(v as u128 * (self.key_space() >> 64))
+            }
+        }
+    }
+
+    /// Payload of record `idx` in the gensort layout of the key type.
+    fn payload(&self, rng: &mut Rng, idx: u64) -> [u8; Rec::PAYLOAD_SIZE] {
+        let mut p = [0u8; Rec::PAYLOAD_SIZE];
+        let (brk1, brk2, filler_len, end): (&[u8], &[u8], usize, &[u8]) = match self.key_type {
+            KeyType::Binary => (
+                &[0x00, 0x11],
+                &[0x88, 0x99, 0xAA, 0xBB],
+                48,
+                &[0xCC, 0xDD, 0xEE, 0xFF],
+            ),
+            KeyType::Ascii => (b"  ", b"  ", 52, b"\r\n"),
+        };
+        let mut pos = 0;
+        let mut put = |bytes: &[u8]| {
+            p[pos..pos + bytes.len()].copy_from_slice(bytes);
+            pos += bytes.len();
+        };
+        put(brk1);
+        let number = format!("{:032X}", idx);
+        put(number.as_bytes());
+        put(brk2);
+        // Filler: hex digits, each repeated four times
+        let mut bits = rng.next_u64();
+        for _ in 0..filler_len / 4 {
+            put(&[HEX[(bits & 0xF) as usize]; 4]);
+            bits >>= 4;
+        }
+        put(end);
+        p
+    }
+
+    /// Write every record to `path` in gensort layout.
+    pub fn write_file(&self, path: &str, io_mode: IoMode) -> io::Result<()> {
+        let mut w = open_writer(path, io_mode)?;
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+
+    /// Write every record to `w` in gensort layout, without flushing it.
+    pub fn write_to<W: RecordWrite>(&self, w: &mut W) -> io::Result<()> {
+        for rec in self.records() {
+            write_gensort_record(w, &rec)?;
+        }
+        Ok(())
+    }
+
+    /// Iterate over the records in file order.
+    pub fn records(&self) -> impl Iterator<Item = Rec> + '_ {
+        let mut rng = Rng::new(self.seed);
+        let zipf = match self.distribution {
+            KeyDistribution::Zipf { exponent } => Some(Zipf::new(self.records.max(1), exponent)),
+            _ => None,
+        };
+        (0..self.records).map(move |idx| {
+            let key = self.key(&mut rng, zipf.as_ref(), idx);
+            Rec::new(key, self.payload(&mut rng, idx))
+        })
+    }
+}
+
+/// Write `records` uniformly random binary gensort records to `path`.
+/// The same seed gives the same file.
+pub fn generate_file(path: &str, records: u64, seed: u64, io_mode: IoMode) -> io::Result<()> {
+    Generator::new(records, seed).write_file(path, io_mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(generator: &Generator) -> Vec<Vec<u8>> {
+        generator.records().map(|r| r.key).collect()
+    }
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let a: Vec<u64> = (0..4)
+            .scan(Rng::new(7), |r, _| Some(r.next_u64()))
+            .collect();
+        let b: Vec<u64> = (0..4)
+            .scan(Rng::new(7), |r, _| Some(r.next_u64()))
+            .collect();
+        let c: Vec<u64> = (0..4)
+            .scan(Rng::new(8), |r, _| Some(r.next_u64()))
+            .collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_binary_record_layout() {
+        let rec = Generator::new(300, 1).records().nth(258).unwrap();
+        assert_eq!(rec.key.len(), Rec::KEY_SIZE);
+        let p = &rec.payload;
+        assert_eq!(p.len(), Rec::PAYLOAD_SIZE);
+        assert_eq!(&p[..2], &[0x00, 0x11]);
+        assert_eq!(&p[2..34], b"00000000000000000000000000000102");
+        assert_eq!(&p[34..38], &[0x88, 0x99, 0xAA, 0xBB]);
+        assert!(p[38..86].chunks(4).all(|c| c.iter().all(|&b| b == c[0])));
+        assert_eq!(&p[86..], &[0xCC, 0xDD, 0xEE, 0xFF]);
+    }
+
+    #[test]
+    fn test_ascii_record_layout() {
+        let generator = Generator::new(50, 9).with_key_type(KeyType::Ascii);
+        for (idx, rec) in generator.records().enumerate() {
+            assert!(rec.key.iter().all(|b| (b' '..=b'~').contains(b)));
+            let p = &rec.payload;
+            assert_eq!(&p[..2], b"  ");
+            assert_eq!(&p[2..34], format!("{:032X}", idx).as_bytes());
+            assert_eq!(&p[34..36], b"  ");
+            assert!(p[36..88].iter().all(u8::is_ascii_hexdigit));
+            assert_eq!(&p[88..], b"\r\n");
+        }
+    }
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let dist = KeyDistribution::Zipf { exponent: 1.2 };
+        let a = Generator::new(100, 5).with_distribution(dist);
+        let b = Generator::new(100, 5).with_distribution(dist);
+        assert_eq!(
+            a.records().collect::<Vec<_>>(),
+            b.records().collect::<Vec<_>>()
+        );
+        assert_ne!(
+            keys(&a),
+            keys(&Generator::new(100, 6).with_distribution(dist))
+        );
+    }
+
+    #[test]
+    fn test_sorted_and_reverse() {
+        for key_type in [KeyType::Binary, KeyType::Ascii] {
+            let sorted = keys(
+                &Generator::new(1000, 1)
+                    .with_key_type(key_type)
+                    .with_distribution(KeyDistribution::Sorted),
+            );
+            assert!(sorted.windows(2).all(|w| w[0] < w[1]));
+
+            let reverse = keys(
+                &Generator::new(1000, 1)
+                    .with_key_type(key_type)
+                    .with_distribution(KeyDistribution::Reverse),
+            );
+            assert!(reverse.windows(2).all(|w| w[0] > w[1]));
+        }
+    }
+
+    #[test]
+    fn test_partially_sorted() {
+        let generator = Generator::new(10_000, 3)
+            .with_distribution(KeyDistribution::PartiallySorted { percent: 80 });
+        let sorted = keys(&Generator::new(10_000, 3).with_distribution(KeyDistribution::Sorted));
+        let in_place = keys(&generator)
+            .iter()
+            .zip(&sorted)
+            .filter(|(a, b)| a == b)
+            .count();
+        assert!((7_500..8_500).contains(&in_place), "{}", in_place);
+    }
+
+    #[test]
+    fn test_duplicates_and_zipf() {
+        let mut dup = keys(
+            &Generator::new(5_000, 2)
+                .with_distribution(KeyDistribution::Duplicates { distinct: 10 }),
+        );
+        dup.sort();
+        dup.dedup();
+        assert_eq!(dup.len(), 10);
+
+        // With exponent 1 over 5000 ranks the top key has about 1/H(5000) ≈ 11% of records
+        let mut zipf = keys(
+            &Generator::new(5_000, 2).with_distribution(KeyDistribution::Zipf { exponent: 1.0 }),
+        );
+        zipf.sort();
+        let mut counts: Vec<usize> = zipf.chunk_by(|a, b| a == b).map(|run| run.len()).collect();
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        assert!((400..750).contains(&counts[0]), "{}", counts[0]);
+        assert!(2 * counts[0] > 3 * counts[1]);
+        assert!(counts.len() > 500);
+    }
+
+    #[test]
+    fn test_parse_distribution() {
+        assert_eq!("random".parse(), Ok(KeyDistribution::Random));
+        assert_eq!("reverse".parse(), Ok(KeyDistribution::Reverse));
+        assert_eq!(
+            "partial".parse(),
+            Ok(KeyDistribution::PartiallySorted { percent: 90 })
+        );
+        assert_eq!(
+            "dup:7".parse(),
+            Ok(KeyDistribution::Duplicates { distinct: 7 })
+        );
+        assert_eq!(
+            "zipf:0.8".parse(),
+            Ok(KeyDistribution::Zipf { exponent: 0.8 })
+        );
+        assert!("partial:101".parse::<KeyDistribution>().is_err());
+        assert!("dup:0".parse::<KeyDistribution>().is_err());
+        assert!("zipf:-1".parse::<KeyDistribution>().is_err());
+        assert!("sorted:3".parse::<KeyDistribution>().is_err());
+        assert!("normal".parse::<KeyDistribution>().is_err());
+    }
+}
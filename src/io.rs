@@ -1,17 +1,250 @@
+use crate::background::{ReadThread, WriteThread};
+use crate::checksum::Crc32;
+use crate::format::RecordFormat;
+use crate::key_spec::KeySpec;
 use crate::record::Rec;
+use crate::uring::{ReadAhead, WriteBehind};
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write as IoWrite};
-use std::path::PathBuf;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write as IoWrite};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
 
 #[cfg(target_os = "linux")]
 use std::os::unix::fs::OpenOptionsExt;
 
-/// Direct I/O alignment requirement (typically 512 or 4096)
-const ALIGNMENT: usize = 4096;
+/// Direct I/O alignment used when the device's cannot be detected, and for
+/// buffered I/O (typically 512 or 4096)
+pub const DEFAULT_ALIGNMENT: usize = 4096;
+/// Bytes per read or write request by default.
+pub const DEFAULT_BUFFER_SIZE: usize = 4 << 20;
+/// Number of requests the io_uring backend keeps in flight per file.
+pub const URING_QUEUE_DEPTH: usize = 4;
+/// Buffers per file of `IoBackend::Threaded` when none are given.
+pub const DEFAULT_IO_BUFFERS: usize = 4;
+
+/// How reads and writes are issued to the kernel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoBackend {
+    /// One blocking `read` / `write` call per buffer.
+    #[default]
+    Sync,
+    /// io_uring with `URING_QUEUE_DEPTH` buffer-sized requests in flight
+    /// (Linux 5.6+). Files fall back to `Sync` when io_uring cannot be set up.
+    Uring,
+    /// A read-ahead or write-behind thread per file, exchanging `buffers` (at least
+    /// 2) aligned buffers with the caller over channels: while the caller works on
+    /// one buffer, the thread fills or drains the others.
+    Threaded { buffers: usize },
+}
+
+impl IoBackend {
+    /// Number of buffers one reader or writer using this backend holds.
+    pub fn buffers(self) -> usize {
+        match self {
+            IoBackend::Sync => 1,
+            IoBackend::Uring => URING_QUEUE_DEPTH + 1,
+            IoBackend::Threaded { buffers } => buffers.max(2),
+        }
+    }
+
+    /// True if io_uring can be used in this process.
+    pub fn uring_available() -> bool {
+        crate::uring::available()
+    }
+}
+
+impl std::str::FromStr for IoBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        match (name, arg) {
+            ("sync", None) => Ok(IoBackend::Sync),
+            ("uring", None) => Ok(IoBackend::Uring),
+            ("threaded", None) => Ok(IoBackend::Threaded {
+                buffers: DEFAULT_IO_BUFFERS,
+            }),
+            ("threaded", Some(n)) => match n.parse() {
+                Ok(buffers) if buffers >= 2 => Ok(IoBackend::Threaded { buffers }),
+                _ => Err(format!(
+                    "invalid buffer count '{}' (expected an integer of at least 2)",
+                    n
+                )),
+            },
+            _ => Err(format!(
+                "unknown I/O backend '{}' (expected sync, uring or threaded[:BUFFERS])",
+                s
+            )),
+        }
+    }
+}
+
+/// Settings for opening readers and writers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoOptions {
+    pub mode: IoMode,
+    pub backend: IoBackend,
+    /// Bytes per read or write request, rounded up to a multiple of the alignment.
+    pub buffer_size: usize,
+    /// Direct I/O alignment of file offsets, lengths and buffers, a power of two.
+    /// None detects it per file with `detect_alignment` in `IoMode::Direct` and
+    /// uses `DEFAULT_ALIGNMENT` otherwise.
+    pub alignment: Option<usize>,
+    /// Make finished files durable: `DirectWriter::finish` fdatasyncs the file
+    /// and fsyncs its directory, so it survives a crash once finish returns.
+    pub durable: bool,
+}
+
+impl Default for IoOptions {
+    fn default() -> Self {
+        Self::new(IoMode::default())
+    }
+}
+
+impl IoOptions {
+    pub fn new(mode: IoMode) -> Self {
+        Self {
+            mode,
+            backend: IoBackend::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            alignment: None,
+            durable: false,
+        }
+    }
+
+    pub fn with_backend(mut self, backend: IoBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Use `alignment` instead of detecting it.
+    pub fn with_alignment(mut self, alignment: usize) -> Self {
+        self.alignment = Some(alignment);
+        self
+    }
+
+    /// Sync finished files and their directories to disk.
+    pub fn with_durable(mut self, durable: bool) -> Self {
+        self.durable = durable;
+        self
+    }
+
+    /// Backend files are opened with: `IoMode::Mmap` always uses `IoBackend::Sync`.
+    pub fn effective_backend(&self) -> IoBackend {
+        match self.mode {
+            IoMode::Mmap => IoBackend::Sync,
+            _ => self.backend,
+        }
+    }
+
+    /// Memory held in I/O buffers by one reader or writer with these options.
+    pub fn buffer_bytes(&self) -> usize {
+        let alignment = self.alignment.unwrap_or(DEFAULT_ALIGNMENT);
+        self.effective_backend().buffers() * self.buffer_size.max(1).next_multiple_of(alignment)
+    }
+
+    /// Alignment and buffer size to use for `file`.
+    fn resolve(&self, file: &File) -> io::Result<(usize, usize)> {
+        let alignment = match self.alignment {
+            Some(a) if !a.is_power_of_two() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("alignment {} is not a power of two", a),
+                ));
+            }
+            Some(a) => a,
+            None if self.mode == IoMode::Direct => detect_alignment(file),
+            None => DEFAULT_ALIGNMENT,
+        };
+        Ok((
+            alignment,
+            self.buffer_size.max(1).next_multiple_of(alignment),
+        ))
+    }
+}
+
+/// Direct I/O alignment for `file`: the larger of the offset and memory alignment
+/// reported by `statx(STATX_DIOALIGN)` (Linux 6.1+), else the logical sector size
+/// of a block device (`BLKSSZGET`), else the smallest read length the file accepts
+/// with `O_DIRECT`, else `DEFAULT_ALIGNMENT`.
+pub fn detect_alignment(file: &File) -> usize {
+    statx_dio_alignment(file)
+        .or_else(|| block_device_sector_size(file))
+        .or_else(|| probe_alignment(file))
+        .unwrap_or(DEFAULT_ALIGNMENT)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn statx_dio_alignment(file: &File) -> Option<usize> {
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::statx(
+            file.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            libc::STATX_DIOALIGN,
+            &mut stx,
+        )
+    };
+    // An offset alignment of 0 means Direct I/O is not supported on the file
+    if ret != 0 || stx.stx_mask & libc::STATX_DIOALIGN == 0 || stx.stx_dio_offset_align == 0 {
+        return None;
+    }
+    let alignment = stx.stx_dio_offset_align.max(stx.stx_dio_mem_align) as usize;
+    alignment.is_power_of_two().then_some(alignment)
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn statx_dio_alignment(_file: &File) -> Option<usize> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn block_device_sector_size(file: &File) -> Option<usize> {
+    use std::os::unix::fs::FileTypeExt;
+    if !file.metadata().ok()?.file_type().is_block_device() {
+        return None;
+    }
+    let mut size: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut size) };
+    (ret == 0 && size > 0 && (size as usize).is_power_of_two()).then_some(size as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn block_device_sector_size(_file: &File) -> Option<usize> {
+    None
+}
+
+/// Try `O_DIRECT` reads of 512 bytes up to `DEFAULT_ALIGNMENT` at offset 0. Only
+/// files with at least that much data tell: reads at end of file never fail.
+fn probe_alignment(file: &File) -> Option<usize> {
+    use std::os::unix::fs::FileExt;
+    if file.metadata().ok()?.len() < DEFAULT_ALIGNMENT as u64 {
+        return None;
+    }
+    let mut buf = aligned_buffer(DEFAULT_ALIGNMENT, DEFAULT_ALIGNMENT);
+    let mut size = 512;
+    while size <= DEFAULT_ALIGNMENT {
+        match file.read_at(&mut buf[..size], 0) {
+            Ok(_) => return Some(size),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => size *= 2,
+            Err(_) => return None, // not readable
+        }
+    }
+    None
+}
 
 /// Helper to create aligned buffer
-fn aligned_buffer(size: usize) -> Vec<u8> {
-    let layout = std::alloc::Layout::from_size_align(size, ALIGNMENT).unwrap();
+fn aligned_buffer(size: usize, alignment: usize) -> Vec<u8> {
+    let layout = std::alloc::Layout::from_size_align(size, alignment).unwrap();
     let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
     if ptr.is_null() {
         panic!("Failed to allocate aligned buffer");
@@ -19,33 +252,244 @@ fn aligned_buffer(size: usize) -> Vec<u8> {
     unsafe { Vec::from_raw_parts(ptr, size, size) }
 }
 
+/// Background reads of the next chunks of a file.
+enum Ahead {
+    Uring(Box<ReadAhead>),
+    Thread(ReadThread),
+}
+
+impl Ahead {
+    fn next(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        match self {
+            Ahead::Uring(ahead) => ahead.next(buf),
+            Ahead::Thread(ahead) => ahead.next(buf),
+        }
+    }
+}
+
+/// Background writes of full chunks of a file.
+enum Behind {
+    Uring(WriteBehind),
+    Thread(WriteThread),
+}
+
+impl Behind {
+    fn write(&mut self, buf: &mut Vec<u8>, len: usize, offset: u64) -> io::Result<()> {
+        match self {
+            Behind::Uring(behind) => behind.write(buf, len, offset),
+            Behind::Thread(behind) => behind.write(buf, len, offset),
+        }
+    }
+
+    fn wait_all(&mut self) -> io::Result<()> {
+        match self {
+            Behind::Uring(behind) => behind.wait_all(),
+            Behind::Thread(behind) => behind.wait_all(),
+        }
+    }
+}
+
+/// Read-only mapping of a whole file.
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is private to its owner and never written through
+unsafe impl Send for Mmap {}
+
+impl Mmap {
+    /// Map the first `len` bytes of `file` and advise sequential access.
+    fn map(file: &File, len: u64) -> io::Result<Self> {
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to map"))?;
+        if len == 0 {
+            // mmap rejects empty mappings
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            libc::madvise(ptr, len, libc::MADV_SEQUENTIAL);
+        }
+        Ok(Self { ptr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+/// Copy `data` into `file` at `offset` through a temporary shared mapping. The
+/// file must be open for reading and writing and already extend to the end of
+/// `data`.
+fn mmap_write_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let base = offset - offset % page;
+    let skew = (offset - base) as usize;
+    let len = skew + data.len();
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            base as libc::off_t,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), (ptr as *mut u8).add(skew), data.len());
+        libc::munmap(ptr, len);
+    }
+    Ok(())
+}
+
 /// Reader wrapper for Direct I/O with alignment handling
 pub struct DirectReader {
     file: File,
     buffer: Vec<u8>,
-    buffer_pos: usize,   // Current position in buffer
-    buffer_valid: usize, // Valid data in buffer
-    file_pos: u64,       // Current file position
-    file_size: u64,      // Total file size
+    buffer_pos: usize,    // Current position in buffer
+    buffer_valid: usize,  // Valid data in buffer
+    file_pos: u64,        // Current file position
+    file_size: u64,       // Total file size
+    alignment: usize,     // Direct I/O alignment
+    mode: IoMode,         // mode the file was opened in
+    backend: IoBackend,   // requested backend
+    ahead: Option<Ahead>, // background reads, None with the sync backend
+    map: Option<Mmap>,    // whole-file mapping in mmap mode, replacing the buffer
 }
 
 impl DirectReader {
+    /// Create a reader with the default `IoOptions` for a file opened with `O_DIRECT`.
     pub fn new(file: File) -> io::Result<Self> {
+        Self::with_options(file, IoOptions::default())
+    }
+
+    /// Create a reader with the buffer size, alignment and backend of `io`. The
+    /// backend falls back to `IoBackend::Sync` if io_uring or the read-ahead
+    /// thread is unavailable. `io.mode` must match how `file` was opened. In
+    /// `IoMode::Mmap` the whole file is mapped and no buffer is allocated; the
+    /// file must not shrink while the reader is open.
+    pub fn with_options(file: File, io: IoOptions) -> io::Result<Self> {
         // Get file size
         let file_size = file.metadata()?.len();
+        let (alignment, buffer_size) = io.resolve(&file)?;
+        let map = match io.mode {
+            IoMode::Mmap => Some(Mmap::map(&file, file_size)?),
+            _ => None,
+        };
 
-        Ok(Self {
+        let mut rdr = Self {
             file,
-            buffer: aligned_buffer(ALIGNMENT),
+            buffer: match map {
+                Some(_) => Vec::new(),
+                None => aligned_buffer(buffer_size, alignment),
+            },
             buffer_pos: 0,
             buffer_valid: 0,
             file_pos: 0,
             file_size,
-        })
+            alignment,
+            mode: io.mode,
+            backend: io.effective_backend(),
+            ahead: None,
+            map,
+        };
+        rdr.start_read_ahead(0);
+        Ok(rdr)
+    }
+
+    /// Mode the file was opened in, `IoMode::Buffered` after a fallback from
+    /// `IoMode::Direct`.
+    pub fn io_mode(&self) -> IoMode {
+        self.mode
+    }
+
+    /// Alignment of the reads.
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    /// Bytes per read, 0 when the file is mapped.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Backend actually in use.
+    pub fn io_backend(&self) -> IoBackend {
+        match self.ahead {
+            Some(_) => self.backend,
+            None => IoBackend::Sync,
+        }
+    }
+
+    /// Start background reads at the aligned `offset`; stays sync if that fails.
+    fn start_read_ahead(&mut self, offset: u64) {
+        self.ahead = None; // wait for the old requests before allocating new buffers
+        let (chunk, alignment) = (self.buffer.len(), self.alignment);
+        let alloc = move |size| aligned_buffer(size, alignment);
+        let ahead = match self.backend {
+            IoBackend::Sync => return,
+            IoBackend::Uring => ReadAhead::new(
+                self.file.as_raw_fd(),
+                self.file_size,
+                offset,
+                URING_QUEUE_DEPTH,
+                chunk,
+                &alloc,
+            )
+            .map(|ahead| Ahead::Uring(Box::new(ahead))),
+            IoBackend::Threaded { buffers } => self.file.try_clone().and_then(|file| {
+                ReadThread::new(file, self.file_size, offset, buffers, chunk, &alloc)
+                    .map(Ahead::Thread)
+            }),
+        };
+        self.ahead = ahead.ok();
     }
 
     /// Fill the buffer with the next aligned block from file
     fn fill_buffer(&mut self) -> io::Result<bool> {
+        if let Some(ahead) = &mut self.ahead {
+            let n = ahead.next(&mut self.buffer)?;
+            self.buffer_valid = n;
+            self.buffer_pos = 0;
+            self.file_pos += n as u64;
+            return Ok(n > 0);
+        }
         if self.file_pos >= self.file_size {
             return Ok(false); // EOF
         }
@@ -64,10 +508,40 @@ impl DirectReader {
 
         Ok(true)
     }
+
+    /// Continue reading at byte `offset` of the file. Reading restarts at the
+    /// aligned block containing `offset`.
+    pub fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        if self.map.is_some() {
+            self.file_pos = offset.min(self.file_size);
+            return Ok(());
+        }
+        let block = offset - offset % self.alignment as u64;
+        if self.ahead.is_some() {
+            self.start_read_ahead(block);
+        }
+        if self.ahead.is_none() {
+            self.file.seek(SeekFrom::Start(block))?;
+        }
+        self.file_pos = block;
+        self.buffer_pos = 0;
+        self.buffer_valid = 0;
+        if offset > block && self.fill_buffer()? {
+            self.buffer_pos = ((offset - block) as usize).min(self.buffer_valid);
+        }
+        Ok(())
+    }
 }
 
 impl Read for DirectReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(map) = &self.map {
+            let rest = &map.as_slice()[self.file_pos as usize..];
+            let n = rest.len().min(buf.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            self.file_pos += n as u64;
+            return Ok(n);
+        }
         if self.buffer_pos >= self.buffer_valid {
             // Need to refill buffer
             if !self.fill_buffer()? {
@@ -85,18 +559,92 @@ impl Read for DirectReader {
     }
 }
 
+impl BufRead for DirectReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.map.is_none() && self.buffer_pos >= self.buffer_valid && !self.fill_buffer()? {
+            return Ok(&[]);
+        }
+        match &self.map {
+            Some(map) => Ok(&map.as_slice()[self.file_pos as usize..]),
+            None => Ok(&self.buffer[self.buffer_pos..self.buffer_valid]),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self.map {
+            Some(_) => self.file_pos = (self.file_pos + amt as u64).min(self.file_size),
+            None => self.buffer_pos = (self.buffer_pos + amt).min(self.buffer_valid),
+        }
+    }
+}
+
+/// How files are opened for reading and writing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoMode {
+    /// Bypass the page cache with `O_DIRECT` (Linux only; buffered elsewhere).
+    /// Files on filesystems that reject `O_DIRECT`, such as tmpfs, are opened
+    /// buffered instead.
+    #[default]
+    Direct,
+    /// Regular page-cache I/O.
+    Buffered,
+    /// Memory-mapped files: readers map the whole input, writers copy each
+    /// buffer into a mapping of the output. Always uses `IoBackend::Sync`.
+    Mmap,
+}
+
+impl std::str::FromStr for IoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(IoMode::Direct),
+            "buffered" => Ok(IoMode::Buffered),
+            "mmap" => Ok(IoMode::Mmap),
+            _ => Err(format!(
+                "unknown I/O mode '{}' (expected direct, buffered or mmap)",
+                s
+            )),
+        }
+    }
+}
+
 /// Open a file for reading with Direct I/O.
 pub fn open_direct_reader(path: &str) -> io::Result<DirectReader> {
-    #[cfg(target_os = "linux")]
-    let f = OpenOptions::new()
-        .read(true)
-        .custom_flags(libc::O_DIRECT)
-        .open(path)?;
+    open_reader(path, IoMode::Direct)
+}
 
-    #[cfg(not(target_os = "linux"))]
-    let f = OpenOptions::new().read(true).open(path)?;
+/// Open a file for reading in the given I/O mode.
+pub fn open_reader(path: &str, mode: IoMode) -> io::Result<DirectReader> {
+    open_reader_with(path, IoOptions::new(mode))
+}
+
+/// Open `path` with `opts`, adding `O_DIRECT` in `IoMode::Direct` and read access
+/// in `IoMode::Mmap`. If the filesystem rejects `O_DIRECT` with `EINVAL` (tmpfs
+/// before Linux 6.6, for example), or on systems without it, the file is opened
+/// buffered and `io.mode` becomes `IoMode::Buffered`.
+fn open_file(opts: &mut OpenOptions, path: &Path, io: &mut IoOptions) -> io::Result<File> {
+    match io.mode {
+        IoMode::Direct => {
+            #[cfg(target_os = "linux")]
+            match opts.clone().custom_flags(libc::O_DIRECT).open(path) {
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                res => return res,
+            }
+            io.mode = IoMode::Buffered;
+        }
+        IoMode::Buffered => {}
+        IoMode::Mmap => {
+            opts.read(true);
+        }
+    }
+    opts.open(path)
+}
 
-    DirectReader::new(f)
+/// Open a file for reading with the given I/O options.
+pub fn open_reader_with(path: &str, mut io: IoOptions) -> io::Result<DirectReader> {
+    let file = open_file(OpenOptions::new().read(true), path.as_ref(), &mut io)?;
+    DirectReader::with_options(file, io)
 }
 
 /// Read exactly N bytes into an array. Returns None on clean EOF, error on partial read.
@@ -140,35 +688,663 @@ pub fn read_gensort_record(r: &mut impl Read) -> io::Result<Option<Rec>> {
     Ok(Some(Rec::new(key, payload)))
 }
 
+/// Bytes of the header at the start of every run file, not counting the key
+/// spec of keyed runs.
+pub const RUN_HEADER_SIZE: u64 = 28;
+/// Version of the run header and record layouts this build writes and reads.
+pub const RUN_FORMAT_VERSION: u32 = 2;
+/// First bytes of a run header. A length-prefixed file without a header cannot
+/// start with them: the key length they would encode exceeds `Rec::MAX_KEY_SIZE`.
+const RUN_MAGIC: [u8; 8] = *b"RSRUNHDR";
+const LAYOUT_LEN_PREFIXED: u32 = 1;
+const LAYOUT_FIXED: u32 = 2;
+/// Header flag of runs holding tags.
+const FLAG_TAGS: u32 = 1;
+/// Header flag of runs keyed by a key spec, which follows the header.
+const FLAG_KEY_SPEC: u32 = 2;
+/// Longest key spec a run header may hold.
+const MAX_KEY_SPEC_SIZE: usize = 4096;
+
+/// Layout of the records in a run file, declared by the run header:
+///
+/// ```text
+/// [8]   magic "RSRUNHDR"
+/// u32   format version
+/// u32   layout: 1 = length-prefixed, 2 = fixed
+/// u32   key size (fixed layout, else 0)
+/// u32   payload size (fixed layout, else 0)
+/// u32   contents flags: 1 = tags, 2 = keyed (see `RunContents`)
+/// keyed: u32 length, key spec text as `KeySpec` parses it
+/// ```
+///
+/// All integers are little-endian. Records follow the header directly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunLayout {
+    /// `[u32 key_len][key][u32 payload_len][payload]` records of any size.
+    #[default]
+    LenPrefixed,
+    /// Raw `[key][payload]` records, all of the declared sizes.
+    Fixed {
+        key_size: usize,
+        payload_size: usize,
+    },
+}
+
+impl RunLayout {
+    /// Raw 100-byte gensort records.
+    pub const GENSORT: RunLayout = RunLayout::Fixed {
+        key_size: Rec::KEY_SIZE,
+        payload_size: Rec::PAYLOAD_SIZE,
+    };
+
+    /// Write the run header declaring this layout and `contents`. Panics on a
+    /// fixed layout with empty keys, which readers reject.
+    pub fn write_header<W: RecordWrite>(
+        &self,
+        w: &mut W,
+        contents: &RunContents,
+    ) -> io::Result<()> {
+        let (layout, key_size, payload_size) = match *self {
+            RunLayout::LenPrefixed => (LAYOUT_LEN_PREFIXED, 0, 0),
+            RunLayout::Fixed {
+                key_size,
+                payload_size,
+            } => {
+                assert!(key_size > 0, "fixed run layout with empty keys");
+                (LAYOUT_FIXED, key_size, payload_size)
+            }
+        };
+        let size = |n: usize| {
+            u32::try_from(n).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("fixed record field of {} bytes too large for a run", n),
+                )
+            })
+        };
+        let mut header = Vec::with_capacity(RUN_HEADER_SIZE as usize);
+        header.extend_from_slice(&RUN_MAGIC);
+        header.extend_from_slice(&RUN_FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&layout.to_le_bytes());
+        header.extend_from_slice(&size(key_size)?.to_le_bytes());
+        header.extend_from_slice(&size(payload_size)?.to_le_bytes());
+        let mut flags = if contents.tags { FLAG_TAGS } else { 0 };
+        if contents.key_spec.is_some() {
+            flags |= FLAG_KEY_SPEC;
+        }
+        header.extend_from_slice(&flags.to_le_bytes());
+        if let Some(spec) = &contents.key_spec {
+            let spec = spec.to_string();
+            assert!(
+                spec.len() <= MAX_KEY_SPEC_SIZE,
+                "key spec too long for a run header"
+            );
+            header.extend_from_slice(&(spec.len() as u32).to_le_bytes());
+            header.extend_from_slice(spec.as_bytes());
+        }
+        w.write_all(&header)
+    }
+
+    /// Parse the header fields after the magic into the layout and the contents
+    /// flags. Fixed records need a key: records
+    /// of no bytes at all could be read forever, and a clean end of the file could
+    /// not be told from a truncated record.
+    fn from_header(
+        fields: &[u8; RUN_HEADER_SIZE as usize - RUN_MAGIC.len()],
+    ) -> Result<(Self, u32), String> {
+        let field = |i: usize| u32::from_le_bytes(fields[4 * i..4 * i + 4].try_into().unwrap());
+        if field(0) != RUN_FORMAT_VERSION {
+            return Err(format!("unsupported run format version {}", field(0)));
+        }
+        let layout = match field(1) {
+            LAYOUT_LEN_PREFIXED => RunLayout::LenPrefixed,
+            LAYOUT_FIXED if field(2) == 0 => return Err("fixed layout with empty keys".into()),
+            LAYOUT_FIXED => RunLayout::Fixed {
+                key_size: field(2) as usize,
+                payload_size: field(3) as usize,
+            },
+            other => return Err(format!("unknown run record layout {}", other)),
+        };
+        if field(4) & !(FLAG_TAGS | FLAG_KEY_SPEC) != 0 {
+            return Err(format!("unknown run contents flags {:#x}", field(4)));
+        }
+        Ok((layout, field(4)))
+    }
+
+    /// Write `rec` in this layout. A record of another size than a fixed layout
+    /// declares is an `InvalidInput` error.
+    pub fn write_record<W: RecordWrite>(&self, w: &mut W, rec: &Rec) -> io::Result<()> {
+        match *self {
+            RunLayout::LenPrefixed => write_len_key_len_payload(w, rec),
+            RunLayout::Fixed {
+                key_size,
+                payload_size,
+            } => {
+                if rec.key.len() != key_size || rec.payload.len() != payload_size {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "record with {}-byte key and {}-byte payload in a run of {}-byte keys and {}-byte payloads",
+                            rec.key.len(),
+                            rec.payload.len(),
+                            key_size,
+                            payload_size
+                        ),
+                    ));
+                }
+                w.write_all(&rec.key)?;
+                w.write_all(&rec.payload)
+            }
+        }
+    }
+}
+
+/// What the records of a run are, declared by the run header. Runs are merged
+/// only by the merge that expects their contents.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunContents {
+    /// The records are tags of a tag sort (see `tag_sort`): keys with the input
+    /// position of their record.
+    pub tags: bool,
+    /// The key spec the keys were built from (see `key_spec::Keyed`), None for
+    /// the format's own keys.
+    pub key_spec: Option<KeySpec>,
+}
+
+impl std::fmt::Display for RunContents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.tags { "tags" } else { "records" })?;
+        match &self.key_spec {
+            Some(spec) => write!(f, " keyed by '{}'", spec),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Error from parsing a run file.
+#[derive(Debug)]
+pub enum RunReadError {
+    /// Underlying read failed.
+    Io(io::Error),
+    /// The run header is cut off, of an unsupported version, or declares record
+    /// sizes beyond the reader's limits.
+    BadHeader(String),
+    /// Key length field exceeds the reader's key size limit.
+    BadKeyLength { offset: u64, len: u32 },
+    /// Payload length field exceeds the reader's payload size limit.
+    BadPayloadLength { offset: u64, len: u32 },
+    /// File ended in the middle of the record starting at `offset`.
+    Truncated { offset: u64 },
+}
+
+impl std::fmt::Display for RunReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunReadError::Io(e) => write!(f, "run read failed: {}", e),
+            RunReadError::BadHeader(reason) => write!(f, "bad run header: {}", reason),
+            RunReadError::BadKeyLength { offset, len } => write!(
+                f,
+                "corrupt run record at byte {}: key length {} exceeds limit",
+                offset, len
+            ),
+            RunReadError::BadPayloadLength { offset, len } => write!(
+                f,
+                "corrupt run record at byte {}: payload length {} exceeds limit",
+                offset, len
+            ),
+            RunReadError::Truncated { offset } => {
+                write!(f, "truncated run record at byte {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RunReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RunReadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RunReadError {
+    fn from(e: io::Error) -> Self {
+        RunReadError::Io(e)
+    }
+}
+
+impl From<RunReadError> for io::Error {
+    fn from(e: RunReadError) -> Self {
+        match e {
+            RunReadError::Io(e) => e,
+            RunReadError::Truncated { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Reader for run files. The layout of the records comes from the run header;
+/// a file without one, such as a merged length-prefixed output, is read as
+/// `[u32 key_len][key][u32 payload_len][payload]` records written by
+/// `write_len_key_len_payload`. Yields records in file order. Key and payload
+/// sizes may be at most the reader's limits.
+pub struct RunReader<R: Read = DirectReader> {
+    rdr: R,
+    offset: u64, // bytes consumed so far (start of the next record)
+    done: bool,  // set on EOF or after the first error
+    max_key: usize,
+    max_payload: usize,
+    layout: Option<RunLayout>, // None until the header has been looked for
+    contents: RunContents,     // as declared by the header
+    head: Vec<u8>,             // bytes read while looking for a header that turned out to be data
+}
+
+impl RunReader<DirectReader> {
+    /// Open a run file with Direct I/O.
+    pub fn open(path: &str) -> io::Result<Self> {
+        Self::open_with_mode(path, IoMode::Direct)
+    }
+
+    /// Open a run file in the given I/O mode.
+    pub fn open_with_mode(path: &str, mode: IoMode) -> io::Result<Self> {
+        Ok(Self::new(open_reader(path, mode)?))
+    }
+}
+
+impl<R: Read> RunReader<R> {
+    pub fn new(rdr: R) -> Self {
+        Self {
+            rdr,
+            offset: 0,
+            done: false,
+            max_key: Rec::MAX_KEY_SIZE,
+            max_payload: Rec::MAX_PAYLOAD_SIZE,
+            layout: None,
+            contents: RunContents::default(),
+            head: Vec::new(),
+        }
+    }
+
+    /// Reject records whose key or payload length exceeds these limits.
+    /// Defaults to `Rec::MAX_KEY_SIZE` / `Rec::MAX_PAYLOAD_SIZE`.
+    pub fn with_limits(mut self, max_key: usize, max_payload: usize) -> Self {
+        self.max_key = max_key;
+        self.max_payload = max_payload;
+        self
+    }
+
+    /// Read records in `layout` from the start, without looking for a header,
+    /// e.g. for a byte range in the middle of a run.
+    pub fn with_layout(mut self, layout: RunLayout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    /// Byte offset of the next record in the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Layout of the run's records, reading the header if that has not been done
+    /// yet. Afterwards `offset()` is that of the first record.
+    pub fn layout(&mut self) -> Result<RunLayout, RunReadError> {
+        if let Some(layout) = self.layout {
+            return Ok(layout);
+        }
+        let mut magic = [0u8; RUN_MAGIC.len()];
+        let mut read = 0;
+        while read < magic.len() {
+            match self.rdr.read(&mut magic[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let layout = if magic == RUN_MAGIC {
+            self.offset = RUN_MAGIC.len() as u64;
+            let truncated = |e| match e {
+                RunReadError::Truncated { .. } => RunReadError::BadHeader("truncated".into()),
+                e => e,
+            };
+            let mut fields = [0u8; RUN_HEADER_SIZE as usize - RUN_MAGIC.len()];
+            self.fill(&mut fields, 0, false).map_err(truncated)?;
+            let (layout, flags) =
+                RunLayout::from_header(&fields).map_err(RunReadError::BadHeader)?;
+            self.contents.tags = flags & FLAG_TAGS != 0;
+            if flags & FLAG_KEY_SPEC != 0 {
+                let mut len = [0u8; 4];
+                self.fill(&mut len, 0, false).map_err(truncated)?;
+                let len = u32::from_le_bytes(len) as usize;
+                if len > MAX_KEY_SPEC_SIZE {
+                    return Err(RunReadError::BadHeader(format!(
+                        "key spec of {} bytes",
+                        len
+                    )));
+                }
+                let mut spec = vec![0u8; len];
+                self.fill(&mut spec, 0, false).map_err(truncated)?;
+                let spec = String::from_utf8(spec)
+                    .ok()
+                    .and_then(|spec| spec.parse().ok())
+                    .ok_or_else(|| RunReadError::BadHeader("invalid key spec".into()))?;
+                self.contents.key_spec = Some(spec);
+            }
+            if let RunLayout::Fixed {
+                key_size,
+                payload_size,
+            } = layout
+                && (key_size > self.max_key || payload_size > self.max_payload)
+            {
+                return Err(RunReadError::BadHeader(format!(
+                    "{}-byte keys and {}-byte payloads exceed the limits",
+                    key_size, payload_size
+                )));
+            }
+            layout
+        } else {
+            self.head = magic[..read].to_vec();
+            RunLayout::LenPrefixed
+        };
+        self.layout = Some(layout);
+        Ok(layout)
+    }
+
+    /// What the run's records are, reading the header if that has not been done
+    /// yet. Files without a header, and byte ranges read `with_layout`, hold
+    /// records.
+    pub fn contents(&mut self) -> Result<&RunContents, RunReadError> {
+        self.layout()?;
+        Ok(&self.contents)
+    }
+
+    /// Read the next record. None on clean EOF.
+    pub fn next_rec(&mut self) -> Result<Option<Rec>, RunReadError> {
+        if self.done {
+            return Ok(None);
+        }
+        let res = self.read_rec();
+        if !matches!(res, Ok(Some(_))) {
+            self.done = true;
+        }
+        res
+    }
+
+    fn read_rec(&mut self) -> Result<Option<Rec>, RunReadError> {
+        let start = self.offset;
+        if let RunLayout::Fixed {
+            key_size,
+            payload_size,
+        } = self.layout()?
+        {
+            let mut key = vec![0u8; key_size];
+            if !self.fill(&mut key, start, true)? {
+                return Ok(None);
+            }
+            let mut payload = vec![0u8; payload_size];
+            self.fill(&mut payload, start, false)?;
+            return Ok(Some(Rec::new(key, payload)));
+        }
+
+        let mut len = [0u8; 4];
+        if !self.fill(&mut len, start, true)? {
+            return Ok(None);
+        }
+        let key_len = u32::from_le_bytes(len);
+        if key_len as usize > self.max_key {
+            return Err(RunReadError::BadKeyLength {
+                offset: start,
+                len: key_len,
+            });
+        }
+        let mut key = vec![0u8; key_len as usize];
+        self.fill(&mut key, start, false)?;
+
+        self.fill(&mut len, start, false)?;
+        let payload_len = u32::from_le_bytes(len);
+        if payload_len as usize > self.max_payload {
+            return Err(RunReadError::BadPayloadLength {
+                offset: start,
+                len: payload_len,
+            });
+        }
+        let mut payload = vec![0u8; payload_len as usize];
+        self.fill(&mut payload, start, false)?;
+        Ok(Some(Rec::new(key, payload)))
+    }
+
+    /// Fill `buf` completely. Returns false on clean EOF before the first byte if
+    /// `eof_ok`, otherwise a partial or missing read is `Truncated { offset: start }`.
+    fn fill(&mut self, buf: &mut [u8], start: u64, eof_ok: bool) -> Result<bool, RunReadError> {
+        let mut read = self.head.len().min(buf.len());
+        buf[..read].copy_from_slice(&self.head[..read]);
+        self.head.drain(..read);
+        while read < buf.len() {
+            match self.rdr.read(&mut buf[read..]) {
+                Ok(0) if read == 0 && eof_ok => return Ok(false),
+                Ok(0) => return Err(RunReadError::Truncated { offset: start }),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.offset += buf.len() as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Iterator for RunReader<R> {
+    type Item = Result<Rec, RunReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_rec().transpose()
+    }
+}
+
+/// Layout of records in an input stream, one of the built-in `RecordFormat`s.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Fixed 100-byte gensort records.
+    #[default]
+    Gensort,
+    /// Variable-length `[u32 key_len][key][u32 payload_len][payload]` records.
+    LenPrefixed,
+    /// Newline-terminated lines keyed by the text before the first `delimiter`,
+    /// or by the whole line.
+    Text { delimiter: Option<u8> },
+}
+
+/// Buffered reader that counts the bytes taken from it.
+struct Counted<R> {
+    rdr: R,
+    consumed: u64,
+}
+
+impl<R: BufRead> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.rdr.read(buf)?;
+        self.consumed += n as u64;
+        Ok(n)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.rdr.read_exact(buf)?;
+        self.consumed += buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> BufRead for Counted<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.rdr.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.rdr.consume(amt);
+        self.consumed += amt as u64;
+    }
+}
+
+/// Record source over any buffered reader in a `RecordFormat`.
+pub struct RecordReader<R: BufRead, F: RecordFormat = InputFormat> {
+    rdr: Counted<R>,
+    format: F,
+    records: u64, // records read so far
+}
+
+impl<R: BufRead, F: RecordFormat> RecordReader<R, F> {
+    pub fn new(rdr: R, format: F) -> Self {
+        Self {
+            rdr: Counted { rdr, consumed: 0 },
+            format,
+            records: 0,
+        }
+    }
+
+    /// Input bytes decoded so far: the offset of the next record in the input.
+    pub fn offset(&self) -> u64 {
+        self.rdr.consumed
+    }
+
+    /// Read the next record. None on clean EOF. Errors name the record's index.
+    pub fn next_rec(&mut self) -> io::Result<Option<Rec>> {
+        match self.format.decode(&mut self.rdr) {
+            Ok(rec) => {
+                self.records += rec.is_some() as u64;
+                Ok(rec)
+            }
+            Err(e) => Err(io::Error::new(
+                e.kind(),
+                format!("input record {}: {}", self.records, e),
+            )),
+        }
+    }
+}
+
 /// Writer wrapper for Direct I/O with alignment handling
 pub struct DirectWriter {
     file: File,
     buffer: Vec<u8>,
     pos: usize,
     total_bytes_written: u64, // Track actual data size (not including padding)
+    crc: Crc32,               // CRC-32 of the data written (not including padding)
+    alignment: usize,         // Direct I/O alignment
+    mode: IoMode,             // mode the file was opened in
+    backend: IoBackend,       // backend in use
+    behind: Option<Behind>,   // background writes, None with the sync backend
+    file_offset: u64,         // file offset of buffer[0]
+    durable: bool,            // sync the file (and `path`'s directory) in finish
+    path: Option<PathBuf>,    // where the file was opened, if known
+    finished: Option<Finished>,
+}
+
+/// Final size and checksum of a file written by `DirectWriter::finish`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Finished {
+    /// File size in bytes.
+    pub bytes: u64,
+    /// CRC-32 of the file contents.
+    pub checksum: u32,
 }
 
 impl DirectWriter {
+    /// Create a writer with the default `IoOptions` for a file opened with `O_DIRECT`.
     pub fn new(file: File) -> Self {
-        Self {
+        Self::with_options(file, IoOptions::default()).expect("default I/O options are valid")
+    }
+
+    /// Create a writer with the buffer size, alignment and backend of `io`. The
+    /// backend falls back to `IoBackend::Sync` if io_uring or the write-behind
+    /// thread is unavailable. The file must be empty, and `io.mode` must match how
+    /// it was opened; `IoMode::Mmap` needs it open for reading and writing.
+    pub fn with_options(file: File, io: IoOptions) -> io::Result<Self> {
+        let (alignment, buffer_size) = io.resolve(&file)?;
+        let alloc = move |size| aligned_buffer(size, alignment);
+        let behind = match io.effective_backend() {
+            IoBackend::Sync => None,
+            IoBackend::Uring => {
+                WriteBehind::new(file.as_raw_fd(), URING_QUEUE_DEPTH, buffer_size, &alloc)
+                    .map(Behind::Uring)
+                    .ok()
+            }
+            IoBackend::Threaded { buffers } => file
+                .try_clone()
+                .and_then(|file| WriteThread::new(file, buffers, buffer_size, &alloc))
+                .map(Behind::Thread)
+                .ok(),
+        };
+        Ok(Self {
             file,
-            buffer: aligned_buffer(ALIGNMENT),
+            buffer: alloc(buffer_size),
             pos: 0,
             total_bytes_written: 0,
-        }
+            crc: Crc32::new(),
+            alignment,
+            mode: io.mode,
+            backend: if behind.is_some() {
+                io.backend
+            } else {
+                IoBackend::Sync
+            },
+            behind,
+            file_offset: 0,
+            durable: io.durable,
+            path: None,
+            finished: None,
+        })
+    }
+
+    /// Alignment of the writes.
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    /// Bytes per write.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Mode the file was opened in, `IoMode::Buffered` after a fallback from
+    /// `IoMode::Direct`.
+    pub fn io_mode(&self) -> IoMode {
+        self.mode
+    }
+
+    /// Backend actually in use.
+    pub fn io_backend(&self) -> IoBackend {
+        self.backend
+    }
+
+    /// Bytes written so far, excluding alignment padding.
+    pub fn bytes_written(&self) -> u64 {
+        self.total_bytes_written
+    }
+
+    /// CRC-32 of the bytes written so far, excluding alignment padding.
+    pub fn checksum(&self) -> u32 {
+        self.crc.value()
     }
 
     /// Write data to the buffer, flushing when full
     pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.finished.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write to a finished DirectWriter",
+            ));
+        }
+        self.crc.update(data);
         let mut offset = 0;
         while offset < data.len() {
             let remaining = data.len() - offset;
-            let space = ALIGNMENT - self.pos;
+            let space = self.buffer.len() - self.pos;
 
             if remaining >= space {
                 // Fill current buffer and flush
-                self.buffer[self.pos..ALIGNMENT].copy_from_slice(&data[offset..offset + space]);
-                self.file.write_all(&self.buffer)?;
+                let end = self.buffer.len();
+                self.buffer[self.pos..end].copy_from_slice(&data[offset..offset + space]);
+                self.write_buffer(end)?;
                 self.total_bytes_written += space as u64;
                 self.pos = 0;
                 offset += space;
@@ -183,69 +1359,336 @@ impl DirectWriter {
         Ok(())
     }
 
-    /// Flush remaining data (pad to alignment if needed), then truncate to actual size
-    pub fn flush(&mut self) -> io::Result<()> {
+    /// Write out the first `len` bytes of the buffer at the current file offset.
+    fn write_buffer(&mut self, len: usize) -> io::Result<()> {
+        match &mut self.behind {
+            Some(behind) => behind.write(&mut self.buffer, len, self.file_offset)?,
+            None if self.mode == IoMode::Mmap => {
+                // Grow the file to cover the mapping
+                self.file.set_len(self.file_offset + len as u64)?;
+                mmap_write_at(&self.file, &self.buffer[..len], self.file_offset)?;
+            }
+            None => self.file.write_all(&self.buffer[..len])?,
+        }
+        self.file_offset += len as u64;
+        Ok(())
+    }
+
+    /// Write out the remaining data (padded to the alignment), wait for background
+    /// writes and truncate the file to the data written. With `IoOptions::durable`
+    /// the file's data is then fdatasynced and, for files opened by path, its
+    /// directory fsynced. Returns the final size and checksum. Every failure is
+    /// reported, and after one the file must be considered incomplete.
+    ///
+    /// No writes are accepted afterwards; calling `finish` or `flush` again returns
+    /// the same result without touching the file. A writer dropped without
+    /// `finish` finishes on a best-effort basis: errors are ignored and the file
+    /// is not synced.
+    pub fn finish(&mut self) -> io::Result<Finished> {
+        if let Some(finished) = self.finished {
+            return Ok(finished);
+        }
         if self.pos > 0 {
             // Pad to alignment
-            for i in self.pos..ALIGNMENT {
-                self.buffer[i] = 0;
-            }
-            self.file.write_all(&self.buffer)?;
+            let padded = self.pos.next_multiple_of(self.alignment);
+            self.buffer[self.pos..padded].fill(0);
+            self.write_buffer(padded)?;
             self.pos = 0;
         }
-
-        // Truncate file to actual data size (remove padding)
-        // This requires the file to support seeking, which works with regular files
-        #[cfg(target_os = "linux")]
-        {
-            use std::os::unix::io::AsRawFd;
-            unsafe {
-                libc::ftruncate(self.file.as_raw_fd(), self.total_bytes_written as i64);
+        if let Some(behind) = &mut self.behind {
+            behind.wait_all()?;
+        }
+        // Remove the padding
+        self.file.set_len(self.total_bytes_written)?;
+        if self.durable {
+            self.file.sync_data()?;
+            if let Some(path) = &self.path {
+                sync_parent_dir(path)?;
             }
         }
+        let finished = Finished {
+            bytes: self.total_bytes_written,
+            checksum: self.checksum(),
+        };
+        self.finished = Some(finished);
+        Ok(finished)
+    }
 
-        #[cfg(not(target_os = "linux"))]
-        {
-            self.file.set_len(self.total_bytes_written)?;
+    /// Same as `finish`, for callers that need no size or checksum.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.finish().map(|_| ())
+    }
+}
+
+impl Drop for DirectWriter {
+    fn drop(&mut self) {
+        if self.finished.is_none() {
+            self.durable = false;
+            let _ = self.finish();
         }
+    }
+}
+
+/// Fsync the directory containing `path`, making the file's directory entry durable.
+pub fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Writer for the byte range of a file starting at a given offset, so several
+/// writers can fill disjoint regions of one file concurrently. Whole aligned
+/// blocks are written with Direct I/O (in `IoMode::Direct`); the partial pages at
+/// either end of the region, which neighbouring regions may share, go through the
+/// page cache, so a cached page never holds data written around it directly. In
+/// `IoMode::Mmap` the whole region is written through mappings. The file must
+/// already be large enough to hold the region.
+pub struct RegionWriter {
+    direct: Option<File>, // O_DIRECT handle, None in buffered and mmap mode
+    file: File,           // page-cache handle for partial blocks
+    mmap: bool,           // write through mappings of `file`
+    buffer: Vec<u8>,
+    start: usize, // first valid byte in buffer
+    len: usize,   // end of the valid bytes in buffer
+    offset: u64,  // file offset of buffer[0], a multiple of `page`
+    page: usize,  // the alignment, at least the page size
+    total_bytes_written: u64,
+}
 
+impl RegionWriter {
+    /// Open `path` for writing from byte `start` on. Writes use the buffer size and
+    /// alignment of `io`; the backend is always `IoBackend::Sync`.
+    pub fn open(path: &str, start: u64, mut io: IoOptions) -> io::Result<Self> {
+        let direct = if io.mode == IoMode::Direct {
+            let direct = open_file(OpenOptions::new().write(true), path.as_ref(), &mut io)?;
+            // After a fallback the page-cache handle below does all the writes
+            (io.mode == IoMode::Direct).then_some(direct)
+        } else {
+            None
+        };
+        let file = OpenOptions::new()
+            .read(io.mode == IoMode::Mmap)
+            .write(true)
+            .open(path)?;
+        let (alignment, buffer_size) = io.resolve(direct.as_ref().unwrap_or(&file))?;
+        // Direct writes cover whole pages only, so none shares a page with the
+        // cached writes at the ends of this or a neighbouring region. File offsets
+        // and buffer addresses are kept equally aligned.
+        let page = alignment.max(unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize);
+        let skew = (start % page as u64) as usize;
+        Ok(Self {
+            direct,
+            file,
+            mmap: io.mode == IoMode::Mmap,
+            buffer: aligned_buffer(buffer_size.max(page), alignment),
+            start: skew,
+            len: skew,
+            offset: start - skew as u64,
+            page,
+            total_bytes_written: 0,
+        })
+    }
+
+    /// Bytes written to the region so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.total_bytes_written
+    }
+
+    pub fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        self.total_bytes_written += data.len() as u64;
+        while !data.is_empty() {
+            // A full buffer waits for more data, as it may end the region
+            if self.len == self.buffer.len() {
+                self.write_buffer(false)?;
+            }
+            let n = (self.buffer.len() - self.len).min(data.len());
+            self.buffer[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+        }
         Ok(())
     }
+
+    /// Write out the buffer; `last` if it holds the end of the region.
+    fn write_buffer(&mut self, last: bool) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        if self.mmap {
+            let at = self.offset + self.start as u64;
+            mmap_write_at(&self.file, &self.buffer[self.start..self.len], at)?;
+            self.offset += self.len as u64;
+            self.start = 0;
+            self.len = 0;
+            return Ok(());
+        }
+        // Whole pages in the middle, the partial pages at either end of the
+        // region through the page cache. A partial last page of a buffer that
+        // does not end the region moves to the front of the buffer.
+        let body_start = self.start.next_multiple_of(self.page).min(self.len);
+        let body_end = (self.len / self.page * self.page).max(body_start);
+        let at = |i: usize| self.offset + i as u64;
+        let direct = self.direct.as_ref().unwrap_or(&self.file);
+        self.file
+            .write_all_at(&self.buffer[self.start..body_start], at(self.start))?;
+        direct.write_all_at(&self.buffer[body_start..body_end], at(body_start))?;
+        let end = if last {
+            self.file
+                .write_all_at(&self.buffer[body_end..self.len], at(body_end))?;
+            self.len
+        } else {
+            self.buffer.copy_within(body_end..self.len, 0);
+            body_end
+        };
+        self.offset += end as u64;
+        self.start = 0;
+        self.len -= end;
+        Ok(())
+    }
+
+    /// Write out the buffered tail of the region. Returns the bytes written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        if self.len > self.start {
+            self.write_buffer(true)?;
+        }
+        Ok(self.total_bytes_written)
+    }
 }
 
-impl Drop for DirectWriter {
-    fn drop(&mut self) {
-        let _ = self.flush();
+/// Sink for encoded records: `DirectWriter`, `RegionWriter`, or a `BufWriter`
+/// over any stream, such as stdout.
+pub trait RecordWrite {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()>;
+}
+
+impl RecordWrite for DirectWriter {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        DirectWriter::write_all(self, data)
+    }
+}
+
+impl RecordWrite for RegionWriter {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        RegionWriter::write_all(self, data)
+    }
+}
+
+impl<W: IoWrite> RecordWrite for io::BufWriter<W> {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        IoWrite::write_all(self, data)
     }
 }
 
+/// File name of the `idx`-th run written with `prefix`. The index is zero-padded
+/// to 8 digits and grows past that without truncation, so names never collide.
+/// Run order is carried by the index, not by sorting names.
+pub fn run_file_name(prefix: &str, idx: usize) -> String {
+    format!("{}_{:08}.bin", prefix, idx)
+}
+
 /// Open a run file for writing with Direct I/O.
 pub fn open_run_writer(prefix: &str, idx: usize) -> io::Result<DirectWriter> {
-    let filename = format!("{}_{:03}.bin", prefix, idx);
-    let path = PathBuf::from(filename);
+    open_direct_writer(&run_file_name(prefix, idx))
+}
 
-    #[cfg(target_os = "linux")]
-    let f = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .custom_flags(libc::O_DIRECT)
-        .open(path)?;
+/// Open (create or truncate) a file for writing with Direct I/O.
+pub fn open_direct_writer(path: &str) -> io::Result<DirectWriter> {
+    open_writer(path, IoMode::Direct)
+}
 
-    #[cfg(not(target_os = "linux"))]
-    let f = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(path)?;
+/// Open (create or truncate) a file for writing in the given I/O mode.
+pub fn open_writer(path: &str, mode: IoMode) -> io::Result<DirectWriter> {
+    open_writer_with(path, IoOptions::new(mode))
+}
+
+/// Open (create or truncate) a file for writing with the given I/O options.
+pub fn open_writer_with(path: &str, mut io: IoOptions) -> io::Result<DirectWriter> {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    let file = open_file(&mut opts, path.as_ref(), &mut io)?;
+    let mut writer = DirectWriter::with_options(file, io)?;
+    writer.path = Some(PathBuf::from(path));
+    Ok(writer)
+}
+
+/// Layout of records in a merged output file, one of the built-in `RecordFormat`s.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Variable-length `[u32 key_len][key][u32 payload_len][payload]` records.
+    #[default]
+    LenPrefixed,
+    /// Fixed 100-byte gensort records; every record must be 10 + 90 bytes.
+    Gensort,
+    /// Key and payload of each record on a line of their own; records must not
+    /// contain newlines.
+    Text,
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let delimiter = match s.strip_prefix("text:") {
+            Some("tab") => Some(b'\t'),
+            Some(d) if d.len() == 1 => Some(d.as_bytes()[0]),
+            Some(d) => {
+                return Err(format!(
+                    "invalid text delimiter '{}' (expected one ASCII character or tab)",
+                    d
+                ));
+            }
+            None => None,
+        };
+        match s {
+            "gensort" => Ok(InputFormat::Gensort),
+            "len-prefixed" => Ok(InputFormat::LenPrefixed),
+            "text" => Ok(InputFormat::Text { delimiter: None }),
+            _ if delimiter.is_some() => Ok(InputFormat::Text { delimiter }),
+            _ => Err(format!(
+                "unknown input format '{}' (expected gensort, len-prefixed or text[:DELIM])",
+                s
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "len-prefixed" => Ok(OutputFormat::LenPrefixed),
+            "gensort" => Ok(OutputFormat::Gensort),
+            "text" => Ok(OutputFormat::Text),
+            _ => Err(format!(
+                "unknown output format '{}' (expected len-prefixed, gensort or text)",
+                s
+            )),
+        }
+    }
+}
 
-    Ok(DirectWriter::new(f))
+/// Write: [10-byte key][90-byte payload]. Fails for records of any other size.
+pub fn write_gensort_record<W: RecordWrite>(w: &mut W, rec: &Rec) -> io::Result<()> {
+    if rec.key.len() != Rec::KEY_SIZE || rec.payload.len() != Rec::PAYLOAD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "record with {}-byte key and {}-byte payload cannot be written as gensort",
+                rec.key.len(),
+                rec.payload.len()
+            ),
+        ));
+    }
+    w.write_all(&rec.key)?;
+    w.write_all(&rec.payload)?;
+    Ok(())
 }
 
 /// Write: [u32 LE key_len][key][u32 LE payload_len][payload]
-pub fn write_len_key_len_payload(w: &mut DirectWriter, rec: &Rec) -> io::Result<()> {
-    let key_len_le = (10u32).to_le_bytes();
-    let payload_len_le = (90u32).to_le_bytes();
+pub fn write_len_key_len_payload<W: RecordWrite>(w: &mut W, rec: &Rec) -> io::Result<()> {
+    let key_len_le = (rec.key.len() as u32).to_le_bytes();
+    let payload_len_le = (rec.payload.len() as u32).to_le_bytes();
     w.write_all(&key_len_le)?;
     w.write_all(&rec.key)?;
     w.write_all(&payload_len_le)?;
@@ -353,4 +1796,529 @@ mod tests {
         let payload_len = u32::from_le_bytes([result[14], result[15], result[16], result[17]]);
         assert_eq!(payload_len, 90);
     }
+
+    fn encode_run_record(key_len: u32, key: &[u8], payload_len: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&key_len.to_le_bytes());
+        data.extend_from_slice(key);
+        data.extend_from_slice(&payload_len.to_le_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn test_run_reader_roundtrip() {
+        let mut data = encode_run_record(10, &[1u8; 10], 90, &[2u8; 90]);
+        data.extend(encode_run_record(10, &[3u8; 10], 90, &[4u8; 90]));
+
+        let recs: Vec<Rec> = RunReader::new(Cursor::new(data))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            recs,
+            vec![
+                Rec::new([1u8; 10], [2u8; 90]),
+                Rec::new([3u8; 10], [4u8; 90])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_reader_variable_length() {
+        let mut data = encode_run_record(4, &[1u8; 4], 3000, &[2u8; 3000]);
+        data.extend(encode_run_record(64, &[3u8; 64], 0, &[]));
+
+        let mut rdr = RunReader::new(Cursor::new(data));
+        let first = rdr.next().unwrap().unwrap();
+        assert_eq!(first, Rec::new(vec![1u8; 4], vec![2u8; 3000]));
+        let second = rdr.next().unwrap().unwrap();
+        assert_eq!(second, Rec::new(vec![3u8; 64], vec![]));
+        assert!(rdr.next().is_none());
+        assert_eq!(rdr.offset(), 4 + 4 + 4 + 3000 + 4 + 64 + 4);
+    }
+
+    #[test]
+    fn test_run_reader_rejects_huge_length() {
+        let data = (u32::MAX).to_le_bytes().to_vec();
+        let mut rdr = RunReader::new(Cursor::new(data));
+        assert!(matches!(
+            rdr.next(),
+            Some(Err(RunReadError::BadKeyLength { offset: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_record_reader_formats() {
+        let mut gensort = vec![7u8; 10];
+        gensort.extend_from_slice(&[8u8; 90]);
+        let mut rdr = RecordReader::new(Cursor::new(gensort), InputFormat::Gensort);
+        assert_eq!(
+            rdr.next_rec().unwrap(),
+            Some(Rec::new([7u8; 10], [8u8; 90]))
+        );
+        assert_eq!(rdr.next_rec().unwrap(), None);
+
+        let prefixed = encode_run_record(3, &[1, 2, 3], 2, &[4, 5]);
+        let mut rdr = RecordReader::new(Cursor::new(prefixed), InputFormat::LenPrefixed);
+        assert_eq!(
+            rdr.next_rec().unwrap(),
+            Some(Rec::new(vec![1, 2, 3], vec![4, 5]))
+        );
+        assert_eq!(rdr.next_rec().unwrap(), None);
+    }
+
+    #[test]
+    fn test_run_reader_empty() {
+        let mut rdr = RunReader::new(Cursor::new(Vec::new()));
+        assert!(rdr.next().is_none());
+        assert_eq!(rdr.offset(), 0);
+    }
+
+    #[test]
+    fn test_run_reader_bad_key_length() {
+        let mut data = encode_run_record(10, &[1u8; 10], 90, &[2u8; 90]);
+        data.extend(encode_run_record(11, &[1u8; 11], 90, &[2u8; 90]));
+
+        let mut rdr = RunReader::new(Cursor::new(data)).with_limits(10, 90);
+        assert!(rdr.next().unwrap().is_ok());
+        match rdr.next().unwrap() {
+            Err(RunReadError::BadKeyLength { offset, len }) => {
+                assert_eq!(offset, 108);
+                assert_eq!(len, 11);
+            }
+            other => panic!("expected BadKeyLength, got {:?}", other),
+        }
+        assert!(rdr.next().is_none(), "reader should stop after an error");
+    }
+
+    #[test]
+    fn test_run_reader_bad_payload_length() {
+        let data = encode_run_record(10, &[1u8; 10], 91, &[2u8; 91]);
+        let mut rdr = RunReader::new(Cursor::new(data)).with_limits(10, 90);
+        assert!(matches!(
+            rdr.next(),
+            Some(Err(RunReadError::BadPayloadLength { offset: 0, len: 91 }))
+        ));
+    }
+
+    #[test]
+    fn test_run_reader_truncated() {
+        let mut data = encode_run_record(10, &[1u8; 10], 90, &[2u8; 90]);
+        data.extend(encode_run_record(10, &[3u8; 10], 90, &[4u8; 90]));
+        data.truncate(150);
+
+        let mut rdr = RunReader::new(Cursor::new(data));
+        assert!(rdr.next().unwrap().is_ok());
+        let err = rdr.next().unwrap().unwrap_err();
+        assert!(matches!(err, RunReadError::Truncated { offset: 108 }));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    fn encode_run(layout: RunLayout, recs: &[Rec]) -> Vec<u8> {
+        encode_run_of(layout, &RunContents::default(), recs)
+    }
+
+    fn encode_run_of(layout: RunLayout, contents: &RunContents, recs: &[Rec]) -> Vec<u8> {
+        let mut w = io::BufWriter::new(Vec::new());
+        layout.write_header(&mut w, contents).unwrap();
+        for rec in recs {
+            layout.write_record(&mut w, rec).unwrap();
+        }
+        w.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_run_reader_layouts() {
+        let recs = vec![
+            Rec::new([1u8; 10], [2u8; 90]),
+            Rec::new([3u8; 10], [4u8; 90]),
+        ];
+        let compact = encode_run(RunLayout::GENSORT, &recs);
+        assert_eq!(compact.len() as u64, RUN_HEADER_SIZE + 200);
+        let prefixed = encode_run(RunLayout::LenPrefixed, &recs);
+        assert_eq!(prefixed.len() as u64, RUN_HEADER_SIZE + 216);
+
+        for (data, layout) in [
+            (compact.clone(), RunLayout::GENSORT),
+            (prefixed, RunLayout::LenPrefixed),
+        ] {
+            let mut rdr = RunReader::new(Cursor::new(data));
+            assert_eq!(rdr.layout().unwrap(), layout);
+            assert_eq!(rdr.offset(), RUN_HEADER_SIZE);
+            assert!(!rdr.contents().unwrap().tags);
+            let read: Vec<Rec> = rdr.collect::<Result<_, _>>().unwrap();
+            assert_eq!(read, recs);
+        }
+
+        // Tag and keyed runs say so in their header
+        let keyed = RunContents {
+            tags: false,
+            key_spec: Some("4:2:int-le:desc,0".parse().unwrap()),
+        };
+        let tags = RunContents {
+            tags: true,
+            ..keyed.clone()
+        };
+        for contents in [keyed, tags] {
+            let data = encode_run_of(RunLayout::GENSORT, &contents, &recs);
+            let mut rdr = RunReader::new(Cursor::new(data));
+            assert_eq!(rdr.contents().unwrap(), &contents);
+            assert_eq!(rdr.offset(), RUN_HEADER_SIZE + 4 + 17);
+            assert_eq!(rdr.next_rec().unwrap(), Some(recs[0].clone()));
+        }
+
+        // A byte range in the middle of a compact run has no header
+        let second = compact[RUN_HEADER_SIZE as usize + 100..].to_vec();
+        let mut rdr = RunReader::new(Cursor::new(second)).with_layout(RunLayout::GENSORT);
+        assert_eq!(rdr.next_rec().unwrap(), Some(recs[1].clone()));
+        assert_eq!(rdr.offset(), 100);
+
+        // Records of another size do not fit a fixed layout
+        let mut w = io::BufWriter::new(Vec::new());
+        let err = RunLayout::GENSORT
+            .write_record(&mut w, &Rec::new(vec![1u8; 4], vec![]))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_run_reader_bad_header() {
+        let mut data = encode_run(RunLayout::GENSORT, &[]);
+        data[8] = 9; // version
+        let err = RunReader::new(Cursor::new(data)).next_rec().unwrap_err();
+        assert!(matches!(err, RunReadError::BadHeader(_)));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+
+        let data = encode_run(RunLayout::GENSORT, &[]);
+        let mut rdr = RunReader::new(Cursor::new(data[..12].to_vec()));
+        assert!(matches!(rdr.next_rec(), Err(RunReadError::BadHeader(_))));
+
+        let data = encode_run(RunLayout::GENSORT, &[]);
+        let mut rdr = RunReader::new(Cursor::new(data)).with_limits(8, 90);
+        assert!(matches!(rdr.next_rec(), Err(RunReadError::BadHeader(_))));
+
+        // Empty fixed keys, with or without a payload
+        for payload_size in [0u8, 90] {
+            let mut data = encode_run(RunLayout::GENSORT, &[]);
+            data[16] = 0; // key size
+            data[20] = payload_size;
+            let mut rdr = RunReader::new(Cursor::new(data));
+            assert!(matches!(rdr.next_rec(), Err(RunReadError::BadHeader(_))));
+            assert!(matches!(rdr.next_rec(), Ok(None)));
+        }
+
+        let mut data = encode_run(RunLayout::GENSORT, &[]);
+        data[24] = 0x80; // contents flags
+        let mut rdr = RunReader::new(Cursor::new(data));
+        assert!(matches!(rdr.next_rec(), Err(RunReadError::BadHeader(_))));
+
+        // Key specs cut off or not parsing
+        let keyed = RunContents {
+            tags: false,
+            key_spec: Some("0:4:uint-be".parse().unwrap()),
+        };
+        let data = encode_run_of(RunLayout::GENSORT, &keyed, &[]);
+        let mut rdr = RunReader::new(Cursor::new(data[..data.len() - 1].to_vec()));
+        assert!(matches!(rdr.next_rec(), Err(RunReadError::BadHeader(_))));
+        let mut data = data;
+        data[RUN_HEADER_SIZE as usize + 4] = b'x';
+        let mut rdr = RunReader::new(Cursor::new(data));
+        assert!(matches!(rdr.next_rec(), Err(RunReadError::BadHeader(_))));
+    }
+
+    #[test]
+    fn test_direct_reader_seek_to() {
+        let path = "test_io_seek.bin";
+        let data: Vec<u8> = (0..3 * DEFAULT_ALIGNMENT + 123)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(path, &data).unwrap();
+
+        let mut rdr = open_direct_reader(path).unwrap();
+        for offset in [
+            0,
+            1,
+            DEFAULT_ALIGNMENT - 1,
+            DEFAULT_ALIGNMENT,
+            2 * DEFAULT_ALIGNMENT + 77,
+            data.len(),
+        ] {
+            rdr.seek_to(offset as u64).unwrap();
+            let mut rest = Vec::new();
+            rdr.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, &data[offset..], "offset {}", offset);
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_async_backends_roundtrip() {
+        let path = "test_io_async.bin";
+        let uring = if IoBackend::uring_available() {
+            IoBackend::Uring
+        } else {
+            IoBackend::Sync
+        };
+        // Several buffers plus an unaligned tail
+        let buffer_size = 64 * 1024;
+        let data: Vec<u8> = (0..3 * buffer_size + 5000)
+            .map(|i| (i % 241) as u8)
+            .collect();
+
+        for (backend, expected) in [
+            (IoBackend::Uring, uring),
+            (
+                IoBackend::Threaded { buffers: 2 },
+                IoBackend::Threaded { buffers: 2 },
+            ),
+            (
+                IoBackend::Threaded { buffers: 5 },
+                IoBackend::Threaded { buffers: 5 },
+            ),
+        ] {
+            for mode in [IoMode::Direct, IoMode::Buffered] {
+                let io = IoOptions::new(mode)
+                    .with_backend(backend)
+                    .with_buffer_size(buffer_size);
+                let mut writer = open_writer_with(path, io).unwrap();
+                assert_eq!(writer.io_backend(), expected);
+                for chunk in data.chunks(7777) {
+                    writer.write_all(chunk).unwrap();
+                }
+                writer.flush().unwrap();
+                assert_eq!(writer.bytes_written(), data.len() as u64);
+                drop(writer);
+                assert_eq!(std::fs::read(path).unwrap(), data, "{:?}", backend);
+
+                let mut rdr = open_reader_with(path, io).unwrap();
+                assert_eq!(rdr.io_backend(), expected);
+                let mut read = Vec::new();
+                rdr.read_to_end(&mut read).unwrap();
+                assert_eq!(read, data, "{:?}", backend);
+
+                for offset in [buffer_size + 3, 17, data.len()] {
+                    rdr.seek_to(offset as u64).unwrap();
+                    let mut rest = Vec::new();
+                    rdr.read_to_end(&mut rest).unwrap();
+                    assert_eq!(rest, &data[offset..], "{:?} offset {}", backend, offset);
+                }
+            }
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_uring_reader_reports_shrunk_file() {
+        if !IoBackend::uring_available() {
+            return;
+        }
+        let path = "test_io_uring_shrunk.bin";
+        std::fs::write(path, vec![5u8; 16 * DEFAULT_ALIGNMENT]).unwrap();
+        let io = IoOptions::new(IoMode::Buffered)
+            .with_backend(IoBackend::Uring)
+            .with_buffer_size(DEFAULT_ALIGNMENT);
+        let mut rdr = open_reader_with(path, io).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(2 * DEFAULT_ALIGNMENT as u64)
+            .unwrap();
+        let err = rdr.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_alignment_and_buffer_size() {
+        let path = "test_io_alignment.bin";
+        std::fs::write(path, vec![7u8; 2 * DEFAULT_ALIGNMENT]).unwrap();
+
+        let detected = detect_alignment(&File::open(path).unwrap());
+        assert!(detected.is_power_of_two() && detected <= DEFAULT_ALIGNMENT);
+        let rdr = open_direct_reader(path).unwrap();
+        assert_eq!(rdr.alignment(), detected);
+        assert_eq!(rdr.buffer_size(), DEFAULT_BUFFER_SIZE);
+
+        // Buffer sizes are rounded up to the alignment
+        let io = IoOptions::new(IoMode::Direct)
+            .with_alignment(DEFAULT_ALIGNMENT)
+            .with_buffer_size(5000);
+        let mut rdr = open_reader_with(path, io).unwrap();
+        assert_eq!(rdr.alignment(), DEFAULT_ALIGNMENT);
+        assert_eq!(rdr.buffer_size(), 2 * DEFAULT_ALIGNMENT);
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![7u8; 2 * DEFAULT_ALIGNMENT]);
+        assert_eq!(io.buffer_bytes(), 2 * DEFAULT_ALIGNMENT);
+
+        let writer = open_writer_with(path, io).unwrap();
+        assert_eq!(writer.buffer_size(), 2 * DEFAULT_ALIGNMENT);
+        drop(writer);
+
+        let err = open_writer_with(path, io.with_alignment(3000))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_direct_writer_finish() {
+        let path = "test_io_finish.bin";
+        let data: Vec<u8> = (0..DEFAULT_ALIGNMENT + 300).map(|i| i as u8).collect();
+        for io in [
+            IoOptions::new(IoMode::Direct),
+            IoOptions::new(IoMode::Buffered).with_durable(true),
+            IoOptions::new(IoMode::Mmap).with_durable(true),
+        ] {
+            let mut writer = open_writer_with(path, io).unwrap();
+            writer.write_all(&data).unwrap();
+            let finished = writer.finish().unwrap();
+            assert_eq!(
+                finished,
+                Finished {
+                    bytes: data.len() as u64,
+                    checksum: crate::checksum::crc32(&data),
+                }
+            );
+            assert_eq!(std::fs::read(path).unwrap(), data, "{:?}", io);
+
+            // Finishing again changes nothing, and no more data is accepted
+            std::fs::write(path, b"replaced").unwrap();
+            assert_eq!(writer.finish().unwrap(), finished);
+            writer.flush().unwrap();
+            let err = writer.write_all(b"more").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(std::fs::read(path).unwrap(), b"replaced");
+
+            // Dropping an unfinished writer still writes the data, unpadded
+            let mut writer = open_writer_with(path, io).unwrap();
+            writer.write_all(&data).unwrap();
+            drop(writer);
+            assert_eq!(std::fs::read(path).unwrap(), data, "{:?}", io);
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_mmap_roundtrip() {
+        let path = "test_io_mmap.bin";
+        let data: Vec<u8> = (0..3 * DEFAULT_ALIGNMENT + 1234)
+            .map(|i| (i % 239) as u8)
+            .collect();
+        let io = IoOptions::new(IoMode::Mmap)
+            .with_backend(IoBackend::Threaded { buffers: 3 })
+            .with_buffer_size(DEFAULT_ALIGNMENT);
+        assert_eq!(io.buffer_bytes(), DEFAULT_ALIGNMENT);
+
+        let mut writer = open_writer_with(path, io).unwrap();
+        assert_eq!(writer.io_mode(), IoMode::Mmap);
+        assert_eq!(writer.io_backend(), IoBackend::Sync);
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read(path).unwrap(), data);
+
+        let mut rdr = open_reader_with(path, io).unwrap();
+        assert_eq!(rdr.io_mode(), IoMode::Mmap);
+        assert_eq!(rdr.buffer_size(), 0);
+        let mut read = Vec::new();
+        rdr.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        for offset in [DEFAULT_ALIGNMENT + 3, 0, data.len()] {
+            rdr.seek_to(offset as u64).unwrap();
+            let mut rest = Vec::new();
+            rdr.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, &data[offset..], "offset {}", offset);
+        }
+
+        // Empty files map to nothing
+        drop(open_writer_with(path, io).unwrap());
+        let mut rdr = open_reader_with(path, io).unwrap();
+        assert_eq!(rdr.read(&mut [0u8; 16]).unwrap(), 0);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_parse_io_mode() {
+        assert_eq!("direct".parse(), Ok(IoMode::Direct));
+        assert_eq!("buffered".parse(), Ok(IoMode::Buffered));
+        assert_eq!("mmap".parse(), Ok(IoMode::Mmap));
+        assert!("odirect".parse::<IoMode>().is_err());
+    }
+
+    #[test]
+    fn test_parse_io_backend() {
+        assert_eq!("sync".parse(), Ok(IoBackend::Sync));
+        assert_eq!("uring".parse(), Ok(IoBackend::Uring));
+        assert_eq!(
+            "threaded".parse(),
+            Ok(IoBackend::Threaded {
+                buffers: DEFAULT_IO_BUFFERS
+            })
+        );
+        assert_eq!("threaded:8".parse(), Ok(IoBackend::Threaded { buffers: 8 }));
+        for bad in ["threaded:1", "threaded:x", "uring:2", "aio"] {
+            assert!(bad.parse::<IoBackend>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_region_writers_fill_disjoint_regions() {
+        let path = "test_io_regions.bin";
+        let data: Vec<u8> = (0..5 * DEFAULT_ALIGNMENT + 321)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        // Unaligned cut points, including two inside the same block, and sector
+        // aligned ones inside a page
+        let cuts = [
+            0,
+            100,
+            DEFAULT_ALIGNMENT + 7,
+            DEFAULT_ALIGNMENT + 900,
+            2 * DEFAULT_ALIGNMENT + 512,
+            2 * DEFAULT_ALIGNMENT + 1536,
+            4 * DEFAULT_ALIGNMENT,
+            data.len(),
+        ];
+        for (mode, alignment) in [
+            (IoMode::Direct, DEFAULT_ALIGNMENT),
+            (IoMode::Direct, 512),
+            (IoMode::Mmap, DEFAULT_ALIGNMENT),
+        ] {
+            File::create(path)
+                .unwrap()
+                .set_len(data.len() as u64)
+                .unwrap();
+
+            std::thread::scope(|scope| {
+                for w in cuts.windows(2) {
+                    let region = &data[w[0]..w[1]];
+                    scope.spawn(move || {
+                        // Buffers of two blocks, so regions span several buffers
+                        let io = IoOptions::new(mode)
+                            .with_buffer_size(2 * alignment)
+                            .with_alignment(alignment);
+                        let mut writer = RegionWriter::open(path, w[0] as u64, io).unwrap();
+                        for chunk in region.chunks(333) {
+                            writer.write_all(chunk).unwrap();
+                        }
+                        assert_eq!(writer.finish().unwrap(), region.len() as u64);
+                    });
+                }
+            });
+
+            assert_eq!(
+                std::fs::read(path).unwrap(),
+                data,
+                "{:?}, alignment {}",
+                mode,
+                alignment
+            );
+        }
+        std::fs::remove_file(path).ok();
+    }
 }
@@ -0,0 +1,456 @@
+//! Sort orders other than the byte order of a format's key: fields of the record,
+//! as bytes or integers, ascending or descending, combined into one key.
+//!
+//! Replacement selection, the merge and validation order records by `Rec::key`.
+//! `Keyed` decodes records with a key built from a `KeySpec` whose byte order is
+//! the order the spec asks for, and writes them back unchanged, so none of them
+//! need to know about key specs.
+
+use crate::format::RecordFormat;
+use crate::io::RecordWrite;
+use crate::record::Rec;
+use std::io::{self, BufRead};
+
+/// How a key field's bytes are compared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FieldType {
+    /// Lexicographic byte order.
+    #[default]
+    Bytes,
+    /// Unsigned big-endian integer.
+    UintBe,
+    /// Unsigned little-endian integer.
+    UintLe,
+    /// Two's complement big-endian integer.
+    IntBe,
+    /// Two's complement little-endian integer.
+    IntLe,
+}
+
+/// Bytes `offset..offset + len` of a record, compared as `kind`. Byte fields
+/// without a length run to the end of the record, and are cut short by shorter
+/// records; integer fields need a length and records that hold all of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyField {
+    pub offset: usize,
+    pub len: Option<usize>,
+    pub kind: FieldType,
+    pub descending: bool,
+}
+
+/// Sort order of a record: by its first field, then the second on ties, and so
+/// on. The fields index the record's bytes as the format splits them, key then
+/// payload: the whole 100 bytes of a gensort record, or a text line without its
+/// newline. With no fields, records are ordered by the format's key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeySpec {
+    fields: Vec<KeyField>,
+}
+
+impl KeySpec {
+    /// Order by `fields`. Integer fields need a non-zero length.
+    pub fn new(fields: Vec<KeyField>) -> Result<Self, String> {
+        for f in &fields {
+            if f.kind != FieldType::Bytes && !matches!(f.len, Some(1..)) {
+                return Err(format!(
+                    "integer key field at offset {} needs a length",
+                    f.offset
+                ));
+            }
+        }
+        Ok(Self { fields })
+    }
+
+    pub fn fields(&self) -> &[KeyField] {
+        &self.fields
+    }
+
+    /// True if records are ordered by the format's key.
+    pub fn is_format_key(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Length of every sort key, if all fields are integers.
+    pub fn fixed_len(&self) -> Option<usize> {
+        self.fields
+            .iter()
+            .map(|f| (f.kind != FieldType::Bytes).then_some(f.len?))
+            .sum()
+    }
+
+    /// Key of `record` whose lexicographic byte order is the spec's order.
+    ///
+    /// Integers become big-endian with the sign bit flipped. Byte fields are kept
+    /// as they are if they are the last field and ascending; otherwise zero bytes
+    /// are escaped as `00 FF` and the field ends with `00 00`, so that a field that
+    /// is a prefix of another sorts first without looking at the fields after
+    /// it. Descending fields have all their bytes inverted.
+    pub fn sort_key(&self, record: &[u8]) -> io::Result<Vec<u8>> {
+        let mut key = Vec::new();
+        for (i, f) in self.fields.iter().enumerate() {
+            let start = key.len();
+            let end = f
+                .len
+                .map_or(record.len(), |len| f.offset.saturating_add(len));
+            let bytes = record
+                .get(f.offset.min(record.len())..end.min(record.len()))
+                .unwrap_or_default();
+            match f.kind {
+                FieldType::Bytes if i + 1 == self.fields.len() && !f.descending => {
+                    key.extend_from_slice(bytes)
+                }
+                FieldType::Bytes => {
+                    for &b in bytes {
+                        key.push(b);
+                        if b == 0 {
+                            key.push(0xFF);
+                        }
+                    }
+                    key.extend_from_slice(&[0, 0]);
+                }
+                _ if end > record.len() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "record of {} bytes has no key field at bytes {}..{}",
+                            record.len(),
+                            f.offset,
+                            end
+                        ),
+                    ));
+                }
+                kind => {
+                    key.extend_from_slice(bytes);
+                    if matches!(kind, FieldType::UintLe | FieldType::IntLe) {
+                        key[start..].reverse();
+                    }
+                    if matches!(kind, FieldType::IntBe | FieldType::IntLe) {
+                        key[start] ^= 0x80;
+                    }
+                }
+            }
+            if f.descending {
+                key[start..].iter_mut().for_each(|b| *b = !*b);
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// Parses comma-separated fields `OFFSET[:LEN][:TYPE][:desc]`, with TYPE one of
+/// `bytes` (the default), `uint-be`, `uint-le`, `int-be` or `int-le`, e.g.
+/// `0:4:int-le:desc,10:8` for a descending 4-byte signed little-endian integer,
+/// then the 8 bytes from offset 10.
+impl std::str::FromStr for KeySpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s
+            .split(',')
+            .map(|field| {
+                let mut parts = field.split(':');
+                let offset = parts.next().unwrap_or_default();
+                let mut f = KeyField {
+                    offset: offset
+                        .parse()
+                        .map_err(|_| format!("invalid key field offset '{}'", offset))?,
+                    ..Default::default()
+                };
+                for part in parts {
+                    match part {
+                        "bytes" => f.kind = FieldType::Bytes,
+                        "uint-be" => f.kind = FieldType::UintBe,
+                        "uint-le" => f.kind = FieldType::UintLe,
+                        "int-be" => f.kind = FieldType::IntBe,
+                        "int-le" => f.kind = FieldType::IntLe,
+                        "asc" => f.descending = false,
+                        "desc" => f.descending = true,
+                        _ => match part.parse() {
+                            Ok(len) if f.len.is_none() => f.len = Some(len),
+                            _ => {
+                                return Err(format!(
+                                    "invalid key field '{}' (expected \
+                                     OFFSET[:LEN][:TYPE][:desc])",
+                                    field
+                                ));
+                            }
+                        },
+                    }
+                }
+                Ok(f)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        KeySpec::new(fields)
+    }
+}
+
+/// Writes the fields in the form `FromStr` parses, e.g. for run headers.
+impl std::fmt::Display for KeySpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", field.offset)?;
+            if let Some(len) = field.len {
+                write!(f, ":{}", len)?;
+            }
+            match field.kind {
+                FieldType::Bytes => {}
+                FieldType::UintBe => f.write_str(":uint-be")?,
+                FieldType::UintLe => f.write_str(":uint-le")?,
+                FieldType::IntBe => f.write_str(":int-be")?,
+                FieldType::IntLe => f.write_str(":int-le")?,
+            }
+            if field.descending {
+                f.write_str(":desc")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `RecordFormat` that orders the records of `F` by a `KeySpec`.
+///
+/// Decoded records have the spec's sort key as their key, and as their payload
+/// the length of the original key (u32 LE) followed by the original key and
+/// payload. Encoding writes the original record with `F`, so records keep their
+/// layout. With a spec that uses the format's key, records pass through as `F`
+/// decodes them. Records whose sort key or keyed payload exceed the `Rec` limits
+/// are `InvalidInput` errors when decoded, as runs could not hold them.
+///
+/// The format has the overhead of `F`, which frames the original records it
+/// writes, so keyed runs are merged in parallel like those of `F`. Runs of keyed
+/// records declare their spec, and are only merged by a `Keyed` output format
+/// with the same spec.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Keyed<F> {
+    format: F,
+    spec: KeySpec,
+}
+
+impl<F: RecordFormat> Keyed<F> {
+    pub fn new(format: F, spec: KeySpec) -> Self {
+        Self { format, spec }
+    }
+
+    pub fn spec(&self) -> &KeySpec {
+        &self.spec
+    }
+}
+
+impl<F: RecordFormat> RecordFormat for Keyed<F> {
+    fn decode<R: BufRead>(&self, r: &mut R) -> io::Result<Option<Rec>> {
+        let Some(rec) = self.format.decode(r)? else {
+            return Ok(None);
+        };
+        if self.spec.is_format_key() {
+            return Ok(Some(rec));
+        }
+        let mut payload = Vec::with_capacity(4 + rec.size());
+        payload.extend_from_slice(&(rec.key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&rec.key);
+        payload.extend_from_slice(&rec.payload);
+        let key = self.spec.sort_key(&payload[4..])?;
+        if key.len() > Rec::MAX_KEY_SIZE || payload.len() > Rec::MAX_PAYLOAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "record with key \"{}\" too large to sort by its key spec: {}-byte sort key \
+                     (limit {}) and {}-byte keyed payload (limit {})",
+                    rec.key[..rec.key.len().min(32)].escape_ascii(),
+                    key.len(),
+                    Rec::MAX_KEY_SIZE,
+                    payload.len(),
+                    Rec::MAX_PAYLOAD_SIZE
+                ),
+            ));
+        }
+        Ok(Some(Rec::new(key, payload)))
+    }
+
+    fn encode<W: RecordWrite>(&self, w: &mut W, rec: &Rec) -> io::Result<()> {
+        if self.spec.is_format_key() {
+            return self.format.encode(w, rec);
+        }
+        let original = rec
+            .payload
+            .split_first_chunk::<4>()
+            .and_then(|(len, record)| record.split_at_checked(u32::from_le_bytes(*len) as usize));
+        let Some((key, payload)) = original else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "keyed record without its original key",
+            ));
+        };
+        self.format.encode(w, &Rec::new(key, payload))
+    }
+
+    fn key_spec(&self) -> Option<&KeySpec> {
+        (!self.spec.is_format_key()).then_some(&self.spec)
+    }
+
+    fn overhead(&self) -> Option<u64> {
+        self.format.overhead()
+    }
+
+    fn encoded_size(&self, rec: &Rec) -> u64 {
+        if self.spec.is_format_key() {
+            return self.format.encoded_size(rec);
+        }
+        // The original key and payload, after their key length
+        self.format.overhead().unwrap_or(0) + rec.payload.len().saturating_sub(4) as u64
+    }
+
+    fn fixed_size(&self) -> Option<(usize, usize)> {
+        let (key_size, payload_size) = self.format.fixed_size()?;
+        if self.spec.is_format_key() {
+            Some((key_size, payload_size))
+        } else {
+            Some((self.spec.fixed_len()?, 4 + key_size + payload_size))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Gensort, TextLines};
+    use std::io::{BufWriter, Cursor};
+
+    /// `records` sorted by the keys `spec` gives them.
+    fn sorted(spec: &str, records: &[&[u8]]) -> Vec<Vec<u8>> {
+        let spec: KeySpec = spec.parse().unwrap();
+        let mut records: Vec<Vec<u8>> = records.iter().map(|r| r.to_vec()).collect();
+        records.sort_by_key(|r| spec.sort_key(r).unwrap());
+        records
+    }
+
+    #[test]
+    fn test_parse_key_spec() {
+        let spec: KeySpec = "4:2:int-le:desc,0".parse().unwrap();
+        assert_eq!(
+            spec.fields(),
+            [
+                KeyField {
+                    offset: 4,
+                    len: Some(2),
+                    kind: FieldType::IntLe,
+                    descending: true,
+                },
+                KeyField::default(),
+            ]
+        );
+        assert_eq!(spec.fixed_len(), None);
+        assert_eq!(
+            "0:4:uint-be,8:2:int-be"
+                .parse::<KeySpec>()
+                .unwrap()
+                .fixed_len(),
+            Some(6)
+        );
+        for text in ["4:2:int-le:desc,0", "0:4:uint-be,8:2:int-be", "3:desc,1:5"] {
+            assert_eq!(text.parse::<KeySpec>().unwrap().to_string(), text);
+        }
+        assert_eq!(
+            "0:4:bytes:asc".parse::<KeySpec>().unwrap().to_string(),
+            "0:4"
+        );
+        for bad in ["", "x", "0:4:float", "0:uint-le", "0:0:int-be", "0:4:4"] {
+            assert!(bad.parse::<KeySpec>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_integer_fields() {
+        let records: [&[u8]; 4] = [&[1, 0], &[0, 1], &[255, 255], &[0, 128]];
+        assert_eq!(
+            sorted("0:2:uint-be", &records),
+            [[0, 1], [0, 128], [1, 0], [255, 255]]
+        );
+        assert_eq!(
+            sorted("0:2:uint-le", &records),
+            [[1, 0], [0, 1], [0, 128], [255, 255]]
+        );
+        // -32768, -1, 1, 256
+        assert_eq!(
+            sorted("0:2:int-le", &records),
+            [[0, 128], [255, 255], [1, 0], [0, 1]]
+        );
+        assert_eq!(
+            sorted("0:2:int-be:desc", &records),
+            [[1, 0], [0, 128], [0, 1], [255, 255]]
+        );
+        let err = "1:2:uint-be".parse::<KeySpec>().unwrap().sort_key(&[1, 2]);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_composite_and_byte_fields() {
+        // Descending by the byte after the first two, then ascending by those two
+        let records: [&[u8]; 4] = [b"b\x00x", b"a\x00y", b"b\x00y", b"a\x00x"];
+        assert_eq!(
+            sorted("2:1:desc,0:2", &records),
+            [b"a\x00y", b"b\x00y", b"a\x00x", b"b\x00x"]
+        );
+        // A byte field that is a prefix of another sorts first, descending last,
+        // whatever follows it
+        let records: [&[u8]; 4] = [b"ab\x01", b"a\x00\x02", b"a\x00\x00\x03", b"a"];
+        assert_eq!(
+            sorted("0:2,2", &records),
+            [&b"a"[..], b"a\x00\x00\x03", b"a\x00\x02", b"ab\x01"]
+        );
+        assert_eq!(
+            sorted("0:2:desc,2", &records),
+            [&b"ab\x01"[..], b"a\x00\x00\x03", b"a\x00\x02", b"a"]
+        );
+        assert_eq!(
+            sorted("1:desc", &[b"xa", b"xab", b"x"]),
+            [&b"xab"[..], b"xa", b"x"]
+        );
+    }
+
+    #[test]
+    fn test_keyed_format_keeps_records() {
+        let mut data = vec![9u8; 10];
+        data.extend_from_slice(&[1u8; 86]);
+        data.extend_from_slice(&(-5i32).to_le_bytes());
+        let keyed = Keyed::new(Gensort, "96:4:int-le".parse().unwrap());
+        assert_eq!(keyed.fixed_size(), Some((4, 104)));
+        assert_eq!(keyed.overhead(), Some(0));
+        let rec = keyed.decode(&mut Cursor::new(&data)).unwrap().unwrap();
+        assert_eq!(keyed.encoded_size(&rec), 100);
+        assert_eq!(rec.key, [0x7F, 0xFF, 0xFF, 0xFB]);
+        let mut out = BufWriter::new(Vec::new());
+        keyed.encode(&mut out, &rec).unwrap();
+        assert_eq!(out.into_inner().unwrap(), data);
+
+        // The format's key passes records through
+        let plain = Keyed::new(TextLines::with_delimiter(b' '), KeySpec::default());
+        let rec = plain.decode(&mut Cursor::new(b"k v\n")).unwrap().unwrap();
+        assert_eq!(rec, Rec::new(b"k".to_vec(), b" v".to_vec()));
+        assert_eq!(plain.overhead(), Some(1));
+
+        let mut out = BufWriter::new(Vec::new());
+        let err = keyed.encode(&mut out, &Rec::new(vec![0], vec![9, 0, 0, 0]));
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Sort keys have the same limit as keys
+        let mut line = b"k ".to_vec();
+        line.extend(vec![b'x'; Rec::MAX_KEY_SIZE]);
+        line.push(b'\n');
+        let whole = Keyed::new(TextLines::with_delimiter(b' '), "0".parse().unwrap());
+        let err = whole.decode(&mut Cursor::new(&line)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("key \"k\""), "{}", err);
+        let rest = Keyed::new(TextLines::with_delimiter(b' '), "2:16".parse().unwrap());
+        assert_eq!(
+            rest.decode(&mut Cursor::new(&line))
+                .unwrap()
+                .unwrap()
+                .key
+                .len(),
+            16
+        );
+    }
+}
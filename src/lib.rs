@@ -1,3 +1,18 @@
+mod background;
+pub mod checksum;
+pub mod format;
+pub mod generate;
 pub mod io;
+pub mod key_spec;
+pub mod loser_tree;
+pub mod manifest;
+pub mod memory;
+pub mod merge;
 pub mod record;
 pub mod replacement_selection;
+pub mod run_dir;
+pub mod run_set;
+pub mod tag_sort;
+mod uring;
+pub mod validate;
+pub mod workspace;
@@ -0,0 +1,321 @@
+use std::cmp::Ordering;
+
+/// Tournament tree of losers over a fixed number of leaves.
+///
+/// Each internal node remembers the leaf that *lost* the match played there, and
+/// node 0 holds the overall winner (the minimum). Replacing the winner only replays
+/// the matches on its leaf-to-root path, so a replacement costs about log2(k)
+/// comparisons instead of the ~2·log2(k) of a binary heap pop + push.
+///
+/// Empty leaves (`None`) compare greater than every value, so exhausted inputs
+/// sink to the bottom and `peek` returns `None` once all leaves are empty.
+/// Ties between equal values are broken by leaf index.
+///
+/// Values are ordered by `Ord`, or by a comparison passed to the `_by` methods,
+/// e.g. one that looks values up in storage outside the tree. The `_by` methods
+/// must be given the same ordering every time.
+pub struct LoserTree<T> {
+    leaves: Vec<Option<T>>,
+    tree: Vec<usize>, // tree[0] = winner leaf, tree[1..k] = loser leaf of each match
+    live: usize,      // number of non-empty leaves
+}
+
+impl<T: Ord> LoserTree<T> {
+    /// Build a tree over `leaves`, one leaf per slot / input.
+    pub fn new(leaves: Vec<Option<T>>) -> Self {
+        Self::new_by(leaves, T::cmp)
+    }
+
+    /// Replace the winner with `value` (or empty its leaf with `None`) and replay
+    /// its path to the root. Returns the previous winner.
+    pub fn replace_top(&mut self, value: Option<T>) -> Option<T> {
+        self.replace_top_by(value, T::cmp)
+    }
+
+    /// Remove and return the winner, leaving its leaf empty.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.replace_top(None)
+    }
+
+    /// Replace the value of any leaf, e.g. refill an emptied one, and replay its
+    /// path to the root. Returns the leaf's previous value.
+    pub fn replace_leaf(&mut self, leaf: usize, value: Option<T>) -> Option<T> {
+        self.replace_leaf_by(leaf, value, T::cmp)
+    }
+}
+
+impl<T> LoserTree<T> {
+    /// Like `new`, ordering values by `cmp`.
+    pub fn new_by(leaves: Vec<Option<T>>, cmp: impl Fn(&T, &T) -> Ordering) -> Self {
+        let k = leaves.len();
+        let live = leaves.iter().filter(|l| l.is_some()).count();
+        let mut lt = Self {
+            leaves,
+            tree: vec![0; k.max(1)],
+            live,
+        };
+        lt.build(&cmp);
+        lt
+    }
+
+    /// Play all matches bottom-up.
+    fn build(&mut self, cmp: &impl Fn(&T, &T) -> Ordering) {
+        let k = self.leaves.len();
+        if k <= 1 {
+            return;
+        }
+        // winners[n] = winner of the subtree rooted at node n; leaves live at k..2k
+        let mut winners = vec![0usize; 2 * k];
+        for (i, w) in winners[k..].iter_mut().enumerate() {
+            *w = i;
+        }
+        for n in (1..k).rev() {
+            let (a, b) = (winners[2 * n], winners[2 * n + 1]);
+            if self.less(a, b, cmp) {
+                winners[n] = a;
+                self.tree[n] = b;
+            } else {
+                winners[n] = b;
+                self.tree[n] = a;
+            }
+        }
+        self.tree[0] = winners[1];
+    }
+
+    /// True if leaf `a` should be output before leaf `b`.
+    #[inline]
+    fn less(&self, a: usize, b: usize, cmp: &impl Fn(&T, &T) -> Ordering) -> bool {
+        match (&self.leaves[a], &self.leaves[b]) {
+            (Some(x), Some(y)) => match cmp(x, y) {
+                Ordering::Equal => a < b,
+                o => o == Ordering::Less,
+            },
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => a < b,
+        }
+    }
+
+    /// Number of leaves (the fixed fan-in of the tree).
+    pub fn capacity(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Number of non-empty leaves.
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// Smallest value in the tree, if any.
+    pub fn peek(&self) -> Option<&T> {
+        self.leaves.get(self.tree[0]).and_then(|l| l.as_ref())
+    }
+
+    /// Leaf index of the current winner (e.g. the run it came from), if any.
+    pub fn peek_index(&self) -> Option<usize> {
+        self.peek().map(|_| self.tree[0])
+    }
+
+    /// Like `replace_top`, ordering values by `cmp`.
+    pub fn replace_top_by(
+        &mut self,
+        value: Option<T>,
+        cmp: impl Fn(&T, &T) -> Ordering,
+    ) -> Option<T> {
+        let leaf = self.tree[0];
+        if leaf >= self.leaves.len() {
+            return None; // no leaves at all
+        }
+        if value.is_some() {
+            self.live += 1;
+        }
+        let old = std::mem::replace(&mut self.leaves[leaf], value);
+        if old.is_some() {
+            self.live -= 1;
+        }
+
+        let k = self.leaves.len();
+        let mut winner = leaf;
+        let mut node = (leaf + k) / 2;
+        while node > 0 {
+            if self.less(self.tree[node], winner, &cmp) {
+                std::mem::swap(&mut self.tree[node], &mut winner);
+            }
+            node /= 2;
+        }
+        self.tree[0] = winner;
+        old
+    }
+
+    /// Like `replace_leaf`, ordering values by `cmp`.
+    ///
+    /// Unlike the winner's, a leaf's path does not hold the winners of its
+    /// sibling subtrees, so those are recovered first: going down from the root,
+    /// a node's winner is the subtree's winner if it comes from below, otherwise
+    /// the loser stored at the node. Costs about log2(k) comparisons.
+    pub fn replace_leaf_by(
+        &mut self,
+        leaf: usize,
+        value: Option<T>,
+        cmp: impl Fn(&T, &T) -> Ordering,
+    ) -> Option<T> {
+        let k = self.leaves.len();
+        assert!(leaf < k, "leaf {} out of {}", leaf, k);
+        // Positions as in `build`: node n has children 2n and 2n+1, leaf i is at k+i
+        let below = |pos: usize, node: usize| pos >> (pos.ilog2() - node.ilog2()) == node;
+        let mut path = [0usize; usize::BITS as usize]; // winners of the path's nodes
+        let mut depth = 0;
+        let mut node = (leaf + k) / 2;
+        while node > 0 {
+            depth += 1;
+            node /= 2;
+        }
+        // Top-down: path[d] = winner of the node at depth d, path[depth] = the leaf
+        let mut winner = self.tree[0];
+        for (d, node_winner) in path[..depth].iter_mut().enumerate() {
+            let node = (leaf + k) >> (depth - d);
+            let child = (leaf + k) >> (depth - d - 1);
+            *node_winner = winner;
+            if !below(winner + k, child) {
+                winner = self.tree[node];
+            }
+        }
+
+        if value.is_some() {
+            self.live += 1;
+        }
+        let old = std::mem::replace(&mut self.leaves[leaf], value);
+        if old.is_some() {
+            self.live -= 1;
+        }
+
+        // Bottom-up: replay each match against the sibling subtree's winner
+        let mut winner = leaf;
+        for d in (0..depth).rev() {
+            let node = (leaf + k) >> (depth - d);
+            let child_winner = if d + 1 == depth { leaf } else { path[d + 1] };
+            let sibling = if path[d] == child_winner {
+                self.tree[node]
+            } else {
+                path[d]
+            };
+            if self.less(sibling, winner, &cmp) {
+                self.tree[node] = winner;
+                winner = sibling;
+            } else {
+                self.tree[node] = sibling;
+            }
+        }
+        self.tree[0] = winner;
+        old
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain<T: Ord>(mut lt: LoserTree<T>) -> Vec<T> {
+        let mut out = Vec::new();
+        while let Some(v) = lt.pop() {
+            out.push(v);
+        }
+        out
+    }
+
+    #[test]
+    fn test_empty_tree() {
+        let mut lt: LoserTree<u32> = LoserTree::new(vec![]);
+        assert!(lt.is_empty());
+        assert_eq!(lt.peek(), None);
+        assert_eq!(lt.pop(), None);
+        assert_eq!(lt.replace_top(Some(1)), None);
+    }
+
+    #[test]
+    fn test_single_leaf() {
+        let mut lt = LoserTree::new(vec![Some(5)]);
+        assert_eq!(lt.peek(), Some(&5));
+        assert_eq!(lt.replace_top(Some(3)), Some(5));
+        assert_eq!(lt.peek(), Some(&3));
+        assert_eq!(lt.pop(), Some(3));
+        assert!(lt.is_empty());
+    }
+
+    #[test]
+    fn test_pops_in_order_for_any_fan_in() {
+        for k in 1..20u32 {
+            let leaves = (0..k).map(|i| Some((i * 7919) % 31)).collect();
+            let mut expected: Vec<u32> = (0..k).map(|i| (i * 7919) % 31).collect();
+            expected.sort();
+            assert_eq!(drain(LoserTree::new(leaves)), expected, "k = {}", k);
+        }
+    }
+
+    #[test]
+    fn test_empty_leaves_sink() {
+        let lt = LoserTree::new(vec![None, Some(2), None, Some(1)]);
+        assert_eq!(lt.len(), 2);
+        assert_eq!(lt.peek_index(), Some(3));
+        assert_eq!(drain(lt), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_replace_top_merges_streams() {
+        // Three sorted streams, one per leaf
+        let mut streams = [vec![1, 4, 7], vec![2, 5, 8], vec![3, 6, 9]];
+        let leaves = streams.iter_mut().map(|s| Some(s.remove(0))).collect();
+        let mut lt = LoserTree::new(leaves);
+
+        let mut out = Vec::new();
+        while let Some(idx) = lt.peek_index() {
+            let next = if streams[idx].is_empty() {
+                None
+            } else {
+                Some(streams[idx].remove(0))
+            };
+            out.push(lt.replace_top(next).unwrap());
+        }
+        assert_eq!(out, vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_replace_any_leaf() {
+        for k in 1..20u32 {
+            let mut values: Vec<u32> = (0..k).map(|i| (i * 7919) % 31).collect();
+            let mut lt = LoserTree::new(values.iter().map(|&v| Some(v)).collect());
+            // Empty, refill and change leaves off the winner's path
+            for step in 0..3 * k {
+                let leaf = ((step * 13) % k) as usize;
+                let value = (step % 4 != 0).then_some((step * 37) % 29);
+                let old = lt.replace_leaf(leaf, value);
+                assert_eq!(old, (values[leaf] != u32::MAX).then_some(values[leaf]));
+                values[leaf] = value.unwrap_or(u32::MAX);
+                let min = (0..k as usize).min_by_key(|&i| (values[i], i)).unwrap();
+                let expected = (values[min] != u32::MAX).then_some(min);
+                assert_eq!(lt.peek_index(), expected, "k = {}, step = {}", k, step);
+            }
+            let mut expected: Vec<u32> = values.into_iter().filter(|&v| v != u32::MAX).collect();
+            expected.sort();
+            assert_eq!(drain(lt), expected, "k = {}", k);
+        }
+    }
+
+    #[test]
+    fn test_ties_broken_by_leaf_index() {
+        let mut lt = LoserTree::new(vec![Some(1), Some(1), Some(0), Some(1)]);
+        assert_eq!(lt.pop(), Some(0));
+        assert_eq!(lt.peek_index(), Some(0));
+        lt.pop();
+        assert_eq!(lt.peek_index(), Some(1));
+        lt.pop();
+        assert_eq!(lt.peek_index(), Some(3));
+    }
+}
@@ -1,25 +1,193 @@
-mod io;
-mod record;
-pub mod replacement_selection;
-
-use replacement_selection::ReplacementSelection;
-
-fn main() -> std::io::Result<()> {
-    // ---- CLI & params ----
-    // Usage: cargo run --release -- <gensort_input.bin>
-    let input_path = std::env::args()
-        .nth(1)
-        .expect("Usage: replacement_selection <gensort_input.bin>");
-    let heap_cap = std::env::var("HEAP_CAP")
-        .ok()
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(10_000_000); // adjust: memory_budget / record_size // 10M * 100bytes = 1GB
-    let out_prefix = std::env::var("RUN_PREFIX").unwrap_or_else(|_| "run".to_string());
-
-    // ---- Run replacement selection ----
-    let rs = ReplacementSelection::new(heap_cap, out_prefix.clone());
-    let num_runs = rs.run_from_file(&input_path)?;
-
-    eprintln!("Wrote {} run(s) with prefix '{}_'", num_runs, out_prefix);
-    Ok(())
+mod cli;
+
+use cli::{Cli, Command, Options};
+use rs::generate::Generator;
+use rs::io::{InputFormat, OutputFormat};
+use rs::key_spec::Keyed;
+use rs::manifest::{read_manifest, verify_runs};
+use rs::merge::Merger;
+use rs::replacement_selection::ReplacementSelection;
+use rs::validate::{validate, validate_file, validate_run, validate_run_file};
+use std::io::{BufReader, BufWriter, Read, StdinLock, StdoutLock, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+/// Path argument that stands for stdin or stdout.
+const STDIO: &str = "-";
+
+const EXIT_IO: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_UNSORTED: u8 = 3;
+
+fn main() -> ExitCode {
+    let cli = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    match run(cli) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(EXIT_IO)
+        }
+    }
+}
+
+/// Replacement selection configured from the shared flags, writing runs as `dir/prefix_N.bin`.
+fn workspace(
+    opts: &Options,
+    dir: &str,
+) -> std::io::Result<ReplacementSelection<Keyed<InputFormat>>> {
+    std::fs::create_dir_all(dir)?;
+    let prefix = Path::new(dir)
+        .join(&opts.prefix)
+        .to_string_lossy()
+        .into_owned();
+    let rs = match opts.heap_cap {
+        Some(heap_cap) => ReplacementSelection::new(heap_cap, prefix),
+        None => ReplacementSelection::with_memory_budget(opts.mem, prefix),
+    };
+    Ok(rs
+        .with_format(Keyed::new(
+            opts.input_format.unwrap_or_default(),
+            opts.key.clone(),
+        ))
+        .with_io_options(opts.io)
+        .with_compact_runs(opts.compact_runs)
+        .with_tag_sort(opts.tag_sort)
+        .with_threads(opts.threads))
+}
+
+fn merger(opts: &Options) -> Merger<Keyed<OutputFormat>> {
+    Merger::new()
+        .with_format(Keyed::new(opts.output_format, opts.key.clone()))
+        .with_io_options(opts.io)
+        .with_threads(opts.threads)
+}
+
+fn stdin(opts: &Options) -> BufReader<StdinLock<'static>> {
+    BufReader::with_capacity(opts.io.buffer_size, std::io::stdin().lock())
+}
+
+fn stdout(opts: &Options) -> BufWriter<StdoutLock<'static>> {
+    BufWriter::with_capacity(opts.io.buffer_size, std::io::stdout().lock())
+}
+
+/// Sort `rdr` into `output`, a file or `-` for stdout.
+fn sort_stream(
+    rs: &ReplacementSelection<Keyed<InputFormat>>,
+    rdr: impl Read,
+    output: &str,
+    opts: &Options,
+) -> std::io::Result<u64> {
+    if output == STDIO {
+        let mut out = stdout(opts);
+        let merged = rs.sort(rdr, &mut out, &merger(opts))?;
+        out.flush()?;
+        Ok(merged)
+    } else {
+        rs.sort_to_file(rdr, output, &merger(opts))
+    }
+}
+
+fn run(cli: Cli) -> std::io::Result<ExitCode> {
+    let opts = cli.options;
+    match cli.command {
+        Command::Help => println!("{}", cli::USAGE),
+        Command::Runs { input } => {
+            let rs = workspace(&opts, &opts.output_dir)?;
+            let runs = if input == STDIO {
+                rs.run(stdin(&opts))?
+            } else {
+                rs.run_from_file(&input)?
+            };
+            for run in &runs {
+                eprintln!(
+                    "{}: {} record(s), {} bytes, generation {}",
+                    run.path, run.records, run.bytes, run.generation
+                );
+            }
+            eprintln!(
+                "Wrote {} record(s) in {} run(s) to '{}', manifest '{}'",
+                runs.total_records(),
+                runs.len(),
+                opts.output_dir,
+                rs.manifest_path()
+            );
+        }
+        Command::Merge { output, mut runs } => {
+            if let Some(manifest) = &opts.manifest {
+                let listed = read_manifest(manifest)?;
+                verify_runs(&listed)?;
+                runs = listed.paths();
+            }
+            let merged = if output == STDIO {
+                let mut out = stdout(&opts);
+                let merged = merger(&opts).merge_into(&runs, &mut out)?;
+                out.flush()?;
+                merged
+            } else {
+                merger(&opts).merge(&runs, &output)?
+            };
+            eprintln!("Merged {} record(s) into '{}'", merged, output);
+        }
+        Command::Sort { input, output } => {
+            let rs = workspace(&opts, ".")?
+                .with_temp_dir(&opts.temp_dir)
+                .with_keep_runs(opts.keep_runs);
+            let merged = if input == STDIO {
+                sort_stream(&rs, stdin(&opts), &output, &opts)?
+            } else if output == STDIO {
+                let mut out = stdout(&opts);
+                let merged = rs.sort_from_file_into(&input, &mut out, &merger(&opts))?;
+                out.flush()?;
+                merged
+            } else {
+                rs.sort_from_file_with(&input, &output, &merger(&opts))?
+            };
+            eprintln!("Merged {} record(s) into '{}'", merged, output);
+        }
+        Command::Validate { file } => {
+            let v = match (opts.input_format, file == STDIO) {
+                (Some(format), true) => {
+                    validate(stdin(&opts), Keyed::new(format, opts.key.clone()))?
+                }
+                (Some(format), false) => {
+                    let format = Keyed::new(format, opts.key.clone());
+                    validate_file(&file, format, opts.io.mode)?
+                }
+                (None, true) => validate_run(stdin(&opts))?,
+                (None, false) => validate_run_file(&file, opts.io.mode)?,
+            };
+            eprintln!("Records: {}", v.records);
+            eprintln!("Checksum: {:x}", v.checksum);
+            eprintln!("Duplicate keys: {}", v.duplicates);
+            if let Some(idx) = v.first_unsorted {
+                eprintln!(
+                    "NOT SORTED: {} unordered record(s), first is record {}",
+                    v.unsorted, idx
+                );
+                return Ok(ExitCode::from(EXIT_UNSORTED));
+            }
+            eprintln!("SUCCESS - all records are in order");
+        }
+        Command::Gen { output } => {
+            let records = opts.records.unwrap_or_default();
+            let generator = Generator::new(records, opts.seed)
+                .with_key_type(opts.key_type)
+                .with_distribution(opts.distribution);
+            if output == STDIO {
+                let mut out = stdout(&opts);
+                generator.write_to(&mut out)?;
+                out.flush()?;
+            } else {
+                generator.write_file(&output, opts.io.mode)?;
+            }
+            eprintln!("Wrote {} record(s) to '{}'", records, output);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
 }
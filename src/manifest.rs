@@ -0,0 +1,297 @@
+//! Run manifest: a small binary file listing the runs of one run-generation job.
+//!
+//! Layout (all integers little-endian):
+//!
+//! ```text
+//! [8]  magic "RSMANIFS"
+//! u32  manifest version
+//! u32  run file format
+//! u64  run count
+//! per run:
+//!   u32 name_len, name     file name, relative to the manifest's directory
+//!   u64 records
+//!   u64 bytes
+//!   u64 generation
+//!   u32 checksum           CRC-32 of the run file contents
+//!   u32 min_key_len, min_key
+//!   u32 max_key_len, max_key
+//! u32  CRC-32 of everything above
+//! ```
+//!
+//! The manifest is written to a temporary file, fsynced and renamed into place,
+//! so a reader either sees a complete manifest or none at all. Run generation
+//! syncs the runs before committing their manifest.
+
+use crate::checksum::{Crc32, crc32};
+use crate::io::sync_parent_dir;
+use crate::run_set::{RunInfo, RunSet};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub const MANIFEST_MAGIC: [u8; 8] = *b"RSMANIFS";
+pub const MANIFEST_VERSION: u32 = 1;
+/// Run files use the `[u32 key_len][key][u32 payload_len][payload]` layout.
+pub const RUN_FORMAT_LEN_PREFIXED: u32 = 1;
+/// Run files start with a header declaring their record layout, see `io::RunLayout`.
+pub const RUN_FORMAT_HEADER: u32 = 2;
+
+/// Manifest path for runs written with `prefix`.
+pub fn manifest_path(prefix: &str) -> String {
+    format!("{}.manifest", prefix)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn encode(runs: &RunSet) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&MANIFEST_MAGIC);
+    buf.extend_from_slice(&MANIFEST_VERSION.to_le_bytes());
+    buf.extend_from_slice(&RUN_FORMAT_HEADER.to_le_bytes());
+    buf.extend_from_slice(&(runs.len() as u64).to_le_bytes());
+    for run in runs {
+        let name = Path::new(&run.path)
+            .file_name()
+            .map_or_else(|| run.path.clone(), |n| n.to_string_lossy().into_owned());
+        put_bytes(&mut buf, name.as_bytes());
+        buf.extend_from_slice(&run.records.to_le_bytes());
+        buf.extend_from_slice(&run.bytes.to_le_bytes());
+        buf.extend_from_slice(&run.generation.to_le_bytes());
+        buf.extend_from_slice(&run.checksum.to_le_bytes());
+        put_bytes(&mut buf, &run.min_key);
+        put_bytes(&mut buf, &run.max_key);
+    }
+    let crc = crc32(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// Cursor over the manifest bytes; running out of data is a corrupt manifest.
+struct Parser<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(invalid("truncated manifest"));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+fn decode(data: &[u8], dir: &Path) -> io::Result<RunSet> {
+    if data.len() < 4 {
+        return Err(invalid("truncated manifest"));
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(invalid("manifest checksum mismatch"));
+    }
+
+    let mut p = Parser { data: body };
+    if p.take(MANIFEST_MAGIC.len())? != MANIFEST_MAGIC {
+        return Err(invalid("not a run manifest"));
+    }
+    let version = p.u32()?;
+    if version != MANIFEST_VERSION {
+        return Err(invalid(format!("unsupported manifest version {}", version)));
+    }
+    let run_format = p.u32()?;
+    if run_format != RUN_FORMAT_LEN_PREFIXED && run_format != RUN_FORMAT_HEADER {
+        return Err(invalid(format!("unsupported run format {}", run_format)));
+    }
+
+    let count = p.u64()?;
+    let mut runs = RunSet::default();
+    for _ in 0..count {
+        let name = String::from_utf8(p.bytes()?).map_err(|_| invalid("run name is not UTF-8"))?;
+        runs.runs.push(RunInfo {
+            path: dir.join(name).to_string_lossy().into_owned(),
+            records: p.u64()?,
+            bytes: p.u64()?,
+            generation: p.u64()?,
+            checksum: p.u32()?,
+            min_key: p.bytes()?,
+            max_key: p.bytes()?,
+        });
+    }
+    if !p.data.is_empty() {
+        return Err(invalid("trailing data in manifest"));
+    }
+    Ok(runs)
+}
+
+/// Atomically write the manifest for `runs` to `path`: write a temp file, fsync it,
+/// rename it over `path` and fsync the directory.
+pub fn write_manifest(path: &str, runs: &RunSet) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let res = (|| {
+        let mut f = File::create(&tmp)?;
+        f.write_all(&encode(runs))?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        sync_parent_dir(Path::new(path))
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res
+}
+
+/// Read the manifest at `path`. Run paths are resolved against its directory.
+pub fn read_manifest(path: &str) -> io::Result<RunSet> {
+    let data = std::fs::read(path)?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    decode(&data, dir)
+}
+
+/// Check that every run in `runs` still has the size and checksum recorded for
+/// it, so a run truncated or rewritten since the manifest was committed is an
+/// `InvalidData` error instead of a short or corrupt merge. Sizes are checked
+/// first; the checksums then read every run once.
+pub fn verify_runs(runs: &RunSet) -> io::Result<()> {
+    let context = |run: &RunInfo| {
+        let path = run.path.clone();
+        move |e: io::Error| io::Error::new(e.kind(), format!("run {}: {}", path, e))
+    };
+    for run in runs {
+        let len = std::fs::metadata(&run.path).map_err(context(run))?.len();
+        if len != run.bytes {
+            return Err(invalid(format!(
+                "run {} has {} bytes, manifest lists {}",
+                run.path, len, run.bytes
+            )));
+        }
+    }
+    let mut buf = vec![0u8; 1 << 20];
+    for run in runs {
+        let mut file = File::open(&run.path).map_err(context(run))?;
+        let mut crc = Crc32::new();
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => crc.update(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(context(run)(e)),
+            }
+        }
+        if crc.value() != run.checksum {
+            return Err(invalid(format!(
+                "run {} has checksum {:08x}, manifest lists {:08x}",
+                run.path,
+                crc.value(),
+                run.checksum
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_runs(dir: &str) -> RunSet {
+        RunSet {
+            runs: (0..3u8)
+                .map(|i| RunInfo {
+                    path: format!("{}/run_{:08}.bin", dir, i),
+                    records: 10 * i as u64 + 1,
+                    bytes: 108 * (10 * i as u64 + 1),
+                    min_key: vec![i; 10],
+                    max_key: vec![i + 1; 4],
+                    generation: i as u64,
+                    checksum: 0xDEAD_0000 + i as u32,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let dir = "test_manifest_dir";
+        std::fs::create_dir_all(dir).unwrap();
+        let runs = sample_runs(dir);
+        let path = manifest_path(&format!("{}/run", dir));
+
+        write_manifest(&path, &runs).unwrap();
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert_eq!(read_manifest(&path).unwrap(), runs);
+
+        // Empty run sets are valid manifests too
+        write_manifest(&path, &RunSet::default()).unwrap();
+        assert!(read_manifest(&path).unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_verify_runs_checks_sizes_and_checksums() {
+        let dir = "test_manifest_verify";
+        std::fs::create_dir_all(dir).unwrap();
+        let mut runs = sample_runs(dir);
+        for run in &mut runs.runs {
+            let data = vec![0u8; run.bytes as usize];
+            run.checksum = crc32(&data);
+            std::fs::write(&run.path, data).unwrap();
+        }
+        verify_runs(&runs).unwrap();
+
+        // Same size, different contents
+        let mut data = vec![0u8; runs.runs[2].bytes as usize];
+        data[7] = 1;
+        std::fs::write(&runs.runs[2].path, data).unwrap();
+        let err = verify_runs(&runs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        std::fs::write(&runs.runs[1].path, [0u8; 100]).unwrap();
+        let err = verify_runs(&runs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("run_00000001.bin"), "{}", err);
+        std::fs::remove_file(&runs.runs[1].path).unwrap();
+        let err = verify_runs(&runs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_manifest_detects_corruption() {
+        let data = encode(&sample_runs("d"));
+        let dir = Path::new("d");
+        assert!(decode(&data, dir).is_ok());
+
+        let mut flipped = data.clone();
+        flipped[30] ^= 0x01;
+        let err = decode(&flipped, dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = decode(&data[..data.len() / 2], dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(decode(&[], dir).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::io::IoOptions;
+use crate::record::Rec;
+use crate::workspace::Workspace;
+
+/// Per-allocation bookkeeping charged on top of the requested size (malloc header).
+const ALLOC_OVERHEAD: usize = 16;
+/// Allocation granularity of the system allocator.
+const ALLOC_ALIGN: usize = 16;
+
+/// Memory budget for the run-generation workspace, in bytes.
+///
+/// Charges the real footprint of everything `ReplacementSelection` keeps resident:
+/// the reader and writer I/O buffers, one workspace slot per record (see
+/// `Workspace::SLOT_BYTES`) and each record's key and payload heap allocations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryBudget {
+    bytes: usize,
+}
+
+impl MemoryBudget {
+    /// Bytes per workspace slot, excluding the record's own key/payload buffers.
+    pub const SLOT_BYTES: usize = Workspace::SLOT_BYTES;
+
+    pub fn new(bytes: usize) -> Self {
+        Self { bytes }
+    }
+
+    /// Parse a size such as `2G`, `512M`, `64KiB` or `1000000` (binary units).
+    pub fn parse(s: &str) -> Option<Self> {
+        parse_size(s).map(Self::new)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Bytes held by the reader and writer buffers with the default `IoOptions`.
+    pub fn io_bytes() -> usize {
+        Self::io_bytes_for(&IoOptions::default())
+    }
+
+    /// Bytes held by the reader and writer buffers with `io`.
+    pub fn io_bytes_for(io: &IoOptions) -> usize {
+        2 * io.buffer_bytes()
+    }
+
+    /// Bytes left for workspace slots and records after the I/O buffers of `io`.
+    pub fn workspace_bytes_for(&self, io: &IoOptions) -> usize {
+        self.bytes.saturating_sub(Self::io_bytes_for(io))
+    }
+
+    /// Equal share of the budget for one of `parts` workers, after setting aside
+    /// `reserved` bytes for state shared between them.
+    pub fn split(&self, parts: usize, reserved: usize) -> Self {
+        Self::new(self.bytes.saturating_sub(reserved) / parts.max(1))
+    }
+
+    /// Heap bytes used by a record's key and payload buffers.
+    pub fn record_bytes(rec: &Rec) -> usize {
+        heap_bytes(rec.key.capacity()) + heap_bytes(rec.payload.capacity())
+    }
+
+    /// Total bytes charged for holding `rec` in the workspace.
+    pub fn item_bytes(rec: &Rec) -> usize {
+        Self::SLOT_BYTES + Self::record_bytes(rec)
+    }
+
+    /// Number of fixed-size records (`key_size` + `payload_size`) the budget holds
+    /// next to the I/O buffers of `io`.
+    pub fn records_for(&self, io: &IoOptions, key_size: usize, payload_size: usize) -> usize {
+        let per_item = Self::SLOT_BYTES + heap_bytes(key_size) + heap_bytes(payload_size);
+        self.workspace_bytes_for(io) / per_item
+    }
+}
+
+/// Size of a heap allocation of `cap` bytes including allocator overhead.
+fn heap_bytes(cap: usize) -> usize {
+    if cap == 0 {
+        0
+    } else {
+        cap.div_ceil(ALLOC_ALIGN) * ALLOC_ALIGN + ALLOC_OVERHEAD
+    }
+}
+
+/// Parse a byte count with an optional `K`/`M`/`G`/`T` suffix (powers of 1024).
+/// `B`, `iB` and lowercase suffixes are accepted: `2G`, `2GB`, `2GiB`, `2g`.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let upper = s.to_ascii_uppercase();
+    let digits_end = upper
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(upper.len());
+    let (num, suffix) = upper.split_at(digits_end);
+    let n: usize = num.parse().ok()?;
+    let shift = match suffix.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    n.checked_mul(1usize << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("64K"), Some(64 << 10));
+        assert_eq!(parse_size("512m"), Some(512 << 20));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        assert_eq!(parse_size("2GB"), Some(2 << 30));
+        assert_eq!(parse_size("2GiB"), Some(2 << 30));
+        assert_eq!(parse_size(" 1T "), Some(1 << 40));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("12X"), None);
+        assert_eq!(parse_size("1.5G"), None);
+    }
+
+    #[test]
+    fn test_item_bytes_counts_overhead() {
+        let rec = Rec::new([0u8; 10], [0u8; 90]);
+        // Key and payload are rounded up to the allocator granularity plus a header
+        assert_eq!(MemoryBudget::record_bytes(&rec), (16 + 16) + (96 + 16));
+        assert!(MemoryBudget::item_bytes(&rec) > rec.size());
+    }
+
+    #[test]
+    fn test_split_budget() {
+        let budget = MemoryBudget::new(10_000);
+        assert_eq!(budget.split(4, 2_000), MemoryBudget::new(2_000));
+        assert_eq!(budget.split(1, 0), budget);
+        assert_eq!(budget.split(3, 20_000), MemoryBudget::new(0));
+    }
+
+    #[test]
+    fn test_records_for_fixed_size() {
+        let rec = Rec::new([0u8; 10], [0u8; 90]);
+        let per_item = MemoryBudget::item_bytes(&rec);
+        let io = IoOptions::default().with_buffer_size(4096);
+        let budget = MemoryBudget::new(MemoryBudget::io_bytes_for(&io) + 1000 * per_item + 1);
+        assert_eq!(budget.records_for(&io, 10, 90), 1000);
+
+        // More I/O buffers leave room for fewer records
+        let threaded = io.with_backend(crate::io::IoBackend::Threaded { buffers: 2 });
+        let extra = MemoryBudget::io_bytes_for(&threaded) - MemoryBudget::io_bytes_for(&io);
+        assert!(extra > 0);
+        assert_eq!(
+            budget.records_for(&threaded, 10, 90),
+            1000 - extra.div_ceil(per_item)
+        );
+
+        // Budget smaller than the I/O buffers holds nothing
+        assert_eq!(MemoryBudget::new(100).records_for(&io, 10, 90), 0);
+    }
+}
@@ -0,0 +1,691 @@
+use crate::format::RecordFormat;
+use crate::io::{
+    DirectReader, IoBackend, IoMode, IoOptions, OutputFormat, RecordWrite, RegionWriter,
+    RunContents, RunLayout, RunReader, open_reader_with, open_writer_with, sync_parent_dir,
+};
+use crate::loser_tree::LoserTree;
+use crate::record::{Rec, key_prefix};
+use crate::tag_sort::Gather;
+use std::cmp::Ordering;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Splitter samples taken from each run for the parallel merge, spaced by bytes.
+const SAMPLES_PER_RUN: u64 = 256;
+
+/// Head record of one run during the merge.
+struct MergeItem {
+    rec: Rec,
+    run: usize,   // index of the run this record came from
+    prefix: u128, // `key_prefix` of the record's key
+}
+
+impl MergeItem {
+    fn new(rec: Rec, run: usize) -> Self {
+        let prefix = key_prefix(&rec.key);
+        Self { rec, run, prefix }
+    }
+}
+
+impl PartialEq for MergeItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for MergeItem {}
+
+impl PartialOrd for MergeItem {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeItem {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Primary by key, then by run index so equal keys keep run order (stable merge).
+        // Full keys are only compared when their prefixes are equal.
+        self.prefix
+            .cmp(&other.prefix)
+            .then_with(|| self.rec.key.cmp(&other.rec.key))
+            .then_with(|| self.run.cmp(&other.run))
+    }
+}
+
+/// Record position in a run: byte offset, number of records before it and the
+/// bytes those records take in the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Position {
+    offset: u64,
+    ordinal: u64,
+    output: u64,
+}
+
+/// Sampled record of a run; samples of one run are in file order.
+struct Sample {
+    key: Vec<u8>,
+    run: usize,
+    pos: Position,
+}
+
+impl Sample {
+    /// Order of the record in the merged output: by key, then run, then position.
+    fn cmp_record(&self, key: &[u8], run: usize, ordinal: u64) -> Ordering {
+        (self.key.as_slice(), self.run, self.pos.ordinal).cmp(&(key, run, ordinal))
+    }
+}
+
+/// Sparse index of one run: its record layout, where its records start and end,
+/// and `SAMPLES_PER_RUN` samples in between.
+struct RunIndex {
+    layout: RunLayout,
+    start: Position,
+    samples: Vec<Sample>,
+    end: Position,
+}
+
+/// K-way merge of sorted run files into one sorted output file, encoded in the
+/// `RecordFormat` `F`.
+#[derive(Clone, Debug)]
+pub struct Merger<F = OutputFormat> {
+    format: F,
+    io: IoOptions,
+    threads: usize,
+}
+
+impl Default for Merger {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            io: IoOptions::default(),
+            threads: 1,
+        }
+    }
+}
+
+impl Merger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the layout of the merged output (length-prefixed by default).
+    pub fn with_output_format(mut self, output_format: OutputFormat) -> Self {
+        self.format = output_format;
+        self
+    }
+}
+
+impl<F: RecordFormat> Merger<F> {
+    /// Encode the merged output in `format`.
+    pub fn with_format<G: RecordFormat>(self, format: G) -> Merger<G> {
+        Merger {
+            format,
+            io: self.io,
+            threads: self.threads,
+        }
+    }
+
+    /// Set how runs are read and the output is written (Direct I/O by default).
+    pub fn with_io_mode(mut self, io_mode: IoMode) -> Self {
+        self.io.mode = io_mode;
+        self
+    }
+
+    /// Set how reads and writes are issued (blocking calls by default). Every run
+    /// reader holds `IoOptions::buffer_bytes()` of buffers, so with
+    /// `IoBackend::Uring` memory grows by several buffers per run. The output
+    /// regions of a parallel merge are always written with blocking calls.
+    pub fn with_io_backend(mut self, io_backend: IoBackend) -> Self {
+        self.io.backend = io_backend;
+        self
+    }
+
+    /// Set the I/O mode, backend, buffer size, alignment and durability at once.
+    pub fn with_io_options(mut self, io: IoOptions) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// Merge with `threads` threads (default 1). The runs are split into key ranges
+    /// of about equal size, each merged by one thread into its own contiguous region
+    /// of the output. The output is identical to a single-threaded merge. Formats
+    /// without a `RecordFormat::overhead` are always merged by one thread.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Merge the sorted run files at `run_paths` into one sorted file at `output_path`.
+    /// Returns the number of records merged.
+    pub fn merge(&self, run_paths: &[String], output_path: &str) -> io::Result<u64> {
+        if self.format.overhead().is_some() && self.threads > 1 && !run_paths.is_empty() {
+            return self.merge_parallel(run_paths, output_path);
+        }
+        let mut readers = self.open_runs(run_paths, &self.run_contents(false))?;
+        let mut writer = open_writer_with(output_path, self.io)?;
+        let merged = merge_readers(&mut readers, |rec| self.format.encode(&mut writer, rec))?;
+        writer.finish()?;
+        Ok(merged)
+    }
+
+    /// Merge the sorted run files at `run_paths` into `writer`, for outputs that
+    /// are not files, such as stdout. The merge is single-threaded whatever
+    /// `with_threads` says, and `writer` is not flushed. Returns the number of
+    /// records merged.
+    pub fn merge_into<W: RecordWrite>(
+        &self,
+        run_paths: &[String],
+        writer: &mut W,
+    ) -> io::Result<u64> {
+        let mut readers = self.open_runs(run_paths, &self.run_contents(false))?;
+        merge_readers(&mut readers, |rec| self.format.encode(writer, rec))
+    }
+
+    /// Merge the tag runs of a tag sort (see `ReplacementSelection::with_tag_sort`)
+    /// at `run_paths` and write the records behind the tags, read by `gather`, to
+    /// `output_path`. The merge is single-threaded whatever `with_threads` says.
+    /// Returns the number of records merged.
+    pub fn merge_tags<I: RecordFormat>(
+        &self,
+        run_paths: &[String],
+        gather: &mut Gather<I>,
+        output_path: &str,
+    ) -> io::Result<u64> {
+        let mut writer = open_writer_with(output_path, self.io)?;
+        let merged = self.merge_tags_into(run_paths, gather, &mut writer)?;
+        writer.finish()?;
+        Ok(merged)
+    }
+
+    /// Like `merge_tags`, into `writer`, which is not flushed.
+    pub fn merge_tags_into<I: RecordFormat, W: RecordWrite>(
+        &self,
+        run_paths: &[String],
+        gather: &mut Gather<I>,
+        writer: &mut W,
+    ) -> io::Result<u64> {
+        let mut readers = self.open_runs(run_paths, &self.run_contents(true))?;
+        merge_readers(&mut readers, |tag| {
+            self.format.encode(writer, &gather.record(tag)?)
+        })
+    }
+
+    /// Parallel merge: index the runs, pick splitters from the samples, find each
+    /// splitter's position in every run, then merge the partitions concurrently.
+    /// Each partition's output region holds the encoded sizes of its records.
+    fn merge_parallel(&self, run_paths: &[String], output_path: &str) -> io::Result<u64> {
+        let indexes = parallel_map(run_paths, self.threads, |run, path| {
+            self.index_run(run, path)
+        })
+        .into_iter()
+        .collect::<io::Result<Vec<_>>>()?;
+
+        // Splitters: evenly spaced samples in merged order. Each sample stands for
+        // about the same number of bytes of its run.
+        let mut samples: Vec<&Sample> = indexes.iter().flat_map(|i| &i.samples).collect();
+        samples.sort_by(|a, b| a.cmp_record(&b.key, b.run, b.pos.ordinal));
+        let mut splitters: Vec<&Sample> = (1..self.threads)
+            .filter(|_| !samples.is_empty())
+            .map(|t| samples[t * samples.len() / self.threads])
+            .collect();
+        splitters.dedup_by(|a, b| a.cmp_record(&b.key, b.run, b.pos.ordinal).is_eq());
+
+        // bounds[p][run]: where partition p starts in each run
+        let mut bounds = vec![indexes.iter().map(|i| i.start).collect::<Vec<_>>()];
+        for cut in parallel_map(&splitters, self.threads, |_, s| {
+            self.split_positions(run_paths, &indexes, s)
+        }) {
+            bounds.push(cut?);
+        }
+        bounds.push(indexes.iter().map(|i| i.end).collect());
+
+        // Output region of each partition: the records' encoded sizes
+        let region_size = |from: &[Position], to: &[Position]| -> u64 {
+            from.iter().zip(to).map(|(a, b)| b.output - a.output).sum()
+        };
+        let mut starts = vec![0u64];
+        for w in bounds.windows(2) {
+            starts.push(starts.last().unwrap() + region_size(&w[0], &w[1]));
+        }
+        let total = *starts.last().unwrap();
+        std::fs::File::create(output_path)?.set_len(total)?;
+
+        let partitions: Vec<usize> = (0..bounds.len() - 1).collect();
+        let merged = parallel_map(&partitions, self.threads, |_, &p| {
+            self.merge_partition(
+                run_paths,
+                &indexes,
+                &bounds[p],
+                &bounds[p + 1],
+                output_path,
+                starts[p],
+            )
+            .and_then(|(merged, written)| {
+                if written != starts[p + 1] - starts[p] {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "run changed while it was being merged",
+                    ));
+                }
+                Ok(merged)
+            })
+        });
+        let merged = merged.into_iter().sum::<io::Result<u64>>()?;
+        if self.io.durable {
+            std::fs::File::open(output_path)?.sync_data()?;
+            sync_parent_dir(Path::new(output_path))?;
+        }
+        Ok(merged)
+    }
+
+    /// Read a run once, sampling a record every `bytes / SAMPLES_PER_RUN` bytes.
+    fn index_run(&self, run: usize, path: &str) -> io::Result<RunIndex> {
+        let size = std::fs::metadata(path)?.len();
+        let step = (size / SAMPLES_PER_RUN).max(1);
+        let mut rdr = self.open_run(path, &self.run_contents(false))?;
+        let layout = rdr.layout()?;
+        let start = Position {
+            offset: rdr.offset(),
+            ..Position::default()
+        };
+        let mut samples = Vec::new();
+        let mut next_sample = 0;
+        let mut ordinal = 0;
+        let mut output = 0;
+        loop {
+            let offset = rdr.offset();
+            let Some(rec) = rdr.next_rec()? else {
+                let end = Position {
+                    offset,
+                    ordinal,
+                    output,
+                };
+                return Ok(RunIndex {
+                    layout,
+                    start,
+                    samples,
+                    end,
+                });
+            };
+            let size = self.format.encoded_size(&rec);
+            if offset >= next_sample {
+                let pos = Position {
+                    offset,
+                    ordinal,
+                    output,
+                };
+                samples.push(Sample {
+                    key: rec.key,
+                    run,
+                    pos,
+                });
+                next_sample = offset + step;
+            }
+            ordinal += 1;
+            output += size;
+        }
+    }
+
+    /// Position of the first record at or after `splitter` (in merged order) in every run.
+    fn split_positions(
+        &self,
+        run_paths: &[String],
+        indexes: &[RunIndex],
+        splitter: &Sample,
+    ) -> io::Result<Vec<Position>> {
+        let mut cut = Vec::with_capacity(run_paths.len());
+        for (run, (path, index)) in run_paths.iter().zip(indexes).enumerate() {
+            // Last sample before the splitter; the position lies within one step of it
+            let before = index
+                .samples
+                .partition_point(|s| splitter.cmp_record(&s.key, run, s.pos.ordinal).is_gt());
+            let Some(from) = before.checked_sub(1).map(|i| index.samples[i].pos) else {
+                cut.push(index.start);
+                continue;
+            };
+            let mut rdr = self.open_run_range(path, index.layout, from, index.end)?;
+            let mut pos = from;
+            while let Some(rec) = rdr.next_rec()? {
+                if splitter.cmp_record(&rec.key, run, pos.ordinal).is_le() {
+                    break;
+                }
+                pos = Position {
+                    offset: from.offset + rdr.offset(),
+                    ordinal: pos.ordinal + 1,
+                    output: pos.output + self.format.encoded_size(&rec),
+                };
+            }
+            cut.push(pos);
+        }
+        Ok(cut)
+    }
+
+    /// Contents of the runs this merger takes: records or `tags`, keyed by the
+    /// format's key spec.
+    fn run_contents(&self, tags: bool) -> RunContents {
+        RunContents {
+            tags,
+            key_spec: self.format.key_spec().cloned(),
+        }
+    }
+
+    /// Open the run at `path`, whose header must declare `contents`: records
+    /// and tags, or records keyed by different specs, cannot stand in for each
+    /// other.
+    fn open_run(&self, path: &str, contents: &RunContents) -> io::Result<RunReader> {
+        let mut rdr = RunReader::new(open_reader_with(path, self.io)?);
+        let found = rdr.contents()?;
+        if found != contents {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("run {} holds {}, not {}", path, found, contents),
+            ));
+        }
+        Ok(rdr)
+    }
+
+    fn open_runs(
+        &self,
+        run_paths: &[String],
+        contents: &RunContents,
+    ) -> io::Result<Vec<RunReader>> {
+        run_paths
+            .iter()
+            .map(|p| self.open_run(p, contents))
+            .collect()
+    }
+
+    /// Reader for the records of a run in `layout` between `from` and `to`.
+    fn open_run_range(
+        &self,
+        path: &str,
+        layout: RunLayout,
+        from: Position,
+        to: Position,
+    ) -> io::Result<RunReader<io::Take<DirectReader>>> {
+        let mut rdr = open_reader_with(path, self.io)?;
+        rdr.seek_to(from.offset)?;
+        Ok(RunReader::new(rdr.take(to.offset - from.offset)).with_layout(layout))
+    }
+
+    /// Merge the records between `from` and `to` of every run into the output
+    /// region starting at `start`. Returns records merged and bytes written.
+    fn merge_partition(
+        &self,
+        run_paths: &[String],
+        indexes: &[RunIndex],
+        from: &[Position],
+        to: &[Position],
+        output_path: &str,
+        start: u64,
+    ) -> io::Result<(u64, u64)> {
+        let mut readers = run_paths
+            .iter()
+            .zip(indexes)
+            .zip(from.iter().zip(to))
+            .map(|((path, index), (&a, &b))| self.open_run_range(path, index.layout, a, b))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut writer = RegionWriter::open(output_path, start, self.io)?;
+        let merged = merge_readers(&mut readers, |rec| self.format.encode(&mut writer, rec))?;
+        Ok((merged, writer.finish()?))
+    }
+}
+
+/// K-way merge of `readers`, passing the records to `output` in order. Returns
+/// the number of records merged.
+fn merge_readers<R: Read>(
+    readers: &mut [RunReader<R>],
+    mut output: impl FnMut(&Rec) -> io::Result<()>,
+) -> io::Result<u64> {
+    // One tree leaf per run, primed with the run's first record
+    let mut heads = Vec::with_capacity(readers.len());
+    for (run, rdr) in readers.iter_mut().enumerate() {
+        heads.push(rdr.next_rec()?.map(|rec| MergeItem::new(rec, run)));
+    }
+    let mut tree = LoserTree::new(heads);
+
+    let mut merged: u64 = 0;
+    while let Some(top) = tree.peek() {
+        output(&top.rec)?;
+        merged += 1;
+
+        // Replace the winner with the next record from the same run
+        let run = top.run;
+        let next = readers[run].next_rec()?.map(|rec| MergeItem::new(rec, run));
+        tree.replace_top(next);
+    }
+    Ok(merged)
+}
+
+/// Apply `f` to every item (with its index) on up to `threads` scoped threads.
+/// Results are in item order.
+fn parallel_map<T: Sync, U: Send>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(usize, &T) -> U + Sync,
+) -> Vec<U> {
+    let threads = threads.min(items.len()).max(1);
+    let mut results: Vec<Option<U>> = std::iter::repeat_with(|| None).take(items.len()).collect();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let f = &f;
+                scope.spawn(move || {
+                    (t..items.len())
+                        .step_by(threads)
+                        .map(|i| (i, f(i, &items[i])))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for h in handles {
+            for (i, u) in h.join().expect("merge thread panicked") {
+                results[i] = Some(u);
+            }
+        }
+    });
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// K-way merge the sorted run files at `run_paths` into one sorted file at `output_path`.
+/// The output is length-prefixed, without a run header.
+/// Returns the number of records merged.
+pub fn merge_runs(run_paths: &[String], output_path: &str) -> io::Result<u64> {
+    Merger::new().merge(run_paths, output_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{open_direct_writer, write_len_key_len_payload};
+
+    fn write_run(path: &str, keys: &[u8]) {
+        let mut w = open_direct_writer(path).unwrap();
+        for &k in keys {
+            let mut key = [0u8; 10];
+            key[0] = k;
+            write_len_key_len_payload(&mut w, &Rec::new(key, [k; 90])).unwrap();
+        }
+        w.flush().unwrap();
+    }
+
+    fn read_keys(path: &str) -> Vec<u8> {
+        let mut keys = Vec::new();
+        for rec in RunReader::open(path).unwrap() {
+            let rec = rec.unwrap();
+            assert_eq!(
+                rec.payload, [rec.key[0]; 90],
+                "payload should follow its key"
+            );
+            keys.push(rec.key[0]);
+        }
+        keys
+    }
+
+    #[test]
+    fn test_merge_no_runs() {
+        let output = "test_merge_none_out.bin";
+        let merged = merge_runs(&[], output).unwrap();
+        assert_eq!(merged, 0);
+        assert!(read_keys(output).is_empty());
+        std::fs::remove_file(output).ok();
+    }
+
+    #[test]
+    fn test_merge_interleaved_runs() {
+        let runs = vec![
+            "test_merge_a_000.bin".to_string(),
+            "test_merge_a_001.bin".to_string(),
+            "test_merge_a_002.bin".to_string(),
+        ];
+        write_run(&runs[0], &[1, 4, 7, 10]);
+        write_run(&runs[1], &[2, 5, 8]);
+        write_run(&runs[2], &[3, 3, 6, 9]);
+
+        let output = "test_merge_a_out.bin";
+        let merged = merge_runs(&runs, output).unwrap();
+        assert_eq!(merged, 11);
+        assert_eq!(read_keys(output), vec![1, 2, 3, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+        for p in runs.iter().map(String::as_str).chain([output]) {
+            std::fs::remove_file(p).ok();
+        }
+    }
+
+    #[test]
+    fn test_merge_gensort_output() {
+        let runs = vec![
+            "test_merge_c_000.bin".to_string(),
+            "test_merge_c_001.bin".to_string(),
+        ];
+        write_run(&runs[0], &[2, 4]);
+        write_run(&runs[1], &[1, 3]);
+
+        let output = "test_merge_c_out.bin";
+        let merged = Merger::new()
+            .with_output_format(OutputFormat::Gensort)
+            .with_io_mode(IoMode::Buffered)
+            .merge(&runs, output)
+            .unwrap();
+        assert_eq!(merged, 4);
+
+        // Raw 100-byte records, no length prefixes
+        let data = std::fs::read(output).unwrap();
+        assert_eq!(data.len(), 4 * Rec::SIZE);
+        let keys: Vec<u8> = data.chunks(Rec::SIZE).map(|r| r[0]).collect();
+        assert_eq!(keys, vec![1, 2, 3, 4]);
+
+        for p in runs.iter().map(String::as_str).chain([output]) {
+            std::fs::remove_file(p).ok();
+        }
+    }
+
+    /// Runs of variable-length records with many duplicate keys, plus an empty run.
+    fn write_var_runs(prefix: &str) -> Vec<String> {
+        let mut rng = crate::generate::Rng::new(17);
+        let mut paths = Vec::new();
+        for r in 0..6 {
+            let mut recs: Vec<Rec> = (0..(r * 700))
+                .map(|_| {
+                    let key = vec![(rng.next_u64() % 40) as u8; 1 + (rng.next_u64() % 12) as usize];
+                    let payload = vec![r as u8; (rng.next_u64() % 300) as usize];
+                    Rec::new(key, payload)
+                })
+                .collect();
+            recs.sort_by(|a, b| a.key.cmp(&b.key));
+            let path = format!("{}_{:03}.bin", prefix, r);
+            let mut w = open_direct_writer(&path).unwrap();
+            for rec in &recs {
+                write_len_key_len_payload(&mut w, rec).unwrap();
+            }
+            w.flush().unwrap();
+            paths.push(path);
+        }
+        paths
+    }
+
+    #[test]
+    fn test_parallel_merge_matches_serial() {
+        let runs = write_var_runs("test_merge_par");
+        let serial = "test_merge_par_serial.bin";
+        let expected = merge_runs(&runs, serial).unwrap();
+        assert_eq!(expected, (0..6).map(|r| r * 700).sum::<u64>());
+
+        for threads in [2, 3, 8] {
+            for io_mode in [IoMode::Direct, IoMode::Buffered, IoMode::Mmap] {
+                let output = format!("test_merge_par_out_{}.bin", threads);
+                let merged = Merger::new()
+                    .with_threads(threads)
+                    .with_io_mode(io_mode)
+                    .merge(&runs, &output)
+                    .unwrap();
+                assert_eq!(merged, expected);
+                assert!(
+                    std::fs::read(&output).unwrap() == std::fs::read(serial).unwrap(),
+                    "{} threads ({:?}) should give the serial output",
+                    threads,
+                    io_mode
+                );
+                std::fs::remove_file(output).ok();
+            }
+        }
+
+        for p in runs.iter().map(String::as_str).chain([serial]) {
+            std::fs::remove_file(p).ok();
+        }
+    }
+
+    #[test]
+    fn test_parallel_merge_gensort_and_tiny_inputs() {
+        let runs = vec![
+            "test_merge_d_000.bin".to_string(),
+            "test_merge_d_001.bin".to_string(),
+            "test_merge_d_002.bin".to_string(),
+        ];
+        write_run(&runs[0], &[1, 4, 4, 4, 9]);
+        write_run(&runs[1], &[]);
+        write_run(&runs[2], &[0, 4, 8]);
+
+        let output = "test_merge_d_out.bin";
+        let merged = Merger::new()
+            .with_threads(16)
+            .with_output_format(OutputFormat::Gensort)
+            .merge(&runs, output)
+            .unwrap();
+        assert_eq!(merged, 8);
+        let data = std::fs::read(output).unwrap();
+        let keys: Vec<u8> = data.chunks(Rec::SIZE).map(|r| r[0]).collect();
+        assert_eq!(keys, vec![0, 1, 4, 4, 4, 4, 8, 9]);
+
+        // Only empty runs
+        let merged = Merger::new()
+            .with_threads(4)
+            .merge(&runs[1..2], output)
+            .unwrap();
+        assert_eq!(merged, 0);
+        assert_eq!(std::fs::metadata(output).unwrap().len(), 0);
+
+        for p in runs.iter().map(String::as_str).chain([output]) {
+            std::fs::remove_file(p).ok();
+        }
+    }
+
+    #[test]
+    fn test_merge_with_empty_run() {
+        let runs = vec![
+            "test_merge_b_000.bin".to_string(),
+            "test_merge_b_001.bin".to_string(),
+        ];
+        write_run(&runs[0], &[]);
+        write_run(&runs[1], &[5, 6]);
+
+        let output = "test_merge_b_out.bin";
+        let merged = merge_runs(&runs, output).unwrap();
+        assert_eq!(merged, 2);
+        assert_eq!(read_keys(output), vec![5, 6]);
+
+        for p in runs.iter().map(String::as_str).chain([output]) {
+            std::fs::remove_file(p).ok();
+        }
+    }
+}
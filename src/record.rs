@@ -1,37 +1,108 @@
-/// A gensort record: 10-byte key + 90-byte payload.
+/// A record with a variable-length key and payload.
+/// Gensort records are the fixed-size case: 10-byte key + 90-byte payload.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Rec {
-    pub key: [u8; 10],
-    pub payload: [u8; 90],
+    pub key: Vec<u8>,
+    pub payload: Vec<u8>,
 }
 
 impl Rec {
-    pub fn new(key: [u8; 10], payload: [u8; 90]) -> Self {
-        Self { key, payload }
+    pub fn new(key: impl Into<Vec<u8>>, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            key: key.into(),
+            payload: payload.into(),
+        }
+    }
+
+    /// Key + payload bytes (excluding any length prefixes).
+    pub fn size(&self) -> usize {
+        self.key.len() + self.payload.len()
     }
 
+    // Gensort layout
     pub const SIZE: usize = 100; // 10 + 90 bytes
     pub const KEY_SIZE: usize = 10;
     pub const PAYLOAD_SIZE: usize = 90;
+
+    // Upper bounds accepted from length-prefixed input (guards against corrupt lengths)
+    pub const MAX_KEY_SIZE: usize = 64 * 1024;
+    pub const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+}
+
+/// The first 16 bytes of `key` as a big-endian integer, zero-padded. Keys with
+/// different prefixes compare like their prefixes; equal prefixes need the full
+/// keys compared, since a key shorter than 16 bytes and the same key with zeros
+/// appended share a prefix.
+pub fn key_prefix(key: &[u8]) -> u128 {
+    let mut bytes = [0u8; 16];
+    let n = key.len().min(16);
+    bytes[..n].copy_from_slice(&key[..n]);
+    u128::from_be_bytes(bytes)
+}
+
+/// High bits of a `generation_prefix` holding the generation.
+const PREFIX_GEN_BITS: u32 = 32;
+
+/// `u128` that orders like `(g, key)` wherever the two differ in `g` or in the
+/// first 12 key bytes: the generation in the high 32 bits above those key bytes.
+/// From `2^32 - 1` on, the generation saturates and the key bits are left zero,
+/// so such values always tie on the key and need `g` and the full key compared.
+pub fn generation_prefix(g: u64, key: &[u8]) -> u128 {
+    let key_bits = 128 - PREFIX_GEN_BITS;
+    let max_gen = (1u64 << PREFIX_GEN_BITS) - 1;
+    if g < max_gen {
+        ((g as u128) << key_bits) | (key_prefix(key) >> PREFIX_GEN_BITS)
+    } else {
+        (max_gen as u128) << key_bits
+    }
 }
 
 /// Item in the heap, tagged with generation to implement freezing.
+///
+/// Ordered by `(g, key, seq)`. Most comparisons are settled by one compare of
+/// a precomputed `generation_prefix`, whose 12 key bytes hold all of a gensort
+/// key. Full keys are only compared when those are equal, so the fields are
+/// only readable after `Item::new`.
 #[derive(Clone, Debug)]
 pub struct Item {
-    pub rec: Rec,
-    pub g: u64,   // current run == current_gen, future runs have gen > current_gen
-    pub seq: u64, // tie-breaker for total order
+    rec: Rec,
+    g: u64,       // current run == current_gen, future runs have gen > current_gen
+    seq: u64,     // tie-breaker for total order
+    prefix: u128, // `generation_prefix(g, &rec.key)`
 }
 
 impl Item {
     pub fn new(rec: Rec, g: u64, seq: u64) -> Self {
-        Self { rec, g, seq }
+        let prefix = generation_prefix(g, &rec.key);
+        Self {
+            rec,
+            g,
+            seq,
+            prefix,
+        }
+    }
+
+    pub fn rec(&self) -> &Rec {
+        &self.rec
+    }
+
+    /// Generation the record is to be written in.
+    pub fn g(&self) -> u64 {
+        self.g
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn into_rec(self) -> Rec {
+        self.rec
     }
 }
 
 impl PartialEq for Item {
     fn eq(&self, other: &Self) -> bool {
-        self.g == other.g && self.seq == other.seq && self.rec.key == other.rec.key
+        self.cmp(other).is_eq()
     }
 }
 
@@ -48,13 +119,11 @@ impl Ord for Item {
         // Primary by generation, then by key, then by seq.
         // With Reverse in BinaryHeap, this becomes a min-heap by (gen, key, seq),
         // ensuring we fully drain the current generation before considering future ones.
-        match self.g.cmp(&other.g) {
-            std::cmp::Ordering::Equal => match self.rec.key.cmp(&other.rec.key) {
-                std::cmp::Ordering::Equal => self.seq.cmp(&other.seq),
-                o => o,
-            },
-            o => o,
-        }
+        self.prefix
+            .cmp(&other.prefix)
+            .then_with(|| self.g.cmp(&other.g))
+            .then_with(|| self.rec.key.cmp(&other.rec.key))
+            .then_with(|| self.seq.cmp(&other.seq))
     }
 }
 
@@ -71,6 +140,24 @@ mod tests {
         assert_eq!(rec.payload, payload);
     }
 
+    #[test]
+    fn test_rec_variable_length() {
+        let rec = Rec::new(vec![1u8; 4], vec![2u8; 3000]);
+        assert_eq!(rec.key.len(), 4);
+        assert_eq!(rec.payload.len(), 3000);
+        assert_eq!(rec.size(), 3004);
+    }
+
+    #[test]
+    fn test_item_ordering_variable_keys() {
+        // Lexicographic: a key sorts before any longer key it is a prefix of
+        let short = Item::new(Rec::new(vec![5u8, 1], vec![]), 0, 1);
+        let long = Item::new(Rec::new(vec![5u8, 1, 0], vec![]), 0, 0);
+        let bigger = Item::new(Rec::new(vec![5u8, 2], vec![]), 0, 0);
+        assert!(short < long);
+        assert!(long < bigger);
+    }
+
     #[test]
     fn test_item_ordering() {
         let rec1 = Rec::new([1u8; 10], [0u8; 90]);
@@ -95,6 +182,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_item_prefix_ties() {
+        // Keys longer than the prefix, and keys differing only in trailing zeros
+        let long_a = Item::new(Rec::new([[7u8; 12], [1u8; 12]].concat(), vec![]), 0, 1);
+        let long_b = Item::new(Rec::new([[7u8; 12], [2u8; 12]].concat(), vec![]), 0, 0);
+        assert!(long_a < long_b);
+        let short = Item::new(Rec::new(vec![7u8], vec![]), 0, 1);
+        let padded = Item::new(Rec::new(vec![7u8, 0], vec![]), 0, 0);
+        assert!(short < padded);
+        assert_eq!(key_prefix(&[7]), key_prefix(&[7, 0]));
+
+        // Generations past the prefix's range still order before keys
+        let max = (1u64 << 32) - 1;
+        let gens = [max - 1, max, max + 1];
+        let items: Vec<Item> = gens
+            .iter()
+            .map(|&g| Item::new(Rec::new(vec![(255 - g % 256) as u8], vec![]), g, 0))
+            .collect();
+        assert!(items.windows(2).all(|w| w[0] < w[1]));
+        let low_key = Item::new(Rec::new(vec![0u8], vec![]), max + 1, 0);
+        assert!(items[2] > low_key && items[1] < low_key);
+    }
+
     #[test]
     fn test_item_seq_ordering() {
         let rec = Rec::new([1u8; 10], [0u8; 90]);
@@ -1,109 +1,732 @@
+use crate::format::RecordFormat;
 use crate::io::{
-    open_direct_reader, open_run_writer, read_gensort_record, write_len_key_len_payload,
+    DirectWriter, InputFormat, IoBackend, IoMode, IoOptions, RecordReader, RecordWrite,
+    RunContents, RunLayout, open_reader_with, open_writer_with, run_file_name,
 };
-use crate::record::Item;
-use std::cmp::Reverse;
-use std::collections::BinaryHeap;
-use std::io::{self, Read};
+use crate::manifest::{manifest_path, write_manifest};
+use crate::memory::MemoryBudget;
+use crate::merge::Merger;
+use crate::record::Rec;
+use crate::run_dir::RunDir;
+use crate::run_set::{RunInfo, RunSet};
+use crate::tag_sort::{Gather, TAG_PAYLOAD_SIZE, tag};
+use crate::workspace::Workspace;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, mpsc};
 
-pub struct ReplacementSelection {
+/// Record bytes (as charged by `MemoryBudget`) the reader hands to a worker at once.
+const BATCH_BYTES: usize = 64 * 1024;
+/// Batches queued per worker while it is busy.
+const BATCH_QUEUE: usize = 2;
+
+/// Bytes of the record batches handed to workers and not yet drained. Each batch
+/// is charged at least `BATCH_BYTES`, so one holding a record larger than that
+/// waits until it fits in the bytes set aside for batches.
+struct InFlight {
+    limit: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+impl InFlight {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Charge a batch of `bytes`, waiting until it fits or nothing else is in
+    /// flight.
+    fn acquire(&self, bytes: usize) -> Charge<'_> {
+        let bytes = bytes.max(BATCH_BYTES);
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && *used + bytes > self.limit {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += bytes;
+        Charge {
+            in_flight: self,
+            bytes,
+        }
+    }
+}
+
+/// A batch's share of `InFlight`, given back when the batch is dropped.
+struct Charge<'a> {
+    in_flight: &'a InFlight,
+    bytes: usize,
+}
+
+impl Drop for Charge<'_> {
+    fn drop(&mut self) {
+        *self.in_flight.used.lock().unwrap() -= self.bytes;
+        self.in_flight.released.notify_all();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplacementSelection<F = InputFormat> {
     heap_cap: usize,
+    mem_budget: Option<MemoryBudget>,
     out_prefix: String,
+    format: F, // layout of the input records
+    io: IoOptions,
+    temp_dir: PathBuf,
+    keep_runs: bool,
+    compact_runs: bool,
+    tag_sort: bool,
+    threads: usize,
 }
 
 impl ReplacementSelection {
+    /// Hold up to `heap_cap` records in the workspace.
     pub fn new(heap_cap: usize, out_prefix: String) -> Self {
         Self {
             heap_cap,
+            mem_budget: None,
             out_prefix,
+            format: InputFormat::default(),
+            io: IoOptions::default(),
+            temp_dir: PathBuf::from("."),
+            keep_runs: false,
+            compact_runs: true,
+            tag_sort: false,
+            threads: 1,
+        }
+    }
+
+    /// Hold as many records as fit in `mem_budget`, counting the workspace slots,
+    /// each record's buffers and the I/O buffers.
+    pub fn with_memory_budget(mem_budget: MemoryBudget, out_prefix: String) -> Self {
+        Self {
+            mem_budget: Some(mem_budget),
+            ..Self::new(usize::MAX, out_prefix)
+        }
+    }
+
+    /// Set the layout of input records (gensort by default).
+    pub fn with_input_format(mut self, input_format: InputFormat) -> Self {
+        self.format = input_format;
+        self
+    }
+}
+
+impl<F: RecordFormat> ReplacementSelection<F> {
+    /// Decode the input with `format`.
+    pub fn with_format<G: RecordFormat>(self, format: G) -> ReplacementSelection<G> {
+        ReplacementSelection {
+            heap_cap: self.heap_cap,
+            mem_budget: self.mem_budget,
+            out_prefix: self.out_prefix,
+            format,
+            io: self.io,
+            temp_dir: self.temp_dir,
+            keep_runs: self.keep_runs,
+            compact_runs: self.compact_runs,
+            tag_sort: self.tag_sort,
+            threads: self.threads,
+        }
+    }
+
+    /// Set how the input is read and runs are written (Direct I/O by default).
+    pub fn with_io_mode(mut self, io_mode: IoMode) -> Self {
+        self.io.mode = io_mode;
+        self
+    }
+
+    /// Set how reads and writes are issued (blocking calls by default). The memory
+    /// budget charges the buffers of the chosen backend.
+    pub fn with_io_backend(mut self, io_backend: IoBackend) -> Self {
+        self.io.backend = io_backend;
+        self
+    }
+
+    /// Set the I/O mode, backend, buffer size, alignment and durability at once.
+    pub fn with_io_options(mut self, io: IoOptions) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// Directory for the intermediate runs of `sort_from_file` (default: current directory).
+    /// Each sort creates a uniquely named subdirectory there.
+    pub fn with_temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    /// Keep run files on disk instead of removing them after the merge or on error.
+    pub fn with_keep_runs(mut self, keep_runs: bool) -> Self {
+        self.keep_runs = keep_runs;
+        self
+    }
+
+    /// Write runs of fixed-size records, such as gensort input, as raw records
+    /// after a header declaring their size (the default). With `false`, or for
+    /// formats whose records vary in size, runs hold length-prefixed records.
+    pub fn with_compact_runs(mut self, compact_runs: bool) -> Self {
+        self.compact_runs = compact_runs;
+        self
+    }
+
+    /// Tag sort (off by default): runs hold each record's key with only its offset
+    /// and length in the input (see `tag_sort`), so the workspace fits many more
+    /// keys of wide records. `sort_from_file` then merges the tags and gathers the
+    /// records from the input file in merged order, one positioned read each, in a
+    /// single-threaded merge. Sorting a stream with `sort` is not possible, as the
+    /// records could not be read again.
+    pub fn with_tag_sort(mut self, tag_sort: bool) -> Self {
+        self.tag_sort = tag_sort;
+        self
+    }
+
+    /// Layout of the records in the runs written.
+    pub fn run_layout(&self) -> RunLayout {
+        match self.format.fixed_size() {
+            Some((key_size, _)) if self.compact_runs && self.tag_sort => RunLayout::Fixed {
+                key_size,
+                payload_size: TAG_PAYLOAD_SIZE,
+            },
+            Some((key_size, payload_size)) if self.compact_runs => RunLayout::Fixed {
+                key_size,
+                payload_size,
+            },
+            _ => RunLayout::LenPrefixed,
         }
     }
 
+    /// What the records in the runs written are.
+    pub fn run_contents(&self) -> RunContents {
+        RunContents {
+            tags: self.tag_sort,
+            key_spec: self.format.key_spec().cloned(),
+        }
+    }
+
+    /// Generate runs with `threads` workers (default 1). The input is dealt out to
+    /// the workers in batches, round robin, and each worker runs replacement
+    /// selection in its own workspace into its own runs (`<prefix>_t<w>_N.bin`).
+    /// The workspace size or memory budget is shared between the workers, and the
+    /// runs are the same for the same input and thread count.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
     /// Run the replacement selection algorithm on the input.
-    /// Returns the number of runs created.
-    pub fn run<R: Read>(&self, mut rdr: R) -> io::Result<usize> {
-        let mut heap: BinaryHeap<Reverse<Item>> = BinaryHeap::new();
+    /// Returns the runs created, in order, and commits their manifest at
+    /// `manifest_path()` once they are synced. On error, the runs written so far
+    /// are removed unless `with_keep_runs(true)` was set. Tag sorts need
+    /// `run_from_file`.
+    pub fn run<R: Read>(&self, rdr: R) -> io::Result<RunSet> {
+        if self.tag_sort {
+            return Err(tag_sort_needs_file());
+        }
+        self.run_records(RecordReader::new(BufReader::new(rdr), self.format.clone()))
+    }
+
+    fn run_records<R: BufRead>(&self, mut rdr: RecordReader<R, F>) -> io::Result<RunSet> {
+        // A manifest left by an earlier job with this prefix would describe the
+        // runs about to be overwritten
+        let manifest = self.manifest_path();
+        match std::fs::remove_file(&manifest) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        let mut opened = Vec::new();
+        let res = if self.threads > 1 {
+            self.generate_runs_parallel(&mut rdr, &mut opened)
+        } else {
+            self.generate_runs(|| self.next_input(&mut rdr), &mut opened)
+        };
+        // The manifest may only list runs that are on disk. Its directory, which
+        // holds the runs too, is synced when it is committed.
+        let res = res.and_then(|runs| {
+            if !self.io.durable {
+                for run in &runs {
+                    File::open(&run.path)?.sync_data()?;
+                }
+            }
+            write_manifest(&manifest, &runs).map(|()| runs)
+        });
+        if res.is_err() && !self.keep_runs {
+            for path in opened {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        res
+    }
+
+    /// Next input record, or its tag in a tag sort.
+    fn next_input<R: BufRead>(&self, rdr: &mut RecordReader<R, F>) -> io::Result<Option<Rec>> {
+        let offset = rdr.offset();
+        let rec = rdr.next_rec()?;
+        Ok(match rec {
+            Some(rec) if self.tag_sort => Some(tag(rec, offset, rdr.offset() - offset)),
+            rec => rec,
+        })
+    }
+
+    /// Deal the input out to `threads` workers and concatenate their runs in
+    /// worker order.
+    fn generate_runs_parallel<R: BufRead>(
+        &self,
+        rdr: &mut RecordReader<R, F>,
+        opened: &mut Vec<String>,
+    ) -> io::Result<RunSet> {
+        let threads = self.threads;
+        // Record batches in flight: each worker's full queue and the batch it is
+        // draining, plus the batch the reader is filling.
+        let in_flight = threads * (BATCH_QUEUE + 2) * BATCH_BYTES;
+        let mem_budget = self.mem_budget.map(|b| b.split(threads, in_flight));
+        if let Some(share) = mem_budget
+            && share.workspace_bytes_for(&self.io) == 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "memory budget of {} bytes is too small for {} threads: \
+                     {} bytes go to queued batches and each worker needs {} for I/O buffers",
+                    self.mem_budget.map_or(0, |b| b.bytes()),
+                    threads,
+                    in_flight,
+                    MemoryBudget::io_bytes_for(&self.io)
+                ),
+            ));
+        }
+        let workers: Vec<Self> = (0..threads)
+            .map(|w| Self {
+                heap_cap: if self.heap_cap == usize::MAX {
+                    usize::MAX
+                } else {
+                    (self.heap_cap / threads).max(1)
+                },
+                mem_budget,
+                out_prefix: format!("{}_t{:03}", self.out_prefix, w),
+                threads: 1,
+                ..self.clone()
+            })
+            .collect();
+
+        // All of it but the batch the reader is filling
+        let charged = InFlight::new(in_flight - BATCH_BYTES);
+
+        let (results, read_res) = std::thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(threads);
+            let mut handles = Vec::with_capacity(threads);
+            for worker in &workers {
+                let (tx, rx) = mpsc::sync_channel::<(Vec<Rec>, Charge)>(BATCH_QUEUE);
+                senders.push(tx);
+                handles.push(scope.spawn(move || {
+                    let mut opened = Vec::new();
+                    let mut batch = Vec::new().into_iter();
+                    let mut charge = None;
+                    let res = worker.generate_runs(
+                        || loop {
+                            if let Some(rec) = batch.next() {
+                                return Ok(Some(rec));
+                            }
+                            drop(charge.take()); // drained
+                            match rx.recv() {
+                                Ok((next, next_charge)) => {
+                                    batch = next.into_iter();
+                                    charge = Some(next_charge);
+                                }
+                                Err(_) => return Ok(None), // reader is done
+                            }
+                        },
+                        &mut opened,
+                    );
+                    (res, opened)
+                }));
+            }
+
+            // Batch `i` goes to worker `i % threads`. A worker that failed drops its
+            // receiver, which stops the reader; its error is reported below.
+            let read_res = (|| {
+                let mut batch = Vec::new();
+                let mut batch_bytes = 0;
+                let mut batches = 0;
+                let mut send = |batch: Vec<Rec>, bytes: usize| {
+                    let charge = charged.acquire(bytes);
+                    let sent = senders[batches % threads].send((batch, charge)).is_ok();
+                    batches += 1;
+                    sent
+                };
+                while let Some(rec) = self.next_input(rdr)? {
+                    let size = MemoryBudget::record_bytes(&rec) + size_of::<Rec>();
+                    if !batch.is_empty() && batch_bytes + size > BATCH_BYTES {
+                        if !send(std::mem::take(&mut batch), batch_bytes) {
+                            return Ok(());
+                        }
+                        batch_bytes = 0;
+                    }
+                    batch_bytes += size;
+                    batch.push(rec);
+                }
+                if !batch.is_empty() {
+                    send(batch, batch_bytes);
+                }
+                Ok(())
+            })();
+            drop(senders);
+            let results: Vec<_> = handles
+                .into_iter()
+                .map(|h| h.join().expect("run generation worker panicked"))
+                .collect();
+            (results, read_res)
+        });
+
+        let mut runs = RunSet::default();
+        let mut first_err = read_res.err();
+        for (res, worker_opened) in results {
+            opened.extend(worker_opened);
+            match res {
+                Ok(worker_runs) => runs.runs.extend(worker_runs.runs),
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(runs),
+        }
+    }
+
+    /// Replacement selection over the records returned by `next_rec`, appending
+    /// the path of every run file it creates to `opened`.
+    fn generate_runs(
+        &self,
+        mut next_rec: impl FnMut() -> io::Result<Option<Rec>>,
+        opened: &mut Vec<String>,
+    ) -> io::Result<RunSet> {
         let mut seq: u64 = 0;
         let mut current_gen: u64 = 0;
         let mut run_idx: usize = 0;
-        let mut last_out_key: Option<[u8; 10]> = None;
 
-        // Prime heap with up to heap_cap records
-        while heap.len() < self.heap_cap {
-            match read_gensort_record(&mut rdr)? {
-                Some(rec) => {
-                    heap.push(Reverse(Item::new(rec, 0, seq)));
-                    seq += 1;
+        // Bytes available for tree slots + record buffers (unbounded in record-count mode).
+        // Admission keeps room for one in-flight record of the admitted record's size:
+        // the next input record is read while the winner is still held.
+        let workspace_limit = self
+            .mem_budget
+            .map_or(usize::MAX, |b| b.workspace_bytes_for(&self.io));
+        let mut rec_bytes: usize = 0; // record buffers held by the tree
+        // A record read from the input that did not fit yet
+        let mut pending = None;
+
+        // Prime the workspace with up to heap_cap records (or until the budget is full),
+        // one slot each. Slot capacity is charged as allocated, so Vec growth counts.
+        let mut initial: Vec<Rec> = Vec::new();
+        while initial.len() < self.heap_cap {
+            let Some(rec) = next_rec()? else { break };
+            let size = MemoryBudget::record_bytes(&rec);
+            if initial.len() == initial.capacity() {
+                // Grow to at most as many slots as fit if later records are this size
+                let slots = MemoryBudget::SLOT_BYTES;
+                let room = workspace_limit.saturating_sub(rec_bytes + size + initial.len() * slots);
+                let fit = initial.len() + room / (slots + size);
+                let new_cap = (initial.capacity() * 2).max(64).min(fit).min(self.heap_cap);
+                if new_cap <= initial.len() && !initial.is_empty() {
+                    pending = Some(rec);
+                    break;
                 }
-                None => break,
+                initial.reserve_exact(new_cap.max(1) - initial.len());
+            } else if initial.capacity() * MemoryBudget::SLOT_BYTES + rec_bytes + 2 * size
+                > workspace_limit
+            {
+                pending = Some(rec);
+                break;
             }
+            rec_bytes += size;
+            initial.push(rec);
+            seq += 1;
         }
 
-        if heap.is_empty() {
-            return Ok(0);
+        if initial.is_empty() {
+            return Ok(RunSet::default());
         }
+        initial.shrink_to_fit();
+        let record_limit = workspace_limit.saturating_sub(initial.len() * MemoryBudget::SLOT_BYTES);
+        let mut workspace = Workspace::new(initial);
 
         // Open first run writer
-        let mut writer = open_run_writer(&self.out_prefix, run_idx)?;
-        let mut records_in_current_run = 0;
+        let mut runs = RunSet::default();
+        let mut out = self.open_run(run_idx, current_gen, opened)?;
+        let mut last_key = Vec::new(); // key of the record written last
 
         // Main loop
-        loop {
-            if heap.is_empty() {
-                break;
-            }
-
+        while let Some((top, top_gen)) = workspace.peek() {
             // If the smallest item is not from current_gen, current run is done.
-            if heap.peek().map(|x| x.0.g).unwrap() != current_gen {
+            if top_gen != current_gen {
                 // Only rotate if we actually wrote something to current run
-                if records_in_current_run > 0 {
-                    writer.flush()?;
+                if out.info.records > 0 {
+                    runs.runs.push(out.finish(std::mem::take(&mut last_key))?);
                     run_idx += 1;
                     current_gen += 1;
-                    last_out_key = None;
-                    writer = open_run_writer(&self.out_prefix, run_idx)?;
-                    records_in_current_run = 0;
+                    out = self.open_run(run_idx, current_gen, opened)?;
                 } else {
                     // This shouldn't happen in normal operation, but handle it defensively
                     current_gen += 1;
+                    out.info.generation = current_gen;
                 }
                 continue;
             }
 
-            // Pop next output record
-            let Reverse(item) = heap.pop().unwrap();
-            write_len_key_len_payload(&mut writer, &item.rec)?;
-            records_in_current_run += 1;
+            // Output the winner, copying it from its slot to the run writer
+            out.write(top)?;
+            let top_bytes = MemoryBudget::record_bytes(top);
 
-            last_out_key = Some(item.rec.key);
+            // Refill: take the pending record or read one more, decide its generation
+            // and let it take the winner's slot. If it does not fit the budget yet, the
+            // slot is emptied and the record waits until enough memory is released
+            // (the last live slot always accepts it). On EOF the workspace drains; run
+            // rotation happens naturally when only future-gen items remain.
+            let next = match pending.take() {
+                Some(rec) => Some(rec),
+                None => next_rec()?,
+            };
+            let old = match next {
+                Some(next_rec)
+                    if rec_bytes - top_bytes + 2 * MemoryBudget::record_bytes(&next_rec)
+                        <= record_limit
+                        || workspace.len() == 1 =>
+                {
+                    let target_gen = if next_rec.key < top.key {
+                        current_gen + 1 // freeze to future run
+                    } else {
+                        current_gen
+                    };
+                    rec_bytes += MemoryBudget::record_bytes(&next_rec);
+                    seq += 1;
+                    workspace.replace_top(next_rec, target_gen, seq - 1)
+                }
+                next_rec => {
+                    pending = next_rec;
+                    workspace.pop().expect("workspace has a winner")
+                }
+            };
+            rec_bytes -= top_bytes;
+            last_key = old.key;
 
-            // Refill: try to read one more input record and decide its generation
-            if let Some(next_rec) = read_gensort_record(&mut rdr)? {
-                let target_gen = match last_out_key {
-                    Some(last) if next_rec.key < last => current_gen + 1, // freeze to future run
-                    _ => current_gen,
+            // Refill slots emptied above once records fit again, so that a large
+            // record only shrinks the workspace while it is held
+            while workspace.len() < workspace.capacity() {
+                let Some(rec) = (match pending.take() {
+                    Some(rec) => Some(rec),
+                    None => next_rec()?,
+                }) else {
+                    break;
                 };
-                heap.push(Reverse(Item::new(next_rec, target_gen, seq)));
+                let size = MemoryBudget::record_bytes(&rec);
+                if rec_bytes + 2 * size > record_limit {
+                    pending = Some(rec);
+                    break;
+                }
+                let target_gen = if rec.key < last_key {
+                    current_gen + 1
+                } else {
+                    current_gen
+                };
+                rec_bytes += size;
                 seq += 1;
+                workspace.push(rec, target_gen, seq - 1);
             }
-            // else: EOF; keep draining heap; run rotation will happen naturally when
-            // only future-gen items remain.
         }
 
-        writer.flush()?;
-        Ok(run_idx + 1)
+        runs.runs.push(out.finish(last_key)?);
+        Ok(runs)
+    }
+
+    fn open_run(
+        &self,
+        idx: usize,
+        generation: u64,
+        opened: &mut Vec<String>,
+    ) -> io::Result<RunOutput> {
+        let path = run_file_name(&self.out_prefix, idx);
+        opened.push(path.clone());
+        let layout = self.run_layout();
+        let mut writer = open_writer_with(&path, self.io)?;
+        layout.write_header(&mut writer, &self.run_contents())?;
+        Ok(RunOutput {
+            writer,
+            layout,
+            info: RunInfo {
+                path,
+                records: 0,
+                bytes: 0,
+                min_key: Vec::new(),
+                max_key: Vec::new(),
+                generation,
+                checksum: 0,
+            },
+        })
     }
 
     /// Run replacement selection from a file path
-    pub fn run_from_file(&self, input_path: &str) -> io::Result<usize> {
-        let rdr = open_direct_reader(input_path)?;
-        self.run(rdr)
+    pub fn run_from_file(&self, input_path: &str) -> io::Result<RunSet> {
+        let rdr = open_reader_with(input_path, self.io)?;
+        self.run_records(RecordReader::new(rdr, self.format.clone()))
+    }
+
+    /// Paths of the first `num_runs` run files written by this instance with one thread.
+    pub fn run_paths(&self, num_runs: usize) -> Vec<String> {
+        (0..num_runs)
+            .map(|idx| run_file_name(&self.out_prefix, idx))
+            .collect()
+    }
+
+    /// Path of the manifest written next to the runs, `<prefix>.manifest`.
+    pub fn manifest_path(&self) -> String {
+        manifest_path(&self.out_prefix)
+    }
+
+    /// Generate runs from `input_path` and merge them into `output_path`.
+    /// Returns the number of records merged.
+    pub fn sort_from_file(&self, input_path: &str, output_path: &str) -> io::Result<u64> {
+        let merger = Merger::new().with_io_options(self.io);
+        self.sort_from_file_with(input_path, output_path, &merger)
+    }
+
+    /// Like `sort_from_file`, merging with `merger`. Runs are written to a fresh
+    /// directory under the temp dir, named after the file name of the run prefix,
+    /// and the directory is removed afterwards (also on error) unless runs are kept.
+    pub fn sort_from_file_with<G: RecordFormat>(
+        &self,
+        input_path: &str,
+        output_path: &str,
+        merger: &Merger<G>,
+    ) -> io::Result<u64> {
+        self.in_run_dir(|rs| {
+            let runs = rs.run_from_file(input_path)?;
+            if self.tag_sort {
+                let mut gather = Gather::open(input_path, self.format.clone())?;
+                merger.merge_tags(&runs.paths(), &mut gather, output_path)
+            } else {
+                merger.merge(&runs.paths(), output_path)
+            }
+        })
+    }
+
+    /// Like `sort_from_file_with`, merging into `writer`, which is not flushed.
+    /// The merge is single-threaded.
+    pub fn sort_from_file_into<W: RecordWrite, G: RecordFormat>(
+        &self,
+        input_path: &str,
+        writer: &mut W,
+        merger: &Merger<G>,
+    ) -> io::Result<u64> {
+        self.in_run_dir(|rs| {
+            let runs = rs.run_from_file(input_path)?;
+            if self.tag_sort {
+                let mut gather = Gather::open(input_path, self.format.clone())?;
+                merger.merge_tags_into(&runs.paths(), &mut gather, writer)
+            } else {
+                merger.merge_into(&runs.paths(), writer)
+            }
+        })
+    }
+
+    /// Sort the records of `rdr` into `writer`, for streams such as stdin and
+    /// stdout. Runs go to a fresh directory under the temp dir as with
+    /// `sort_from_file_with`; the merge into `writer` is single-threaded, and
+    /// `writer` is not flushed. Returns the number of records merged. A tag sort
+    /// needs its input file and is an `InvalidInput` error here.
+    pub fn sort<R: Read, W: RecordWrite, G: RecordFormat>(
+        &self,
+        rdr: R,
+        writer: &mut W,
+        merger: &Merger<G>,
+    ) -> io::Result<u64> {
+        if self.tag_sort {
+            return Err(tag_sort_needs_file());
+        }
+        self.in_run_dir(|rs| {
+            let runs = rs.run(rdr)?;
+            merger.merge_into(&runs.paths(), writer)
+        })
+    }
+
+    /// Sort the records of `rdr`, e.g. stdin, into the file at `output_path`.
+    /// Unlike with `sort`, the merge may use all of the merger's threads. A tag
+    /// sort is an `InvalidInput` error as with `sort`.
+    pub fn sort_to_file<R: Read, G: RecordFormat>(
+        &self,
+        rdr: R,
+        output_path: &str,
+        merger: &Merger<G>,
+    ) -> io::Result<u64> {
+        if self.tag_sort {
+            return Err(tag_sort_needs_file());
+        }
+        self.in_run_dir(|rs| {
+            let runs = rs.run(rdr)?;
+            merger.merge(&runs.paths(), output_path)
+        })
+    }
+
+    /// Call `f` with a copy of `self` whose runs go to a fresh directory under the
+    /// temp dir, named after the file name of the run prefix. The directory is
+    /// removed afterwards (also on error) unless runs are kept.
+    fn in_run_dir<T>(&self, f: impl FnOnce(&Self) -> io::Result<T>) -> io::Result<T> {
+        let mut run_dir = RunDir::create_in(&self.temp_dir)?;
+        if self.keep_runs {
+            run_dir.keep();
+        }
+        let name = Path::new(&self.out_prefix)
+            .file_name()
+            .map_or("run".into(), |n| n.to_string_lossy());
+        let rs = Self {
+            out_prefix: run_dir.prefix(&name),
+            ..self.clone()
+        };
+        f(&rs)
+    }
+}
+
+fn tag_sort_needs_file() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "tag sort needs an input file to gather records from",
+    )
+}
+
+/// Run file being written, with the metadata collected so far.
+struct RunOutput {
+    writer: DirectWriter,
+    layout: RunLayout,
+    info: RunInfo,
+}
+
+impl RunOutput {
+    fn write(&mut self, rec: &Rec) -> io::Result<()> {
+        if self.info.records == 0 {
+            self.info.min_key = rec.key.clone();
+        }
+        self.layout.write_record(&mut self.writer, rec)?;
+        self.info.records += 1;
+        Ok(())
+    }
+
+    /// Finish the run file; `max_key` is the key of the last record written.
+    fn finish(mut self, max_key: Vec<u8>) -> io::Result<RunInfo> {
+        let finished = self.writer.finish()?;
+        self.info.bytes = finished.bytes;
+        self.info.checksum = finished.checksum;
+        self.info.max_key = max_key;
+        Ok(self.info)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::io::RUN_HEADER_SIZE;
     use crate::record::Rec;
     use std::io::Cursor;
 
@@ -116,9 +739,10 @@ mod tests {
     #[test]
     fn test_empty_input() {
         let input = Cursor::new(vec![]);
-        let rs = ReplacementSelection::new(10, "test_run".to_string());
-        let num_runs = rs.run(input).unwrap();
+        let rs = ReplacementSelection::new(10, "test_empty".to_string());
+        let num_runs = rs.run(input).unwrap().len();
         assert_eq!(num_runs, 0);
+        std::fs::remove_file(rs.manifest_path()).ok();
     }
 
     #[test]
@@ -129,12 +753,13 @@ mod tests {
         input[10..100].copy_from_slice(&rec.payload);
 
         let cursor = Cursor::new(input);
-        let rs = ReplacementSelection::new(10, "test_run".to_string());
-        let num_runs = rs.run(cursor).unwrap();
+        let rs = ReplacementSelection::new(10, "test_single".to_string());
+        let num_runs = rs.run(cursor).unwrap().len();
         assert_eq!(num_runs, 1);
 
         // Clean up
-        std::fs::remove_file("test_run_000.bin").ok();
+        std::fs::remove_file("test_single_00000000.bin").ok();
+        std::fs::remove_file(rs.manifest_path()).ok();
     }
 
     #[test]
@@ -149,13 +774,14 @@ mod tests {
 
         let cursor = Cursor::new(input);
         let rs = ReplacementSelection::new(3, "test_sorted".to_string());
-        let num_runs = rs.run(cursor).unwrap();
+        let num_runs = rs.run(cursor).unwrap().len();
 
         // All sorted input should produce single run
         assert_eq!(num_runs, 1);
 
         // Clean up
-        std::fs::remove_file("test_sorted_000.bin").ok();
+        std::fs::remove_file("test_sorted_00000000.bin").ok();
+        std::fs::remove_file(rs.manifest_path()).ok();
     }
 
     #[test]
@@ -170,7 +796,7 @@ mod tests {
 
         let cursor = Cursor::new(input);
         let rs = ReplacementSelection::new(3, "test_reverse".to_string());
-        let num_runs = rs.run(cursor).unwrap();
+        let num_runs = rs.run(cursor).unwrap().len();
 
         // Reverse sorted should produce multiple runs
         // With heap_cap=3, worst case for reverse sorted is more runs
@@ -178,8 +804,355 @@ mod tests {
 
         // Clean up
         for i in 0..num_runs {
-            std::fs::remove_file(format!("test_reverse_{:03}.bin", i)).ok();
+            std::fs::remove_file(format!("test_reverse_{:08}.bin", i)).ok();
+        }
+        std::fs::remove_file(rs.manifest_path()).ok();
+    }
+
+    fn create_len_prefixed_input(recs: &[Rec]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for rec in recs {
+            input.extend_from_slice(&(rec.key.len() as u32).to_le_bytes());
+            input.extend_from_slice(&rec.key);
+            input.extend_from_slice(&(rec.payload.len() as u32).to_le_bytes());
+            input.extend_from_slice(&rec.payload);
+        }
+        input
+    }
+
+    fn read_runs(runs: &RunSet) -> Vec<Vec<Rec>> {
+        runs.iter()
+            .map(|run| {
+                let path = &run.path;
+                let recs = crate::io::RunReader::open(path)
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                std::fs::remove_file(path).ok();
+                recs
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_memory_budget_matches_record_capacity() {
+        // Reverse sorted input: every run holds exactly the workspace capacity
+        let mut input = Vec::new();
+        for i in (1..=12).rev() {
+            let rec = create_test_record(i);
+            input.extend_from_slice(&rec.key);
+            input.extend_from_slice(&rec.payload);
+        }
+
+        // Budget for 3 gensort records plus one in-flight record
+        let per_rec = MemoryBudget::record_bytes(&create_test_record(0));
+        let budget = MemoryBudget::new(
+            MemoryBudget::io_bytes() + 3 * (MemoryBudget::SLOT_BYTES + per_rec) + per_rec,
+        );
+        let rs = ReplacementSelection::with_memory_budget(budget, "test_budget".to_string());
+        let runs = read_runs(&rs.run(Cursor::new(input)).unwrap());
+
+        assert_eq!(runs.len(), 4);
+        assert!(runs.iter().all(|r| r.len() == 3));
+        std::fs::remove_file(rs.manifest_path()).ok();
+    }
+
+    #[test]
+    fn test_memory_budget_variable_length() {
+        // Small records with a few large ones that force leaves to be given up
+        let recs: Vec<Rec> = (0..60u32)
+            .map(|i| {
+                let key = vec![((i * 41) % 97) as u8; 4 + (i as usize % 8)];
+                let payload_len = if i % 10 == 0 { 3000 } else { 50 };
+                Rec::new(key, vec![i as u8; payload_len])
+            })
+            .collect();
+        let budget = MemoryBudget::new(MemoryBudget::io_bytes() + 8 * 1024);
+        let rs = ReplacementSelection::with_memory_budget(budget, "test_budget_var".to_string())
+            .with_input_format(InputFormat::LenPrefixed);
+        let run_set = rs
+            .run(Cursor::new(create_len_prefixed_input(&recs)))
+            .unwrap();
+        let runs = read_runs(&run_set);
+        std::fs::remove_file(rs.manifest_path()).ok();
+
+        // Every record is written once and each run is sorted
+        let mut output: Vec<Rec> = runs.iter().flatten().cloned().collect();
+        for run in &runs {
+            assert!(run.windows(2).all(|w| w[0].key <= w[1].key));
+        }
+        let mut expected = recs.clone();
+        expected.sort_by(|a, b| (&a.key, &a.payload).cmp(&(&b.key, &b.payload)));
+        output.sort_by(|a, b| (&a.key, &a.payload).cmp(&(&b.key, &b.payload)));
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_run_lengths_recover_after_large_record() {
+        // Reverse sorted input: every run holds the records in the workspace, so
+        // run lengths show its size before and after one oversized record
+        let small = |i: u64| Rec::new(i.to_be_bytes(), [0u8; 20]);
+        let mut recs: Vec<Rec> = (0..3000u64).rev().map(small).collect();
+        let per_rec = MemoryBudget::record_bytes(&recs[0]);
+        let workspace = 100 * (MemoryBudget::SLOT_BYTES + per_rec) + per_rec;
+        recs[500].payload = vec![0u8; workspace / 3];
+        let budget = MemoryBudget::new(MemoryBudget::io_bytes() + workspace);
+        let rs =
+            ReplacementSelection::with_memory_budget(budget, "test_budget_recover".to_string())
+                .with_input_format(InputFormat::LenPrefixed);
+        let run_set = rs
+            .run(Cursor::new(create_len_prefixed_input(&recs)))
+            .unwrap();
+        let runs = read_runs(&run_set);
+        std::fs::remove_file(rs.manifest_path()).ok();
+
+        assert_eq!(runs.iter().map(Vec::len).sum::<usize>(), recs.len());
+        assert_eq!(runs[0].len(), 100);
+        // A few short runs while the large record is held, then full ones again
+        assert!(runs.len() <= 3000 / 100 + 3, "{} runs", runs.len());
+        assert!(
+            runs[runs.len() - 5..runs.len() - 1]
+                .iter()
+                .all(|r| r.len() == 100)
+        );
+    }
+
+    #[test]
+    fn test_run_metadata() {
+        // 3, 1, 2 | 0: with heap_cap=2 the runs are [1, 2, 3] and [0]
+        let mut input = Vec::new();
+        for i in [3u8, 1, 2, 0] {
+            let rec = create_test_record(i);
+            input.extend_from_slice(&rec.key);
+            input.extend_from_slice(&rec.payload);
+        }
+
+        let rs = ReplacementSelection::new(2, "test_meta".to_string());
+        let runs = rs.run(Cursor::new(input)).unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs.total_records(), 4);
+        // Compact runs: a header, then raw 100-byte records
+        assert_eq!(runs.total_bytes(), 2 * RUN_HEADER_SIZE + 4 * 100);
+        assert_eq!(runs.paths(), rs.run_paths(2));
+
+        let first = &runs.runs[0];
+        assert_eq!(first.records, 3);
+        assert_eq!(first.bytes, RUN_HEADER_SIZE + 3 * 100);
+        assert_eq!(first.min_key, create_test_record(1).key);
+        assert_eq!(first.max_key, create_test_record(3).key);
+        assert_eq!(first.generation, 0);
+        assert_eq!(
+            std::fs::metadata(&first.path).unwrap().len(),
+            first.bytes,
+            "byte size should match the file on disk"
+        );
+
+        let second = &runs.runs[1];
+        assert_eq!(second.records, 1);
+        assert_eq!(second.min_key, create_test_record(0).key);
+        assert_eq!(second.max_key, create_test_record(0).key);
+        assert_eq!(second.generation, 1);
+
+        for path in runs.paths() {
+            std::fs::remove_file(path).ok();
+        }
+        std::fs::remove_file(rs.manifest_path()).ok();
+    }
+
+    #[test]
+    fn test_len_prefixed_runs() {
+        let mut input = Vec::new();
+        for i in [3u8, 1, 2, 0] {
+            let rec = create_test_record(i);
+            input.extend_from_slice(&rec.key);
+            input.extend_from_slice(&rec.payload);
+        }
+
+        let compact = ReplacementSelection::new(2, "test_layout_compact".to_string());
+        assert_eq!(compact.run_layout(), RunLayout::GENSORT);
+        let prefixed = ReplacementSelection::new(2, "test_layout_prefixed".to_string())
+            .with_compact_runs(false);
+        assert_eq!(prefixed.run_layout(), RunLayout::LenPrefixed);
+        let variable = ReplacementSelection::new(2, "unused".to_string())
+            .with_input_format(InputFormat::LenPrefixed);
+        assert_eq!(variable.run_layout(), RunLayout::LenPrefixed);
+
+        let runs = prefixed.run(Cursor::new(input.clone())).unwrap();
+        assert_eq!(runs.total_bytes(), 2 * RUN_HEADER_SIZE + 4 * 108);
+        let compact_runs = compact.run(Cursor::new(input)).unwrap();
+        assert_eq!(read_runs(&runs), read_runs(&compact_runs));
+        std::fs::remove_file(prefixed.manifest_path()).ok();
+        std::fs::remove_file(compact.manifest_path()).ok();
+    }
+
+    #[test]
+    fn test_manifest_lists_runs() {
+        let mut input = Vec::new();
+        for i in [5u8, 2, 7, 1, 3, 0, 9] {
+            let rec = create_test_record(i);
+            input.extend_from_slice(&rec.key);
+            input.extend_from_slice(&rec.payload);
+        }
+
+        let rs = ReplacementSelection::new(2, "test_manifest".to_string());
+        let runs = rs.run(Cursor::new(input)).unwrap();
+        assert!(runs.len() > 1);
+
+        let listed = crate::manifest::read_manifest(&rs.manifest_path()).unwrap();
+        assert_eq!(listed, runs);
+        for run in &runs {
+            let data = std::fs::read(&run.path).unwrap();
+            assert_eq!(run.checksum, crate::checksum::crc32(&data));
         }
+
+        for path in runs.paths() {
+            std::fs::remove_file(path).ok();
+        }
+        std::fs::remove_file(rs.manifest_path()).ok();
+    }
+
+    fn run_contents(runs: &RunSet) -> Vec<Vec<Vec<u8>>> {
+        runs.iter()
+            .map(|run| {
+                crate::io::RunReader::open(&run.path)
+                    .unwrap()
+                    .map(|r| r.unwrap().key)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_runs_deterministic() {
+        let recs: Vec<Rec> = crate::generate::Generator::new(20_000, 4)
+            .records()
+            .collect();
+        let mut input = Vec::new();
+        for rec in &recs {
+            input.extend_from_slice(&rec.key);
+            input.extend_from_slice(&rec.payload);
+        }
+
+        let rs = ReplacementSelection::new(400, "test_parallel".to_string()).with_threads(4);
+        let first = rs.run(Cursor::new(input.clone())).unwrap();
+        let first_keys = run_contents(&first);
+        let second = rs.run(Cursor::new(input)).unwrap();
+        assert_eq!(first, second, "same thread count gives the same runs");
+        assert_eq!(first_keys, run_contents(&second));
+
+        // Every worker wrote runs, each run is sorted and no record is lost
+        for w in 0..4 {
+            let prefix = format!("test_parallel_t{:03}_", w);
+            assert!(first.iter().any(|r| r.path.starts_with(&prefix)));
+        }
+        assert!(
+            first_keys
+                .iter()
+                .all(|k| k.windows(2).all(|w| w[0] <= w[1]))
+        );
+        let mut keys: Vec<Vec<u8>> = first_keys.into_iter().flatten().collect();
+        let mut expected: Vec<Vec<u8>> = recs.into_iter().map(|r| r.key).collect();
+        keys.sort();
+        expected.sort();
+        assert_eq!(keys, expected);
+        assert_eq!(
+            crate::manifest::read_manifest(&rs.manifest_path()).unwrap(),
+            first
+        );
+
+        for path in first.paths() {
+            std::fs::remove_file(path).ok();
+        }
+        std::fs::remove_file(rs.manifest_path()).ok();
+    }
+
+    #[test]
+    fn test_parallel_memory_budget() {
+        // Reverse sorted input: every run fills its worker's share of the budget
+        let mut input = Vec::new();
+        for i in (0..20_000u32).rev() {
+            let mut key = [0u8; 10];
+            key[..4].copy_from_slice(&i.to_be_bytes());
+            input.extend_from_slice(&key);
+            input.extend_from_slice(&[0u8; 90]);
+        }
+
+        let per_rec = MemoryBudget::item_bytes(&create_test_record(0));
+        let in_flight = 2 * (BATCH_QUEUE + 2) * BATCH_BYTES;
+        let budget = MemoryBudget::new(in_flight + 2 * (MemoryBudget::io_bytes() + 1000 * per_rec));
+        let rs =
+            ReplacementSelection::with_memory_budget(budget, "test_parallel_budget".to_string())
+                .with_threads(2);
+        let runs = rs.run(Cursor::new(input)).unwrap();
+
+        assert_eq!(runs.total_records(), 20_000);
+        let largest = runs.iter().map(|r| r.records).max().unwrap();
+        assert!(
+            largest <= 1000,
+            "worker workspace exceeded its share: {}",
+            largest
+        );
+        assert!(largest >= 900, "worker workspace too small: {}", largest);
+
+        for path in runs.paths() {
+            std::fs::remove_file(path).ok();
+        }
+        std::fs::remove_file(rs.manifest_path()).ok();
+    }
+
+    #[test]
+    fn test_in_flight_charges_large_batches() {
+        let in_flight = InFlight::new(4 * BATCH_BYTES);
+        let small = in_flight.acquire(10);
+        assert_eq!(*in_flight.used.lock().unwrap(), BATCH_BYTES);
+        std::thread::scope(|scope| {
+            // A batch larger than the limit waits until nothing else is in flight
+            let large = scope.spawn(|| in_flight.acquire(10 * BATCH_BYTES).bytes);
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!large.is_finished());
+            drop(small);
+            assert_eq!(large.join().unwrap(), 10 * BATCH_BYTES);
+        });
+        assert_eq!(*in_flight.used.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_parallel_budget_too_small() {
+        // The queued batches alone take the whole budget
+        let in_flight = 4 * (BATCH_QUEUE + 2) * BATCH_BYTES;
+        let rs = ReplacementSelection::with_memory_budget(
+            MemoryBudget::new(in_flight),
+            "test_parallel_small".to_string(),
+        )
+        .with_threads(4);
+        let input = vec![0u8; 100 * 100];
+        let err = rs.run(Cursor::new(input)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!Path::new(&rs.manifest_path()).exists());
+    }
+
+    #[test]
+    fn test_parallel_error_removes_runs() {
+        // 5000 good records followed by a truncated one
+        let mut input = Vec::new();
+        for i in 0..5000u32 {
+            input.extend_from_slice(&create_test_record((i % 251) as u8).key);
+            input.extend_from_slice(&[0u8; 90]);
+        }
+        input.extend_from_slice(&[7u8; 30]);
+
+        let rs = ReplacementSelection::new(64, "test_parallel_err".to_string()).with_threads(3);
+        assert!(rs.run(Cursor::new(input)).is_err());
+        let leftovers = std::fs::read_dir(".")
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.file_name()
+                    .to_string_lossy()
+                    .starts_with("test_parallel_err")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
     }
 
     #[test]
@@ -194,14 +1167,15 @@ mod tests {
 
         let cursor = Cursor::new(input);
         let rs = ReplacementSelection::new(3, "test_cap".to_string());
-        let num_runs = rs.run(cursor).unwrap();
+        let num_runs = rs.run(cursor).unwrap().len();
 
         // Should successfully process all records
         assert!(num_runs >= 1);
 
         // Clean up
         for i in 0..num_runs {
-            std::fs::remove_file(format!("test_cap_{:03}.bin", i)).ok();
+            std::fs::remove_file(format!("test_cap_{:08}.bin", i)).ok();
         }
+        std::fs::remove_file(rs.manifest_path()).ok();
     }
 }
@@ -0,0 +1,87 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Distinguishes run directories created by the same process.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Uniquely named directory for the intermediate runs of one sort job.
+///
+/// The name combines the process id, a timestamp and a per-process counter, so
+/// concurrent jobs sharing a temp directory never collide. The directory and
+/// everything in it is removed on drop, whether the job succeeded or failed,
+/// unless `keep` was called.
+pub struct RunDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl RunDir {
+    /// Create a fresh directory `rs-<pid>-<nanos>-<n>` under `parent`.
+    pub fn create_in(parent: impl AsRef<Path>) -> io::Result<Self> {
+        let parent = parent.as_ref();
+        std::fs::create_dir_all(parent)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        loop {
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let path = parent.join(format!("rs-{}-{}-{}", std::process::id(), nanos, id));
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(Self { path, keep: false }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Run file prefix inside this directory, for `ReplacementSelection`.
+    pub fn prefix(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().into_owned()
+    }
+
+    /// Leave the directory and its runs on disk when dropped.
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_dirs_are_unique_and_removed() {
+        let parent = "test_run_dir_parent";
+        let a = RunDir::create_in(parent).unwrap();
+        let b = RunDir::create_in(parent).unwrap();
+        assert_ne!(a.path(), b.path());
+        assert!(a.path().starts_with(parent));
+
+        std::fs::write(a.prefix("run_00000000.bin"), b"data").unwrap();
+        let a_path = a.path().to_path_buf();
+        drop(a);
+        assert!(!a_path.exists(), "run dir should be removed on drop");
+
+        let mut b = b;
+        b.keep();
+        let b_path = b.path().to_path_buf();
+        drop(b);
+        assert!(b_path.exists(), "kept run dir should survive drop");
+
+        std::fs::remove_dir_all(parent).ok();
+    }
+}
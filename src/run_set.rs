@@ -0,0 +1,58 @@
+/// Metadata of one sorted run file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunInfo {
+    pub path: String,
+    pub records: u64,
+    /// File size in bytes, including the run header and any length prefixes.
+    pub bytes: u64,
+    /// Key of the first (smallest) record.
+    pub min_key: Vec<u8>,
+    /// Key of the last (largest) record.
+    pub max_key: Vec<u8>,
+    /// Replacement-selection generation that produced the run.
+    pub generation: u64,
+    /// CRC-32 of the run file contents.
+    pub checksum: u32,
+}
+
+/// Runs produced by one run-generation pass, in the order they were written.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunSet {
+    pub runs: Vec<RunInfo>,
+}
+
+impl RunSet {
+    pub fn len(&self) -> usize {
+        self.runs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, RunInfo> {
+        self.runs.iter()
+    }
+
+    /// Run file paths, e.g. for `Merger::merge`.
+    pub fn paths(&self) -> Vec<String> {
+        self.runs.iter().map(|r| r.path.clone()).collect()
+    }
+
+    pub fn total_records(&self) -> u64 {
+        self.runs.iter().map(|r| r.records).sum()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.runs.iter().map(|r| r.bytes).sum()
+    }
+}
+
+impl<'a> IntoIterator for &'a RunSet {
+    type Item = &'a RunInfo;
+    type IntoIter = std::slice::Iter<'a, RunInfo>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.runs.iter()
+    }
+}
@@ -0,0 +1,120 @@
+//! Tag sort: run generation and the merge carry each record's key with only its
+//! position in the input, and the merged order is used to gather the records
+//! from the input file in a final pass.
+
+use crate::format::RecordFormat;
+use crate::record::Rec;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+/// Payload of a tag: the record's input offset and encoded length, each a
+/// little-endian u64.
+pub const TAG_PAYLOAD_SIZE: usize = 16;
+
+/// Tag for `rec`, which was decoded from the `len` input bytes at `offset`. The
+/// key is shrunk to fit, as formats may decode it into a buffer sized for the
+/// whole record.
+pub fn tag(rec: Rec, offset: u64, len: u64) -> Rec {
+    let mut key = rec.key;
+    key.shrink_to_fit();
+    let mut payload = Vec::with_capacity(TAG_PAYLOAD_SIZE);
+    payload.extend_from_slice(&offset.to_le_bytes());
+    payload.extend_from_slice(&len.to_le_bytes());
+    Rec::new(key, payload)
+}
+
+/// Input offset and length of the record behind `tag`.
+pub fn tag_position(tag: &Rec) -> io::Result<(u64, u64)> {
+    let Ok(bytes) = <[u8; TAG_PAYLOAD_SIZE]>::try_from(tag.payload.as_slice()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("tag payload of {} bytes", tag.payload.len()),
+        ));
+    };
+    let (offset, len) = bytes.split_at(8);
+    Ok((
+        u64::from_le_bytes(offset.try_into().unwrap()),
+        u64::from_le_bytes(len.try_into().unwrap()),
+    ))
+}
+
+/// Reads the records behind tags from the input file they were taken from.
+///
+/// Records are read with one positioned read each, through the page cache, as
+/// the merged order visits the input in random order.
+#[derive(Debug)]
+pub struct Gather<F> {
+    input: File,
+    format: F,
+    buf: Vec<u8>,
+}
+
+impl<F: RecordFormat> Gather<F> {
+    /// Gather from the input file at `path`, whose records are in `format`.
+    pub fn open(path: &str, format: F) -> io::Result<Self> {
+        Ok(Self {
+            input: File::open(path)?,
+            format,
+            buf: Vec::new(),
+        })
+    }
+
+    /// The record behind `tag`. A record whose key differs from the tag's means
+    /// the input changed since the tags were taken, an `InvalidData` error.
+    pub fn record(&mut self, tag: &Rec) -> io::Result<Rec> {
+        let (offset, len) = tag_position(tag)?;
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "tagged record too large"))?;
+        self.buf.resize(len, 0);
+        self.input.read_exact_at(&mut self.buf, offset)?;
+        match self.format.decode(&mut self.buf.as_slice())? {
+            Some(rec) if rec.key == tag.key => Ok(rec),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "input record at offset {} no longer matches its tag",
+                    offset
+                ),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::TextLines;
+    use std::io::Write;
+
+    #[test]
+    fn test_gather_records_by_tag() {
+        let path = "test_gather_input.txt";
+        std::fs::File::create(path)
+            .unwrap()
+            .write_all(b"pear 3\napple 10\n")
+            .unwrap();
+        let format = TextLines::with_delimiter(b' ');
+        let mut gather = Gather::open(path, format).unwrap();
+
+        let apple = tag(Rec::new(b"apple".to_vec(), vec![]), 7, 9);
+        assert_eq!(tag_position(&apple).unwrap(), (7, 9));
+        assert_eq!(
+            gather.record(&apple).unwrap(),
+            Rec::new(b"apple".to_vec(), b" 10".to_vec())
+        );
+        let pear = tag(Rec::new(b"pear".to_vec(), vec![]), 0, 7);
+        assert_eq!(gather.record(&pear).unwrap().payload, b" 3");
+
+        // A tag that no longer points at its record
+        let moved = tag(Rec::new(b"fig".to_vec(), vec![]), 0, 7);
+        let err = gather.record(&moved).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let short = tag(Rec::new(b"pear".to_vec(), vec![]), 10, 20);
+        assert_eq!(
+            gather.record(&short).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        std::fs::remove_file(path).ok();
+    }
+}
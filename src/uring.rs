@@ -0,0 +1,565 @@
+//! Minimal io_uring bindings for the asynchronous I/O backend.
+//!
+//! Only what `ReadAhead` and `WriteBehind` need: a ring created with
+//! `io_uring_setup`, `IORING_OP_READ` / `IORING_OP_WRITE` submissions at explicit
+//! offsets and completion reaping. The kernel ABI structures are declared here
+//! because `libc` only provides the syscall numbers.
+
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+/// Set by kernels that support `IORING_OP_READ` / `IORING_OP_WRITE` (5.6+).
+const IORING_FEAT_RW_CUR_POS: u32 = 1 << 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory-mapped region of the ring, unmapped on drop.
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// An io_uring instance with one submission and one completion queue.
+struct Ring {
+    fd: RawFd,
+    sq_ring: Mmap,
+    cq_ring: Mmap,
+    sqes: Mmap,
+    params: Params,
+    unsubmitted: u32,
+}
+
+// The ring is only used by the reader or writer that owns it.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    /// Set up a ring with room for `entries` requests. Fails if io_uring is not
+    /// available (old kernel, seccomp, `io_uring_disabled`).
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = sys_setup(entries, &mut params)?;
+        let ring = (|| {
+            if params.features & IORING_FEAT_RW_CUR_POS == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "io_uring without IORING_OP_READ/WRITE",
+                ));
+            }
+            let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+            let cq_len =
+                params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+            let sqes_len = params.sq_entries as usize * size_of::<Sqe>();
+            Ok(Ring {
+                fd,
+                sq_ring: Mmap::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+                cq_ring: Mmap::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+                sqes: Mmap::new(fd, sqes_len, IORING_OFF_SQES)?,
+                params,
+                unsubmitted: 0,
+            })
+        })();
+        if ring.is_err() {
+            unsafe { libc::close(fd) };
+        }
+        ring
+    }
+
+    fn atomic(&self, map: &Mmap, offset: u32) -> &AtomicU32 {
+        unsafe { &*map.at::<AtomicU32>(offset) }
+    }
+
+    /// Queue a read or write of `len` bytes at `buf` and file `offset`.
+    ///
+    /// Safety: `buf` must stay valid, and unused by anyone else, until the
+    /// completion for `user_data` has been reaped.
+    unsafe fn push(
+        &mut self,
+        opcode: u8,
+        fd: RawFd,
+        buf: *mut u8,
+        len: usize,
+        offset: u64,
+        user_data: u64,
+    ) {
+        let off = &self.params.sq_off;
+        let head = self.atomic(&self.sq_ring, off.head).load(Ordering::Acquire);
+        let tail = self.atomic(&self.sq_ring, off.tail).load(Ordering::Relaxed);
+        // Callers keep at most `sq_entries` requests in flight
+        debug_assert!(tail.wrapping_sub(head) < self.params.sq_entries);
+        let mask = unsafe { *self.sq_ring.at::<u32>(off.ring_mask) };
+        let idx = tail & mask;
+        unsafe {
+            *self.sqes.at::<Sqe>(idx * size_of::<Sqe>() as u32) = Sqe {
+                opcode,
+                fd,
+                off: offset,
+                addr: buf as u64,
+                len: len as u32,
+                user_data,
+                ..Sqe::default()
+            };
+            *self.sq_ring.at::<u32>(off.array + idx * 4) = idx;
+        }
+        self.atomic(&self.sq_ring, off.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+    }
+
+    /// Submit queued requests and wait until at least `wait` completions are available.
+    fn enter(&mut self, wait: u32) -> io::Result<()> {
+        loop {
+            let flags = if wait > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+            let ret = sys_enter(self.fd, self.unsubmitted, wait, flags);
+            if ret >= 0 {
+                self.unsubmitted -= (ret as u32).min(self.unsubmitted);
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Take one completion: `(user_data, result)`.
+    fn pop(&mut self) -> Option<(u64, i32)> {
+        let off = &self.params.cq_off;
+        let head = self.atomic(&self.cq_ring, off.head).load(Ordering::Relaxed);
+        let tail = self.atomic(&self.cq_ring, off.tail).load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let mask = unsafe { *self.cq_ring.at::<u32>(off.ring_mask) };
+        let cqe = unsafe {
+            &*self
+                .cq_ring
+                .at::<Cqe>(off.cqes + (head & mask) * size_of::<Cqe>() as u32)
+        };
+        let done = (cqe.user_data, cqe.res);
+        self.atomic(&self.cq_ring, off.head)
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(done)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sys_setup(entries: u32, params: &mut Params) -> io::Result<RawFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, params as *mut Params) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd as RawFd)
+}
+
+#[cfg(target_os = "linux")]
+fn sys_enter(fd: RawFd, to_submit: u32, wait: u32, flags: u32) -> libc::c_long {
+    unsafe {
+        libc::syscall(
+            libc::SYS_io_uring_enter,
+            fd,
+            to_submit,
+            wait,
+            flags,
+            ptr::null::<libc::sigset_t>(),
+            0usize,
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sys_setup(_entries: u32, _params: &mut Params) -> io::Result<RawFd> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "io_uring is Linux only",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sys_enter(_fd: RawFd, _to_submit: u32, _wait: u32, _flags: u32) -> libc::c_long {
+    unreachable!("no ring can be set up")
+}
+
+/// One buffer and the request it is used for.
+struct Slot {
+    buf: Vec<u8>,
+    offset: u64,
+    len: usize,
+    /// Completion result once reaped; None while in flight.
+    result: Option<i32>,
+}
+
+/// Buffers shared with the kernel and the ring they are submitted to.
+/// Slots in `in_flight` must not be touched until their completion is reaped.
+/// Requests are queued and handed to the kernel in batches: once half the slots
+/// are queued, on `flush`, or when waiting for a completion.
+struct Slots {
+    ring: Ring,
+    fd: RawFd,
+    slots: Vec<Slot>,
+    in_flight: VecDeque<usize>, // in submission order
+}
+
+impl Slots {
+    fn new(
+        fd: RawFd,
+        depth: usize,
+        buf_size: usize,
+        alloc: &dyn Fn(usize) -> Vec<u8>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            ring: Ring::new(depth as u32)?,
+            fd,
+            slots: (0..depth)
+                .map(|_| Slot {
+                    buf: alloc(buf_size),
+                    offset: 0,
+                    len: 0,
+                    result: Some(0),
+                })
+                .collect(),
+            in_flight: VecDeque::with_capacity(depth),
+        })
+    }
+
+    /// Queue a request for `slot`, submitting the queue once it holds half the slots.
+    fn submit(&mut self, slot: usize, opcode: u8, offset: u64, len: usize) -> io::Result<()> {
+        let s = &mut self.slots[slot];
+        s.offset = offset;
+        s.len = len;
+        s.result = None;
+        let buf = s.buf.as_mut_ptr();
+        unsafe {
+            self.ring
+                .push(opcode, self.fd, buf, len, offset, slot as u64)
+        };
+        self.in_flight.push_back(slot);
+        if self.ring.unsubmitted as usize * 2 >= self.slots.len() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Hand the queued requests to the kernel without waiting.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.ring.unsubmitted == 0 {
+            return Ok(());
+        }
+        self.ring.enter(0)
+    }
+
+    /// Wait for the oldest request in flight and return its slot.
+    fn wait_oldest(&mut self) -> io::Result<Option<usize>> {
+        let Some(&slot) = self.in_flight.front() else {
+            return Ok(None);
+        };
+        while self.slots[slot].result.is_none() {
+            match self.ring.pop() {
+                Some((user_data, res)) => self.slots[user_data as usize].result = Some(res),
+                // Submits the queued requests too
+                None => self.ring.enter(1)?,
+            }
+        }
+        self.in_flight.pop_front();
+        Ok(Some(slot))
+    }
+
+    /// Wait for every request in flight, ignoring results.
+    fn drain(&mut self) {
+        while let Ok(Some(_)) = self.wait_oldest() {}
+        // If waiting failed the kernel may still own the buffers: leak them
+        if !self.in_flight.is_empty() {
+            for slot in std::mem::take(&mut self.in_flight) {
+                std::mem::forget(std::mem::take(&mut self.slots[slot].buf));
+            }
+        }
+    }
+}
+
+impl Drop for Slots {
+    fn drop(&mut self) {
+        self.drain();
+    }
+}
+
+/// Finish a short transfer synchronously; `done` bytes of the slot are already done.
+fn complete_sync(fd: RawFd, slot: &mut Slot, mut done: usize, write: bool) -> io::Result<usize> {
+    while done < slot.len {
+        let buf = slot.buf[done..slot.len].as_mut_ptr();
+        let offset = (slot.offset + done as u64) as libc::off_t;
+        let n = unsafe {
+            if write {
+                libc::pwrite(fd, buf.cast(), slot.len - done, offset)
+            } else {
+                libc::pread(fd, buf.cast(), slot.len - done, offset)
+            }
+        };
+        match n {
+            0 if !write => break, // EOF
+            n if n > 0 => done += n as usize,
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+    Ok(done)
+}
+
+fn completion_result(res: i32) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Sequential read-ahead: keeps up to `depth` chunk-sized reads of a file in flight.
+pub(crate) struct ReadAhead {
+    slots: Slots,
+    chunk: usize,
+    next_offset: u64, // file offset of the next read to submit
+    file_size: u64,
+}
+
+impl ReadAhead {
+    /// Start reading the file at `offset`, which must be aligned for Direct I/O.
+    pub(crate) fn new(
+        fd: RawFd,
+        file_size: u64,
+        offset: u64,
+        depth: usize,
+        chunk: usize,
+        alloc: &dyn Fn(usize) -> Vec<u8>,
+    ) -> io::Result<Self> {
+        let mut ahead = Self {
+            slots: Slots::new(fd, depth, chunk, alloc)?,
+            chunk,
+            next_offset: offset,
+            file_size,
+        };
+        for slot in 0..depth {
+            ahead.submit(slot)?;
+        }
+        ahead.slots.flush()?;
+        Ok(ahead)
+    }
+
+    fn submit(&mut self, slot: usize) -> io::Result<()> {
+        if self.next_offset >= self.file_size {
+            return Ok(());
+        }
+        let offset = self.next_offset;
+        self.next_offset += self.chunk as u64;
+        self.slots.submit(slot, IORING_OP_READ, offset, self.chunk)
+    }
+
+    /// Swap the next chunk in file order into `buf`, which must be a chunk-sized
+    /// buffer from the same allocator. Returns the number of valid bytes, 0 at EOF.
+    pub(crate) fn next(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let Some(slot) = self.slots.wait_oldest()? else {
+            return Ok(0);
+        };
+        let res = self.slots.slots[slot].result.unwrap_or(0);
+        let s = &mut self.slots.slots[slot];
+        let expected = (self.file_size.saturating_sub(s.offset) as usize).min(s.len);
+        let mut valid = completion_result(res)?;
+        if valid < expected {
+            valid = complete_sync(self.slots.fd, s, valid, false)?;
+        }
+        // The file shrank since its size was taken
+        if valid < expected {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "file ends at byte {}, expected {}",
+                    s.offset + valid as u64,
+                    self.file_size
+                ),
+            ));
+        }
+        let valid = valid.min(expected);
+        std::mem::swap(buf, &mut s.buf);
+        self.submit(slot)?;
+        Ok(valid)
+    }
+}
+
+/// Write-behind: keeps up to `depth` chunk-sized writes of a file in flight.
+pub(crate) struct WriteBehind {
+    slots: Slots,
+    free: Vec<usize>,
+}
+
+impl WriteBehind {
+    pub(crate) fn new(
+        fd: RawFd,
+        depth: usize,
+        chunk: usize,
+        alloc: &dyn Fn(usize) -> Vec<u8>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            slots: Slots::new(fd, depth, chunk, alloc)?,
+            free: (0..depth).collect(),
+        })
+    }
+
+    /// Check the result of a reaped write, finishing it synchronously if short.
+    fn check(&mut self, slot: usize) -> io::Result<()> {
+        let res = self.slots.slots[slot].result.unwrap_or(0);
+        let done = completion_result(res)?;
+        let s = &mut self.slots.slots[slot];
+        if done < s.len {
+            complete_sync(self.slots.fd, s, done, true)?;
+        }
+        Ok(())
+    }
+
+    /// Write the first `len` bytes of `buf` at `offset`. `buf` is swapped with an
+    /// idle chunk-sized buffer, waiting for the oldest write if all are busy.
+    pub(crate) fn write(&mut self, buf: &mut Vec<u8>, len: usize, offset: u64) -> io::Result<()> {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                let slot = self.slots.wait_oldest()?.expect("no write in flight");
+                self.free.push(slot);
+                self.check(slot)?;
+                self.free.pop().unwrap()
+            }
+        };
+        std::mem::swap(buf, &mut self.slots.slots[slot].buf);
+        self.slots.submit(slot, IORING_OP_WRITE, offset, len)
+    }
+
+    /// Wait for every write in flight and report the first error.
+    pub(crate) fn wait_all(&mut self) -> io::Result<()> {
+        let mut first_err = None;
+        while let Some(slot) = self.slots.wait_oldest()? {
+            self.free.push(slot);
+            if let Err(e) = self.check(slot) {
+                first_err.get_or_insert(e);
+            }
+        }
+        first_err.map_or(Ok(()), Err)
+    }
+}
+
+/// True if io_uring can be set up in this process.
+pub(crate) fn available() -> bool {
+    Ring::new(1).is_ok()
+}
@@ -0,0 +1,177 @@
+//! Sort verification modelled on gensort's `valsort`.
+
+use crate::checksum::Crc32;
+use crate::format::RecordFormat;
+use crate::io::{IoMode, RecordReader, RunReader, open_reader};
+use crate::record::Rec;
+use std::io::{self, BufReader, Read};
+
+/// Result of checking a run or output file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Validation {
+    pub records: u64,
+    /// Index of the first record whose key is smaller than its predecessor's.
+    pub first_unsorted: Option<u64>,
+    /// Number of records whose key is smaller than their predecessor's.
+    pub unsorted: u64,
+    /// Number of records whose key equals their predecessor's.
+    pub duplicates: u64,
+    /// Sum of the CRC-32 of every record's key and payload. The sum does not depend
+    /// on record order or file layout, so an input and its sorted output match.
+    pub checksum: u128,
+}
+
+impl Validation {
+    pub fn is_sorted(&self) -> bool {
+        self.first_unsorted.is_none()
+    }
+
+    /// True if both hold the same records, in any order.
+    pub fn same_records(&self, other: &Validation) -> bool {
+        self.records == other.records && self.checksum == other.checksum
+    }
+}
+
+/// CRC-32 of a record's key followed by its payload; for gensort records this is
+/// the CRC of the 100-byte record, as `valsort` computes it.
+pub fn record_checksum(rec: &Rec) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&rec.key);
+    crc.update(&rec.payload);
+    crc.value()
+}
+
+/// Check that the records in `rdr` are in non-decreasing key order, counting
+/// records and duplicate keys and summing record checksums.
+pub fn validate<R: Read, F: RecordFormat>(rdr: R, format: F) -> io::Result<Validation> {
+    let mut rdr = RecordReader::new(BufReader::new(rdr), format);
+    validate_records(|| rdr.next_rec())
+}
+
+/// Like `validate`, for a run file in any `RunLayout`, or a length-prefixed file
+/// without a run header.
+pub fn validate_run<R: Read>(rdr: R) -> io::Result<Validation> {
+    let mut rdr = RunReader::new(rdr);
+    validate_records(|| Ok(rdr.next_rec()?))
+}
+
+fn validate_records(
+    mut next_rec: impl FnMut() -> io::Result<Option<Rec>>,
+) -> io::Result<Validation> {
+    let mut v = Validation::default();
+    let mut prev_key: Option<Vec<u8>> = None;
+    while let Some(rec) = next_rec()? {
+        match prev_key.as_ref().map(|p| rec.key.cmp(p)) {
+            Some(std::cmp::Ordering::Less) => {
+                v.first_unsorted.get_or_insert(v.records);
+                v.unsorted += 1;
+            }
+            Some(std::cmp::Ordering::Equal) => v.duplicates += 1,
+            _ => {}
+        }
+        v.checksum += record_checksum(&rec) as u128;
+        v.records += 1;
+        prev_key = Some(rec.key);
+    }
+    Ok(v)
+}
+
+/// Check the file at `path`, see `validate`.
+pub fn validate_file<F: RecordFormat>(
+    path: &str,
+    format: F,
+    io_mode: IoMode,
+) -> io::Result<Validation> {
+    let mut rdr = RecordReader::new(open_reader(path, io_mode)?, format);
+    validate_records(|| rdr.next_rec())
+}
+
+/// Check the run file at `path`, see `validate_run`.
+pub fn validate_run_file(path: &str, io_mode: IoMode) -> io::Result<Validation> {
+    let mut rdr = RunReader::open_with_mode(path, io_mode)?;
+    validate_records(|| Ok(rdr.next_rec()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::InputFormat;
+    use std::io::Cursor;
+
+    fn gensort_input(keys: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &k in keys {
+            data.push(k);
+            data.extend_from_slice(&[0u8; 99]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_validate_sorted() {
+        let v = validate(
+            Cursor::new(gensort_input(&[1, 2, 2, 5])),
+            InputFormat::Gensort,
+        )
+        .unwrap();
+        assert_eq!(v.records, 4);
+        assert!(v.is_sorted());
+        assert_eq!(v.duplicates, 1);
+    }
+
+    #[test]
+    fn test_validate_unsorted() {
+        let v = validate(
+            Cursor::new(gensort_input(&[1, 3, 2, 0])),
+            InputFormat::Gensort,
+        )
+        .unwrap();
+        assert_eq!(v.records, 4);
+        assert_eq!(v.first_unsorted, Some(2));
+        assert_eq!(v.unsorted, 2);
+        assert_eq!(v.duplicates, 0);
+    }
+
+    #[test]
+    fn test_checksum_ignores_order_and_format() {
+        let keys = [7u8, 3, 9, 3, 1];
+        let unsorted = validate(Cursor::new(gensort_input(&keys)), InputFormat::Gensort).unwrap();
+
+        // Same records, sorted and length-prefixed
+        let mut sorted_keys = keys;
+        sorted_keys.sort();
+        let mut data = Vec::new();
+        for k in sorted_keys {
+            let mut key = [0u8; 10];
+            key[0] = k;
+            data.extend_from_slice(&10u32.to_le_bytes());
+            data.extend_from_slice(&key);
+            data.extend_from_slice(&90u32.to_le_bytes());
+            data.extend_from_slice(&[0u8; 90]);
+        }
+        let sorted = validate(Cursor::new(data), InputFormat::LenPrefixed).unwrap();
+
+        assert!(sorted.is_sorted());
+        assert!(sorted.same_records(&unsorted));
+        assert_eq!(
+            unsorted.checksum,
+            gensort_input(&keys)
+                .chunks(100)
+                .map(|r| crate::checksum::crc32(r) as u128)
+                .sum::<u128>()
+        );
+
+        // A changed payload byte changes the checksum
+        let mut corrupt = gensort_input(&keys);
+        corrupt[150] = 1;
+        let corrupt = validate(Cursor::new(corrupt), InputFormat::Gensort).unwrap();
+        assert!(!corrupt.same_records(&unsorted));
+    }
+
+    #[test]
+    fn test_validate_empty() {
+        let v = validate(Cursor::new(Vec::new()), InputFormat::LenPrefixed).unwrap();
+        assert_eq!(v, Validation::default());
+        assert!(v.is_sorted());
+    }
+}
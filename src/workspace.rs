@@ -0,0 +1,205 @@
+//! Replacement-selection workspace: records stay in the slot they were read into,
+//! while a loser tree orders small entries pointing at them.
+
+use crate::loser_tree::LoserTree;
+use crate::record::{Rec, generation_prefix};
+use std::cmp::Ordering;
+use std::mem::size_of;
+
+/// Tree leaf: a record's `generation_prefix`, split so that entries stay 8-byte
+/// aligned, and the slot holding the record.
+struct Entry {
+    prefix: [u64; 2],
+    slot: u32,
+}
+
+impl Entry {
+    fn new(g: u64, key: &[u8], slot: usize) -> Self {
+        let prefix = generation_prefix(g, key);
+        Self {
+            prefix: [(prefix >> 64) as u64, prefix as u64],
+            slot: slot as u32,
+        }
+    }
+}
+
+/// Record held in a slot, with its generation and arrival order.
+struct Slot {
+    rec: Rec,
+    g: u64,
+    seq: u64, // tie-breaker between equal keys of one generation
+}
+
+/// Fixed number of record slots, ordered by `(generation, key, seq)`.
+///
+/// Matches in the tree compare the entries' prefixes and only look at the slots
+/// when those are equal, so records never move: a record's bytes are written
+/// from the buffers it was decoded into straight to the run writer, and the
+/// record read next takes over the winner's slot. Slots emptied by `pop` are
+/// kept for `push` to refill.
+pub struct Workspace {
+    tree: LoserTree<Entry>,
+    slots: Vec<Slot>, // slot i belongs to tree leaf i
+    free: Vec<u32>,   // empty slots
+}
+
+/// Order of the records behind entries `a` and `b`.
+fn cmp_entries(slots: &[Slot], a: &Entry, b: &Entry) -> Ordering {
+    a.prefix.cmp(&b.prefix).then_with(|| {
+        let (x, y) = (&slots[a.slot as usize], &slots[b.slot as usize]);
+        x.g.cmp(&y.g)
+            .then_with(|| x.rec.key.cmp(&y.rec.key))
+            .then_with(|| x.seq.cmp(&y.seq))
+    })
+}
+
+impl Workspace {
+    /// Bytes per slot besides the record's key and payload buffers: the slot, its
+    /// tree leaf and loser node, its free list entry, and the scratch space used
+    /// to build the tree.
+    pub const SLOT_BYTES: usize =
+        size_of::<Slot>() + size_of::<Option<Entry>>() + size_of::<u32>() + 3 * size_of::<usize>();
+
+    /// Workspace with one slot per record, all in generation 0, in arrival order.
+    pub fn new(records: Vec<Rec>) -> Self {
+        assert!(records.len() <= u32::MAX as usize, "too many slots");
+        let slots: Vec<Slot> = records
+            .into_iter()
+            .enumerate()
+            .map(|(i, rec)| Slot {
+                rec,
+                g: 0,
+                seq: i as u64,
+            })
+            .collect();
+        let leaves = slots
+            .iter()
+            .enumerate()
+            .map(|(slot, s)| Some(Entry::new(s.g, &s.rec.key, slot)))
+            .collect();
+        let tree = LoserTree::new_by(leaves, |a, b| cmp_entries(&slots, a, b));
+        let free = Vec::with_capacity(slots.len());
+        Self { tree, slots, free }
+    }
+
+    /// Number of slots.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Number of slots holding a record.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Smallest record and its generation, if any.
+    pub fn peek(&self) -> Option<(&Rec, u64)> {
+        let slot = &self.slots[self.tree.peek()?.slot as usize];
+        Some((&slot.rec, slot.g))
+    }
+
+    /// Put `rec` of generation `g` in the smallest record's slot and return that
+    /// record. `seq` orders it after records with an equal key and generation
+    /// that have a smaller one. Panics if the workspace is empty.
+    pub fn replace_top(&mut self, rec: Rec, g: u64, seq: u64) -> Rec {
+        let slot = self.tree.peek_index().expect("workspace is empty");
+        let entry = Entry::new(g, &rec.key, slot);
+        let old = std::mem::replace(&mut self.slots[slot], Slot { rec, g, seq });
+        let slots = &self.slots;
+        self.tree
+            .replace_top_by(Some(entry), |a, b| cmp_entries(slots, a, b));
+        old.rec
+    }
+
+    /// Remove and return the smallest record, leaving its slot empty.
+    pub fn pop(&mut self) -> Option<Rec> {
+        let slot = self.tree.peek_index()?;
+        let old = std::mem::replace(&mut self.slots[slot].rec, Rec::new(Vec::new(), Vec::new()));
+        let slots = &self.slots;
+        self.tree
+            .replace_top_by(None, |a, b| cmp_entries(slots, a, b));
+        self.free.push(slot as u32);
+        Some(old)
+    }
+
+    /// Put `rec` of generation `g` in an empty slot, ordered by `seq` as in
+    /// `replace_top`. Panics if every slot holds a record.
+    pub fn push(&mut self, rec: Rec, g: u64, seq: u64) {
+        let slot = self.free.pop().expect("workspace is full") as usize;
+        let entry = Entry::new(g, &rec.key, slot);
+        self.slots[slot] = Slot { rec, g, seq };
+        let slots = &self.slots;
+        self.tree
+            .replace_leaf_by(slot, Some(entry), |a, b| cmp_entries(slots, a, b));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(key: &[u8], payload: u8) -> Rec {
+        Rec::new(key.to_vec(), vec![payload])
+    }
+
+    #[test]
+    fn test_orders_by_generation_key_and_seq() {
+        let mut ws = Workspace::new(vec![rec(b"b", 0), rec(b"a", 1), rec(b"c", 2)]);
+        assert_eq!((ws.capacity(), ws.len()), (3, 3));
+        assert_eq!(
+            ws.peek().map(|(r, g)| (r.key.clone(), g)),
+            Some((b"a".to_vec(), 0))
+        );
+
+        // A smaller key in the next generation goes after the current one
+        assert_eq!(ws.replace_top(rec(b"0", 3), 1, 3), rec(b"a", 1));
+        // Equal keys of one generation leave in seq order, whatever the slot
+        assert_eq!(ws.replace_top(rec(b"c", 4), 0, 4), rec(b"b", 0));
+        assert_eq!(ws.pop(), Some(rec(b"c", 2)));
+        assert_eq!(ws.pop(), Some(rec(b"c", 4)));
+        assert_eq!(ws.peek().map(|(_, g)| g), Some(1));
+        assert_eq!(ws.pop(), Some(rec(b"0", 3)));
+        assert!(ws.is_empty());
+        assert_eq!(ws.pop(), None);
+    }
+
+    #[test]
+    fn test_push_refills_popped_slots() {
+        let mut ws = Workspace::new(vec![rec(b"b", 0), rec(b"d", 1), rec(b"f", 2)]);
+        assert_eq!(ws.pop(), Some(rec(b"b", 0)));
+        assert_eq!(ws.pop(), Some(rec(b"d", 1)));
+        assert_eq!(ws.len(), 1);
+
+        // Refilled slots take part in the order like the others
+        ws.push(rec(b"e", 3), 0, 3);
+        ws.push(rec(b"a", 4), 1, 4);
+        assert_eq!((ws.capacity(), ws.len()), (3, 3));
+        assert_eq!(ws.replace_top(rec(b"c", 5), 1, 5), rec(b"e", 3));
+        let mut out = Vec::new();
+        while let Some(r) = ws.pop() {
+            out.push(r.payload[0]);
+        }
+        assert_eq!(out, vec![2, 4, 5]);
+    }
+
+    #[test]
+    fn test_full_keys_decide_prefix_ties() {
+        // Keys equal in their first 12 bytes, or equal up to trailing zeros
+        let long = |last: u8| [[9u8; 12].as_slice(), &[last]].concat();
+        let mut ws = Workspace::new(vec![
+            rec(&long(2), 0),
+            rec(&[9u8, 0], 1),
+            rec(&long(1), 2),
+            rec(&[9u8], 3),
+        ]);
+        let mut keys = Vec::new();
+        while let Some(r) = ws.pop() {
+            keys.push(r.key);
+        }
+        assert_eq!(keys, vec![vec![9u8], vec![9u8, 0], long(1), long(2)]);
+    }
+}
@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
+use std::io::Write;
 use std::path::Path;
 
 // Helper to create a gensort-format file with specified keys
@@ -15,40 +15,12 @@ fn create_test_file(path: &str, keys: &[u8]) -> std::io::Result<()> {
     Ok(())
 }
 
-// Helper to read keys from a run file (with length prefixes)
+// Helper to read keys from a run file (compact or length-prefixed)
 fn read_run_file_keys(path: &str) -> std::io::Result<Vec<u8>> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
     let mut keys = Vec::new();
-
-    loop {
-        // Read key length (4 bytes)
-        let mut key_len_buf = [0u8; 4];
-        match reader.read_exact(&mut key_len_buf) {
-            Ok(_) => {
-                let key_len = u32::from_le_bytes(key_len_buf) as usize;
-                assert_eq!(key_len, 10, "Expected key length of 10");
-
-                // Read key
-                let mut key = vec![0u8; key_len];
-                reader.read_exact(&mut key)?;
-                keys.push(key[0]); // Store first byte as identifier
-
-                // Read payload length (4 bytes)
-                let mut payload_len_buf = [0u8; 4];
-                reader.read_exact(&mut payload_len_buf)?;
-                let payload_len = u32::from_le_bytes(payload_len_buf) as usize;
-                assert_eq!(payload_len, 90, "Expected payload length of 90");
-
-                // Read payload
-                let mut payload = vec![0u8; payload_len];
-                reader.read_exact(&mut payload)?;
-            }
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-            Err(e) => return Err(e),
-        }
+    for rec in rs::io::RunReader::open(path)? {
+        keys.push(rec?.key[0]); // Store first byte as identifier
     }
-
     Ok(keys)
 }
 
@@ -64,7 +36,7 @@ fn cleanup_files(patterns: &[&str]) {
             let prefix = &pattern[..idx];
             let suffix = &pattern[idx + 1..];
             for i in 0..100 {
-                let filename = format!("{}{:03}{}", prefix, i, suffix);
+                let filename = format!("{}{:08}{}", prefix, i, suffix);
                 std::fs::remove_file(&filename).ok();
             }
         } else {
@@ -83,18 +55,22 @@ fn test_sorted_input_produces_single_run() {
 
     // Run replacement selection with small heap
     let rs = rs::replacement_selection::ReplacementSelection::new(3, run_prefix.to_string());
-    let num_runs = rs.run_from_file(input_file).unwrap();
+    let num_runs = rs.run_from_file(input_file).unwrap().len();
 
     // Should produce exactly 1 run for sorted input
     assert_eq!(num_runs, 1, "Sorted input should produce single run");
 
     // Verify the run is sorted
-    let keys = read_run_file_keys(&format!("{}_000.bin", run_prefix)).unwrap();
+    let keys = read_run_file_keys(&format!("{}_00000000.bin", run_prefix)).unwrap();
     assert!(verify_run_sorted(&keys), "Run should be sorted");
     assert_eq!(keys, vec![1, 2, 3, 4, 5], "All keys should be present");
 
     // Cleanup
-    cleanup_files(&[input_file, &format!("{}_*.bin", run_prefix)]);
+    cleanup_files(&[
+        input_file,
+        &format!("{}_*.bin", run_prefix),
+        &format!("{}.manifest", run_prefix),
+    ]);
 }
 
 #[test]
@@ -107,20 +83,24 @@ fn test_reverse_sorted_produces_multiple_runs() {
 
     // Run replacement selection with heap capacity of 2
     let rs = rs::replacement_selection::ReplacementSelection::new(2, run_prefix.to_string());
-    let num_runs = rs.run_from_file(input_file).unwrap();
+    let num_runs = rs.run_from_file(input_file).unwrap().len();
 
     // Reverse sorted should produce multiple runs
     assert!(num_runs > 1, "Reverse sorted should produce multiple runs");
 
     // Verify each run is sorted
     for i in 0..num_runs {
-        let run_file = format!("{}_{:03}.bin", run_prefix, i);
+        let run_file = format!("{}_{:08}.bin", run_prefix, i);
         let keys = read_run_file_keys(&run_file).unwrap();
         assert!(verify_run_sorted(&keys), "Run {} should be sorted", i);
     }
 
     // Cleanup
-    cleanup_files(&[input_file, &format!("{}_*.bin", run_prefix)]);
+    cleanup_files(&[
+        input_file,
+        &format!("{}_*.bin", run_prefix),
+        &format!("{}.manifest", run_prefix),
+    ]);
 }
 
 #[test]
@@ -134,12 +114,12 @@ fn test_random_input_all_records_present() {
 
     // Run replacement selection
     let rs = rs::replacement_selection::ReplacementSelection::new(4, run_prefix.to_string());
-    let num_runs = rs.run_from_file(input_file).unwrap();
+    let num_runs = rs.run_from_file(input_file).unwrap().len();
 
     // Collect all output keys from all runs
     let mut all_output_keys = Vec::new();
     for i in 0..num_runs {
-        let run_file = format!("{}_{:03}.bin", run_prefix, i);
+        let run_file = format!("{}_{:08}.bin", run_prefix, i);
         if Path::new(&run_file).exists() {
             let mut keys = read_run_file_keys(&run_file).unwrap();
             all_output_keys.append(&mut keys);
@@ -164,13 +144,17 @@ fn test_random_input_all_records_present() {
 
     // Verify each run is individually sorted
     for i in 0..num_runs {
-        let run_file = format!("{}_{:03}.bin", run_prefix, i);
+        let run_file = format!("{}_{:08}.bin", run_prefix, i);
         let keys = read_run_file_keys(&run_file).unwrap();
         assert!(verify_run_sorted(&keys), "Run {} should be sorted", i);
     }
 
     // Cleanup
-    cleanup_files(&[input_file, &format!("{}_*.bin", run_prefix)]);
+    cleanup_files(&[
+        input_file,
+        &format!("{}_*.bin", run_prefix),
+        &format!("{}.manifest", run_prefix),
+    ]);
 }
 
 #[test]
@@ -183,13 +167,17 @@ fn test_empty_input() {
 
     // Run replacement selection
     let rs = rs::replacement_selection::ReplacementSelection::new(10, run_prefix.to_string());
-    let num_runs = rs.run_from_file(input_file).unwrap();
+    let num_runs = rs.run_from_file(input_file).unwrap().len();
 
     // Should produce 0 runs for empty input
     assert_eq!(num_runs, 0, "Empty input should produce 0 runs");
 
     // Cleanup
-    cleanup_files(&[input_file, &format!("{}_*.bin", run_prefix)]);
+    cleanup_files(&[
+        input_file,
+        &format!("{}_*.bin", run_prefix),
+        &format!("{}.manifest", run_prefix),
+    ]);
 }
 
 #[test]
@@ -202,16 +190,20 @@ fn test_single_record() {
 
     // Run replacement selection
     let rs = rs::replacement_selection::ReplacementSelection::new(10, run_prefix.to_string());
-    let num_runs = rs.run_from_file(input_file).unwrap();
+    let num_runs = rs.run_from_file(input_file).unwrap().len();
 
     // Should produce 1 run
     assert_eq!(num_runs, 1, "Single record should produce 1 run");
 
-    let keys = read_run_file_keys(&format!("{}_000.bin", run_prefix)).unwrap();
+    let keys = read_run_file_keys(&format!("{}_00000000.bin", run_prefix)).unwrap();
     assert_eq!(keys, vec![42], "Should contain the single key");
 
     // Cleanup
-    cleanup_files(&[input_file, &format!("{}_*.bin", run_prefix)]);
+    cleanup_files(&[
+        input_file,
+        &format!("{}_*.bin", run_prefix),
+        &format!("{}.manifest", run_prefix),
+    ]);
 }
 
 #[test]
@@ -225,12 +217,12 @@ fn test_heap_capacity_effect() {
     // Test with small heap capacity
     let rs_small =
         rs::replacement_selection::ReplacementSelection::new(2, "test_cap_small".to_string());
-    let num_runs_small = rs_small.run_from_file(input_file).unwrap();
+    let num_runs_small = rs_small.run_from_file(input_file).unwrap().len();
 
     // Test with larger heap capacity
     let rs_large =
         rs::replacement_selection::ReplacementSelection::new(5, "test_cap_large".to_string());
-    let num_runs_large = rs_large.run_from_file(input_file).unwrap();
+    let num_runs_large = rs_large.run_from_file(input_file).unwrap().len();
 
     // Larger heap should produce fewer or equal runs
     assert!(
@@ -239,5 +231,761 @@ fn test_heap_capacity_effect() {
     );
 
     // Cleanup
-    cleanup_files(&[input_file, "test_cap_small_*.bin", "test_cap_large_*.bin"]);
+    cleanup_files(&[
+        input_file,
+        "test_cap_small_*.bin",
+        "test_cap_large_*.bin",
+        "test_cap_small.manifest",
+        "test_cap_large.manifest",
+    ]);
+}
+
+#[test]
+fn test_sort_from_file_merges_runs() {
+    let input_file = "test_merge_input.bin";
+    let output_file = "test_merge_output.bin";
+    let run_prefix = "test_merge_run";
+
+    // Create random input that produces several runs with a small heap
+    let input_keys = vec![9, 3, 7, 1, 8, 2, 6, 4, 5, 0, 3, 7];
+    create_test_file(input_file, &input_keys).unwrap();
+
+    let rs = rs::replacement_selection::ReplacementSelection::new(2, run_prefix.to_string());
+    let merged = rs.sort_from_file(input_file, output_file).unwrap();
+
+    // Every record should be merged into a single sorted output
+    assert_eq!(merged, input_keys.len() as u64);
+    let keys = read_run_file_keys(output_file).unwrap();
+    let mut expected = input_keys.clone();
+    expected.sort();
+    assert_eq!(keys, expected, "Merged output should be fully sorted");
+
+    // Cleanup
+    cleanup_files(&[
+        input_file,
+        output_file,
+        &format!("{}_*.bin", run_prefix),
+        &format!("{}.manifest", run_prefix),
+    ]);
+}
+
+#[test]
+fn test_variable_length_records_sorted() {
+    let input_file = "test_varlen_input.bin";
+    let output_file = "test_varlen_output.bin";
+    let run_prefix = "test_varlen_run";
+
+    // Keys of 4..=64 bytes, payloads up to a few KB, in length-prefixed format
+    let mut expected = Vec::new();
+    let mut file = File::create(input_file).unwrap();
+    for i in 0..40u32 {
+        let key_len = 4 + (i as usize * 13) % 61;
+        let key: Vec<u8> = (0..key_len)
+            .map(|j| ((i * 37 + j as u32) % 251) as u8)
+            .collect();
+        let payload = vec![i as u8; (i as usize * 97) % 4000];
+        file.write_all(&(key.len() as u32).to_le_bytes()).unwrap();
+        file.write_all(&key).unwrap();
+        file.write_all(&(payload.len() as u32).to_le_bytes())
+            .unwrap();
+        file.write_all(&payload).unwrap();
+        expected.push(rs::record::Rec::new(key, payload));
+    }
+    drop(file);
+
+    let rs = rs::replacement_selection::ReplacementSelection::new(5, run_prefix.to_string())
+        .with_input_format(rs::io::InputFormat::LenPrefixed);
+    let merged = rs.sort_from_file(input_file, output_file).unwrap();
+    assert_eq!(merged, expected.len() as u64);
+
+    // Output holds the same records, ordered by key
+    let output: Vec<rs::record::Rec> = rs::io::RunReader::open(output_file)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    expected.sort_by(|a, b| a.key.cmp(&b.key));
+    assert_eq!(output, expected);
+
+    // Cleanup
+    cleanup_files(&[
+        input_file,
+        output_file,
+        &format!("{}_*.bin", run_prefix),
+        &format!("{}.manifest", run_prefix),
+    ]);
+}
+
+#[test]
+fn test_sort_text_lines_by_first_column() {
+    use rs::io::{InputFormat, OutputFormat};
+    use rs::merge::Merger;
+
+    let input_file = "test_text_input.txt";
+    let output_file = "test_text_output.txt";
+    let temp_dir = "test_text_runs";
+
+    // Tab-separated lines with unique first columns of varying length
+    let mut lines: Vec<String> = (0..500u32)
+        .map(|i| format!("k{}\tvalue {}", (i * 7919) % 1009, i))
+        .collect();
+    std::fs::write(input_file, lines.join("\n")).unwrap();
+
+    let rs = rs::replacement_selection::ReplacementSelection::new(40, "run".to_string())
+        .with_input_format(InputFormat::Text {
+            delimiter: Some(b'\t'),
+        })
+        .with_temp_dir(temp_dir);
+    for threads in [1, 3] {
+        let merger = Merger::new()
+            .with_output_format(OutputFormat::Text)
+            .with_threads(threads);
+        let merged = rs
+            .sort_from_file_with(input_file, output_file, &merger)
+            .unwrap();
+        assert_eq!(merged, 500);
+
+        lines.sort_by(|a, b| a.split('\t').next().cmp(&b.split('\t').next()));
+        let expected = lines.join("\n") + "\n";
+        assert_eq!(std::fs::read_to_string(output_file).unwrap(), expected);
+    }
+
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_file]);
+}
+
+/// Records of a 4-byte big-endian key and a 4-byte payload, to check that a format
+/// defined outside the crate plugs into run generation and the merge.
+#[derive(Clone, Debug)]
+struct Pairs;
+
+impl rs::format::RecordFormat for Pairs {
+    fn decode<R: std::io::BufRead>(&self, r: &mut R) -> std::io::Result<Option<rs::record::Rec>> {
+        let Some(rec) = rs::io::read_exact_into::<8>(r)? else {
+            return Ok(None);
+        };
+        Ok(Some(rs::record::Rec::new(&rec[..4], &rec[4..])))
+    }
+
+    fn encode<W: rs::io::RecordWrite>(
+        &self,
+        w: &mut W,
+        rec: &rs::record::Rec,
+    ) -> std::io::Result<()> {
+        w.write_all(&rec.key)?;
+        w.write_all(&rec.payload)
+    }
+}
+
+#[test]
+fn test_sort_custom_record_format() {
+    use rs::merge::Merger;
+
+    let input_file = "test_pairs_input.bin";
+    let output_file = "test_pairs_output.bin";
+    let temp_dir = "test_pairs_runs";
+
+    let mut pairs: Vec<[u8; 8]> = (0..1000u32)
+        .map(|i| {
+            let mut pair = [0u8; 8];
+            pair[..4].copy_from_slice(&((i * 7919) % 1000).to_be_bytes());
+            pair[4..].copy_from_slice(&i.to_le_bytes());
+            pair
+        })
+        .collect();
+    std::fs::write(input_file, pairs.concat()).unwrap();
+
+    let rs = rs::replacement_selection::ReplacementSelection::new(64, "run".to_string())
+        .with_format(Pairs)
+        .with_temp_dir(temp_dir);
+    let merger = Merger::new().with_format(Pairs).with_threads(2);
+    let merged = rs
+        .sort_from_file_with(input_file, output_file, &merger)
+        .unwrap();
+    assert_eq!(merged, 1000);
+
+    pairs.sort_by_key(|p| u32::from_be_bytes(p[..4].try_into().unwrap()));
+    assert_eq!(std::fs::read(output_file).unwrap(), pairs.concat());
+
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_file]);
+}
+
+#[test]
+fn test_sort_cleans_up_runs_in_temp_dir() {
+    let input_file = "test_tempdir_input.bin";
+    let output_file = "test_tempdir_output.bin";
+    let temp_dir = "test_tempdir_runs";
+
+    create_test_file(input_file, &[5, 4, 3, 2, 1, 9, 8, 7]).unwrap();
+
+    let rs = rs::replacement_selection::ReplacementSelection::new(2, "run".to_string())
+        .with_temp_dir(temp_dir);
+    let merged = rs.sort_from_file(input_file, output_file).unwrap();
+    assert_eq!(merged, 8);
+    assert_eq!(
+        read_run_file_keys(output_file).unwrap(),
+        vec![1, 2, 3, 4, 5, 7, 8, 9]
+    );
+
+    // Runs went to a per-job directory that is gone after the merge
+    let leftovers = std::fs::read_dir(temp_dir).unwrap().count();
+    assert_eq!(leftovers, 0, "Run directory should be removed after sort");
+
+    // With keep_runs the per-job directory and its runs stay
+    let rs = rs.with_keep_runs(true);
+    rs.sort_from_file(input_file, output_file).unwrap();
+    let kept: Vec<_> = std::fs::read_dir(temp_dir).unwrap().collect();
+    assert_eq!(kept.len(), 1);
+    let job_dir = kept[0].as_ref().unwrap().path();
+    assert!(std::fs::read_dir(&job_dir).unwrap().count() > 1);
+
+    // Cleanup
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_file]);
+}
+
+#[test]
+fn test_sort_error_removes_runs() {
+    let input_file = "test_sort_error_input.bin";
+    let temp_dir = "test_sort_error_runs";
+
+    // Second record is truncated, so run generation fails after reading the first
+    let mut data = vec![0u8; 100];
+    data.extend_from_slice(&[1u8; 40]);
+    std::fs::write(input_file, data).unwrap();
+
+    let rs = rs::replacement_selection::ReplacementSelection::new(1, "run".to_string())
+        .with_temp_dir(temp_dir);
+    assert!(rs.sort_from_file(input_file, "unused_output.bin").is_err());
+    assert_eq!(std::fs::read_dir(temp_dir).unwrap().count(), 0);
+
+    // Direct run generation removes the runs it wrote so far
+    let rs =
+        rs::replacement_selection::ReplacementSelection::new(1, "test_sort_error_run".to_string());
+    assert!(rs.run_from_file(input_file).is_err());
+    assert!(!Path::new("test_sort_error_run_00000000.bin").exists());
+    assert!(!Path::new(&rs.manifest_path()).exists());
+
+    // Cleanup
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file]);
+}
+
+#[test]
+fn test_sort_preserves_record_checksum() {
+    use rs::io::{InputFormat, IoMode};
+    use rs::validate::validate_file;
+
+    let input_file = "test_checksum_input.bin";
+    let output_file = "test_checksum_output.bin";
+    let temp_dir = "test_checksum_runs";
+
+    // Records that differ only in their payload, so a swapped payload would show
+    let mut data = Vec::new();
+    for i in 0..300u32 {
+        data.push(((i * 131) % 17) as u8);
+        data.extend_from_slice(&[0u8; 9]);
+        data.extend_from_slice(&[(i % 251) as u8; 90]);
+    }
+    std::fs::write(input_file, data).unwrap();
+
+    let rs = rs::replacement_selection::ReplacementSelection::new(16, "run".to_string())
+        .with_temp_dir(temp_dir);
+    rs.sort_from_file(input_file, output_file).unwrap();
+
+    let input = validate_file(input_file, InputFormat::Gensort, IoMode::Direct).unwrap();
+    let output = validate_file(output_file, InputFormat::LenPrefixed, IoMode::Direct).unwrap();
+    assert!(!input.is_sorted());
+    assert!(output.is_sorted());
+    assert_eq!(output.records, 300);
+    assert_eq!(output.duplicates, 300 - 17);
+    assert!(
+        output.same_records(&input),
+        "sort must not lose or alter records"
+    );
+
+    // Cleanup
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_file]);
+}
+
+#[test]
+fn test_sort_stream_matches_file_sort() {
+    use rs::generate::Generator;
+    use rs::io::{IoMode, OutputFormat};
+    use rs::merge::Merger;
+    use std::io::BufWriter;
+
+    let input_file = "test_stream_input.bin";
+    let output_file = "test_stream_output.bin";
+    let temp_dir = "test_stream_runs";
+    let generator = Generator::new(2_000, 11);
+    generator.write_file(input_file, IoMode::Direct).unwrap();
+    let mut input = BufWriter::new(Vec::new());
+    generator.write_to(&mut input).unwrap();
+    let input = input.into_inner().unwrap();
+    assert_eq!(input, std::fs::read(input_file).unwrap());
+
+    let rs = rs::replacement_selection::ReplacementSelection::new(100, "run".to_string())
+        .with_temp_dir(temp_dir);
+    let merger = Merger::new().with_output_format(OutputFormat::Gensort);
+    rs.sort_from_file_with(input_file, output_file, &merger)
+        .unwrap();
+
+    // Sorting from a reader into a stream gives the same bytes
+    let mut output = BufWriter::new(Vec::new());
+    assert_eq!(rs.sort(&input[..], &mut output, &merger).unwrap(), 2_000);
+    assert_eq!(
+        output.into_inner().unwrap(),
+        std::fs::read(output_file).unwrap()
+    );
+
+    // Sorting from a reader into a file can use the parallel merge
+    let stream_file = "test_stream_output_parallel.bin";
+    let parallel = merger.clone().with_threads(2);
+    assert_eq!(
+        rs.sort_to_file(&input[..], stream_file, &parallel).unwrap(),
+        2_000
+    );
+    assert_eq!(
+        std::fs::read(stream_file).unwrap(),
+        std::fs::read(output_file).unwrap()
+    );
+    assert_eq!(std::fs::read_dir(temp_dir).unwrap().count(), 0);
+
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_file, stream_file]);
+}
+
+#[test]
+fn test_sort_generated_distributions() {
+    use rs::generate::{Generator, KeyDistribution, KeyType};
+    use rs::io::{InputFormat, IoMode};
+    use rs::validate::validate_file;
+
+    let input_file = "test_gen_dist_input.bin";
+    let output_file = "test_gen_dist_output.bin";
+    let temp_dir = "test_gen_dist_runs";
+
+    for (dist, key_type) in [
+        (KeyDistribution::Random, KeyType::Ascii),
+        (KeyDistribution::Sorted, KeyType::Binary),
+        (KeyDistribution::Reverse, KeyType::Binary),
+        (
+            KeyDistribution::PartiallySorted { percent: 50 },
+            KeyType::Binary,
+        ),
+        (KeyDistribution::Duplicates { distinct: 3 }, KeyType::Ascii),
+        (KeyDistribution::Zipf { exponent: 1.1 }, KeyType::Binary),
+    ] {
+        Generator::new(2_000, 11)
+            .with_key_type(key_type)
+            .with_distribution(dist)
+            .write_file(input_file, IoMode::Direct)
+            .unwrap();
+
+        let rs = rs::replacement_selection::ReplacementSelection::new(64, "run".to_string())
+            .with_temp_dir(temp_dir);
+        assert_eq!(rs.sort_from_file(input_file, output_file).unwrap(), 2_000);
+
+        let input = validate_file(input_file, InputFormat::Gensort, IoMode::Direct).unwrap();
+        let output = validate_file(output_file, InputFormat::LenPrefixed, IoMode::Direct).unwrap();
+        assert!(output.is_sorted(), "{:?}", dist);
+        assert!(output.same_records(&input), "{:?}", dist);
+        assert_eq!(output.duplicates, input_dups(input_file), "{:?}", dist);
+    }
+
+    // Cleanup
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_file]);
+}
+
+/// Number of records whose key also occurs earlier in a gensort file.
+fn input_dups(path: &str) -> u64 {
+    let data = std::fs::read(path).unwrap();
+    let mut keys: Vec<&[u8]> = data.chunks(100).map(|r| &r[..10]).collect();
+    let total = keys.len();
+    keys.sort();
+    keys.dedup();
+    (total - keys.len()) as u64
+}
+
+#[test]
+fn test_parallel_sort_matches_single_threaded() {
+    use rs::generate::{Generator, KeyDistribution};
+    use rs::io::{IoMode, IoOptions};
+
+    let input_file = "test_parallel_sort_input.bin";
+    let output_single = "test_parallel_sort_single.bin";
+    let output_parallel = "test_parallel_sort_parallel.bin";
+    let temp_dir = "test_parallel_sort_runs";
+
+    Generator::new(30_000, 8)
+        .with_distribution(KeyDistribution::Duplicates { distinct: 5_000 })
+        .write_file(input_file, IoMode::Direct)
+        .unwrap();
+
+    // Small I/O buffers so most of the budget goes to the workspaces
+    let budget = rs::memory::MemoryBudget::new(8 << 20);
+    let rs = rs::replacement_selection::ReplacementSelection::with_memory_budget(
+        budget,
+        "run".to_string(),
+    )
+    .with_io_options(IoOptions::default().with_buffer_size(64 << 10))
+    .with_temp_dir(temp_dir);
+    rs.sort_from_file(input_file, output_single).unwrap();
+    rs.clone()
+        .with_threads(4)
+        .sort_from_file(input_file, output_parallel)
+        .unwrap();
+
+    // Keys come out in the same order; equal keys may come from different workers
+    let keys = |path: &str| -> Vec<Vec<u8>> {
+        rs::io::RunReader::open(path)
+            .unwrap()
+            .map(|r| r.unwrap().key)
+            .collect()
+    };
+    assert_eq!(keys(output_single), keys(output_parallel));
+    assert_eq!(keys(output_parallel).len(), 30_000);
+
+    // Cleanup
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_single, output_parallel]);
+}
+
+#[test]
+fn test_async_backends_sort_matches_sync() {
+    use rs::generate::Generator;
+    use rs::io::{IoBackend, IoMode};
+
+    let input_file = "test_async_sort_input.bin";
+    let output_sync = "test_async_sort_sync.bin";
+    let output_async = "test_async_sort_async.bin";
+    let temp_dir = "test_async_sort_runs";
+
+    Generator::new(50_000, 14)
+        .write_file(input_file, IoMode::Direct)
+        .unwrap();
+
+    // Small workspace so the sort writes and merges several runs
+    let rs = rs::replacement_selection::ReplacementSelection::new(4_000, "run".to_string())
+        .with_temp_dir(temp_dir);
+    let merged = rs.sort_from_file(input_file, output_sync).unwrap();
+    assert_eq!(merged, 50_000);
+    // io_uring falls back to blocking I/O where it is unavailable
+    for backend in [IoBackend::Uring, IoBackend::Threaded { buffers: 2 }] {
+        let merged = rs
+            .clone()
+            .with_io_backend(backend)
+            .sort_from_file(input_file, output_async)
+            .unwrap();
+        assert_eq!(merged, 50_000);
+        assert_eq!(
+            std::fs::read(output_sync).unwrap(),
+            std::fs::read(output_async).unwrap(),
+            "{:?}",
+            backend
+        );
+    }
+
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_sync, output_async]);
+}
+
+#[test]
+fn test_merge_compact_and_len_prefixed_runs() {
+    use rs::generate::Generator;
+    use rs::io::{IoMode, IoOptions, OutputFormat};
+    use rs::merge::Merger;
+    use rs::replacement_selection::ReplacementSelection;
+
+    let input_file = "test_run_layouts_input.bin";
+    let output_serial = "test_run_layouts_serial.bin";
+    let output_parallel = "test_run_layouts_parallel.bin";
+
+    Generator::new(20_000, 21)
+        .write_file(input_file, IoMode::Direct)
+        .unwrap();
+
+    // Durable: runs and the parallel output are synced as they are finished
+    let io = IoOptions::default()
+        .with_buffer_size(64 << 10)
+        .with_durable(true);
+    let compact =
+        ReplacementSelection::new(3_000, "test_run_layouts_c".to_string()).with_io_options(io);
+    let prefixed = ReplacementSelection::new(3_000, "test_run_layouts_p".to_string())
+        .with_io_options(io)
+        .with_compact_runs(false);
+    let compact_runs = compact.run_from_file(input_file).unwrap();
+    let prefixed_runs = prefixed.run_from_file(input_file).unwrap();
+    // 100 instead of 108 bytes per record
+    assert_eq!(
+        prefixed_runs.total_bytes() - compact_runs.total_bytes(),
+        8 * 20_000 + (prefixed_runs.len() as u64 - compact_runs.len() as u64) * 24
+    );
+
+    let mut paths = compact_runs.paths();
+    paths.extend(prefixed_runs.paths());
+    let merger = Merger::new()
+        .with_output_format(OutputFormat::Gensort)
+        .with_io_options(io);
+    assert_eq!(merger.merge(&paths, output_serial).unwrap(), 40_000);
+    let merged = merger
+        .clone()
+        .with_threads(3)
+        .merge(&paths, output_parallel)
+        .unwrap();
+    assert_eq!(merged, 40_000);
+    assert_eq!(
+        std::fs::read(output_serial).unwrap(),
+        std::fs::read(output_parallel).unwrap()
+    );
+    let v = rs::validate::validate_file(
+        output_parallel,
+        rs::io::InputFormat::Gensort,
+        IoMode::Direct,
+    )
+    .unwrap();
+    assert!(v.is_sorted());
+    assert_eq!(v.duplicates, 20_000);
+
+    for run in compact_runs.iter().chain(&prefixed_runs) {
+        std::fs::remove_file(&run.path).ok();
+    }
+    std::fs::remove_file(compact.manifest_path()).ok();
+    std::fs::remove_file(prefixed.manifest_path()).ok();
+    cleanup_files(&[input_file, output_serial, output_parallel]);
+}
+
+#[test]
+fn test_tag_sort_matches_record_sort() {
+    use rs::generate::{Generator, KeyDistribution};
+    use rs::io::{InputFormat, IoMode, OutputFormat};
+    use rs::memory::MemoryBudget;
+    use rs::merge::Merger;
+    use rs::replacement_selection::ReplacementSelection;
+    use rs::tag_sort::Gather;
+    use rs::validate::validate_file;
+    use std::io::BufWriter;
+
+    let input_file = "test_tag_input.bin";
+    let output_file = "test_tag_output.bin";
+    let tag_output_file = "test_tag_output_tags.bin";
+    let temp_dir = "test_tag_runs";
+    Generator::new(3_000, 5)
+        .with_distribution(KeyDistribution::Duplicates { distinct: 50 })
+        .write_file(input_file, IoMode::Direct)
+        .unwrap();
+
+    // Equal keys keep their input order either way, so the outputs are identical
+    let merger = Merger::new().with_output_format(OutputFormat::Gensort);
+    let rs = ReplacementSelection::new(100, "run".to_string()).with_temp_dir(temp_dir);
+    rs.sort_from_file_with(input_file, output_file, &merger)
+        .unwrap();
+    let tags = rs.clone().with_tag_sort(true);
+    assert_eq!(
+        tags.sort_from_file_with(input_file, tag_output_file, &merger)
+            .unwrap(),
+        3_000
+    );
+    assert_eq!(
+        std::fs::read(tag_output_file).unwrap(),
+        std::fs::read(output_file).unwrap()
+    );
+    let mut out = BufWriter::new(Vec::new());
+    tags.sort_from_file_into(input_file, &mut out, &merger)
+        .unwrap();
+    assert_eq!(
+        out.into_inner().unwrap(),
+        std::fs::read(output_file).unwrap()
+    );
+    // A stream cannot be read again to gather its records
+    let err = tags
+        .sort(&[0u8; 100][..], &mut BufWriter::new(Vec::new()), &merger)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let err = tags.run(&[0u8; 100][..]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    // Tag runs and record runs are each merged only as such
+    let tag_runs = ReplacementSelection::new(100, "test_tag_runs".to_string())
+        .with_tag_sort(true)
+        .run_from_file(input_file)
+        .unwrap();
+    let record_runs = ReplacementSelection::new(100, "test_tag_records".to_string())
+        .run_from_file(input_file)
+        .unwrap();
+    let err = merger
+        .merge(&tag_runs.paths(), tag_output_file)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let mut gather = Gather::open(input_file, InputFormat::Gensort).unwrap();
+    let err = merger
+        .merge_tags(&record_runs.paths(), &mut gather, tag_output_file)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    for path in tag_runs.paths().into_iter().chain(record_runs.paths()) {
+        std::fs::remove_file(path).ok();
+    }
+    cleanup_files(&["test_tag_runs.manifest", "test_tag_records.manifest"]);
+
+    // Workers are dealt batches of tags rather than records
+    tags.with_threads(3)
+        .sort_from_file_with(input_file, tag_output_file, &merger)
+        .unwrap();
+    let input = validate_file(input_file, InputFormat::Gensort, IoMode::Direct).unwrap();
+    let output = validate_file(tag_output_file, InputFormat::Gensort, IoMode::Direct).unwrap();
+    assert!(output.is_sorted());
+    assert!(output.same_records(&input));
+
+    // Tags of wide records fill the same budget with more records
+    let lines: Vec<String> = (0..2_000u32)
+        .map(|i| format!("{:05} {}", (i * 7919) % 2003, "x".repeat(1000)))
+        .collect();
+    std::fs::write(input_file, lines.join("\n")).unwrap();
+    let run_files = || -> usize {
+        std::fs::read_dir(temp_dir)
+            .unwrap()
+            .flat_map(|job| std::fs::read_dir(job.unwrap().path()).unwrap())
+            .filter(|f| f.as_ref().unwrap().path().extension() == Some("bin".as_ref()))
+            .count()
+    };
+    let budget = MemoryBudget::new(MemoryBudget::io_bytes() + 256 * 1024);
+    let rs = ReplacementSelection::with_memory_budget(budget, "run".to_string())
+        .with_input_format(InputFormat::Text {
+            delimiter: Some(b' '),
+        })
+        .with_io_mode(IoMode::Buffered)
+        .with_keep_runs(true)
+        .with_temp_dir(temp_dir);
+    let merger = Merger::new().with_output_format(OutputFormat::Text);
+    rs.sort_from_file_with(input_file, output_file, &merger)
+        .unwrap();
+    let record_runs = run_files();
+    std::fs::remove_dir_all(temp_dir).ok();
+    rs.clone()
+        .with_tag_sort(true)
+        .sort_from_file_with(input_file, tag_output_file, &merger)
+        .unwrap();
+    let tag_runs = run_files();
+    assert!(
+        tag_runs * 3 <= record_runs,
+        "{} tag runs, {} record runs",
+        tag_runs,
+        record_runs
+    );
+    assert_eq!(
+        std::fs::read(tag_output_file).unwrap(),
+        std::fs::read(output_file).unwrap()
+    );
+
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_file, tag_output_file]);
+}
+
+#[test]
+fn test_sort_by_key_spec() {
+    use rs::format::Gensort;
+    use rs::io::OutputFormat;
+    use rs::key_spec::{KeySpec, Keyed};
+    use rs::merge::Merger;
+    use rs::replacement_selection::ReplacementSelection;
+
+    let input_file = "test_key_spec_input.bin";
+    let output_file = "test_key_spec_output.bin";
+    let temp_dir = "test_key_spec_runs";
+
+    // Gensort records with a signed little-endian amount at bytes 10..14 of
+    // which there are only a few values, and a unique key
+    let mut records: Vec<Vec<u8>> = (0..1_000u32)
+        .map(|i| {
+            let mut rec = ((i * 7919) % 1_000).to_be_bytes().to_vec();
+            rec.resize(10, 0);
+            rec.extend_from_slice(&((i % 7) as i32 - 3).to_le_bytes());
+            rec.resize(100, i as u8);
+            rec
+        })
+        .collect();
+    std::fs::write(input_file, records.concat()).unwrap();
+
+    // By amount, largest first, then by key
+    let spec: KeySpec = "10:4:int-le:desc,0:10".parse().unwrap();
+    let amount = |rec: &[u8]| i32::from_le_bytes(rec[10..14].try_into().unwrap());
+    records.sort_by(|a, b| amount(b).cmp(&amount(a)).then(a[..10].cmp(&b[..10])));
+
+    let merger = Merger::new().with_format(Keyed::new(OutputFormat::Gensort, spec.clone()));
+    for (threads, tag_sort) in [(1, false), (3, false), (1, true)] {
+        let rs = ReplacementSelection::new(50, "run".to_string())
+            .with_format(Keyed::new(Gensort, spec.clone()))
+            .with_threads(threads)
+            .with_tag_sort(tag_sort)
+            .with_temp_dir(temp_dir);
+        assert_eq!(
+            rs.sort_from_file_with(input_file, output_file, &merger)
+                .unwrap(),
+            1_000
+        );
+        assert_eq!(
+            std::fs::read(output_file).unwrap(),
+            records.concat(),
+            "threads {}, tag sort {}",
+            threads,
+            tag_sort
+        );
+    }
+
+    // Keyed runs are only merged by the spec they were keyed by
+    let runs = ReplacementSelection::new(200, "test_key_spec_run".to_string())
+        .with_format(Keyed::new(Gensort, spec.clone()))
+        .run_from_file(input_file)
+        .unwrap();
+    let other: KeySpec = "10:4:int-le,0:10".parse().unwrap();
+    let err = Merger::new()
+        .with_output_format(OutputFormat::Gensort)
+        .merge(&runs.paths(), output_file)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let err = Merger::new()
+        .with_format(Keyed::new(OutputFormat::Gensort, other))
+        .merge(&runs.paths(), output_file)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    merger.merge(&runs.paths(), output_file).unwrap();
+    assert_eq!(std::fs::read(output_file).unwrap(), records.concat());
+
+    // and in parallel, into regions sized by the original records
+    let len_prefixed = |rec: &Vec<u8>| {
+        let mut out = 10u32.to_le_bytes().to_vec();
+        out.extend_from_slice(&rec[..10]);
+        out.extend_from_slice(&90u32.to_le_bytes());
+        out.extend_from_slice(&rec[10..]);
+        out
+    };
+    for (format, expected) in [
+        (OutputFormat::Gensort, records.concat()),
+        (
+            OutputFormat::LenPrefixed,
+            records.iter().flat_map(len_prefixed).collect(),
+        ),
+    ] {
+        let merged = Merger::new()
+            .with_format(Keyed::new(format, spec.clone()))
+            .with_threads(3)
+            .merge(&runs.paths(), output_file)
+            .unwrap();
+        assert_eq!(merged, 1_000);
+        assert_eq!(
+            std::fs::read(output_file).unwrap(),
+            expected,
+            "{:?}",
+            format
+        );
+    }
+    for path in runs.paths() {
+        std::fs::remove_file(path).ok();
+    }
+
+    std::fs::remove_dir_all(temp_dir).ok();
+    cleanup_files(&[input_file, output_file, "test_key_spec_run.manifest"]);
 }